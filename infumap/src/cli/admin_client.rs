@@ -0,0 +1,53 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use clap::Arg;
+use serde::Serialize;
+use crate::util::infu::InfuResult;
+use crate::web::routes::admin::{AdminToken, ADMIN_TOKEN_HEADER};
+
+
+/// The argument for CLI commands that can be carried out by a running server, rather than by
+/// opening the db directory (which the server holds a lock on) directly.
+pub fn server_url_arg<'a>() -> Arg<'a> {
+  Arg::new("server_url")
+    .long("server")
+    .help(concat!("URL of a running Infumap web server (e.g. http://127.0.0.1:8000) to carry out the command, instead of ",
+                  "opening the db directory directly. The server must be using the same db directory."))
+    .takes_value(true)
+    .multiple_values(false)
+    .required(false)
+}
+
+/// Send a request to an admin route of the server at `server_url`, returning the response body.
+pub async fn post<T: Serialize>(server_url: &str, db_dir: &str, path: &str, request: &T) -> InfuResult<String> {
+  let admin_token = AdminToken::read(db_dir)?;
+  let url = format!("{}{}", server_url.trim_end_matches('/'), path);
+  let response = reqwest::Client::new()
+    .post(&url)
+    .header(ADMIN_TOKEN_HEADER, admin_token)
+    .json(request)
+    .send().await
+    .map_err(|e| format!("Request to '{}' failed: {}", url, e))?;
+  let status = response.status();
+  let body = response.text().await
+    .map_err(|e| format!("Could not read response from '{}': {}", url, e))?;
+  if !status.is_success() {
+    return Err(format!("Request to '{}' failed with status {}: {}", url, status, body).into());
+  }
+  Ok(body)
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use clap::{ArgMatches, App, Arg};
+use config::Config;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::util::infu::InfuResult;
+use crate::web::routes::admin::{
+  handle_create_api_token, handle_list_api_tokens, handle_revoke_api_token,
+  CreateUserApiTokenRequest, CreateUserApiTokenResponse, ListUserApiTokensRequest, ListUserApiTokensResponse,
+  RevokeUserApiTokenRequest, RevokeUserApiTokenResponse};
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("api-token")
+    .about(concat!("Create, list or revoke the API tokens of a user, which scripts and integrations can use in place of a session ",
+                   "by sending an 'Authorization: Bearer <token>' header"))
+    .subcommand_required(true)
+    .subcommand(App::new("create")
+      .about("Create an API token. The token is printed once, and can't be retrieved later")
+      .arg(username_arg())
+      .arg(Arg::new("name")
+        .long("name")
+        .help("A name for the token, to identify it when listing tokens.")
+        .takes_value(true)
+        .multiple_values(false)
+        .required(true))
+      .arg(Arg::new("read_only")
+        .long("read-only")
+        .help("Only allow the token to be used to read items and files.")
+        .takes_value(false)
+        .required(false))
+      .arg(Arg::new("scope_item_id")
+        .long("scope")
+        .help("The id of an item. If specified, the token can only be used to access this item and the items under it.")
+        .takes_value(true)
+        .multiple_values(false)
+        .required(false))
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg()))
+    .subcommand(App::new("list")
+      .about("List the API tokens of a user")
+      .arg(username_arg())
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg()))
+    .subcommand(App::new("revoke")
+      .about("Revoke an API token, so that it can no longer be used")
+      .arg(Arg::new("id")
+        .help("The id of the token, as shown by 'api-token list'.")
+        .takes_value(true)
+        .required(true))
+      .arg(username_arg())
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg()))
+}
+
+fn username_arg<'a>() -> Arg<'a> {
+  Arg::new("username")
+    .long("username")
+    .help("The user the API tokens are of.")
+    .takes_value(true)
+    .multiple_values(false)
+    .required(true)
+}
+
+fn settings_path_arg<'a>() -> Arg<'a> {
+  Arg::new("settings_path")
+    .short('s')
+    .long("settings")
+    .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                  "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+    .takes_value(true)
+    .multiple_values(false)
+    .required(false)
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  match sub_matches.subcommand() {
+    Some(("create", create_matches)) => {
+      if let Some(config) = config(create_matches) { create(&config, create_matches).await; }
+    },
+    Some(("list", list_matches)) => {
+      if let Some(config) = config(list_matches) { list(&config, list_matches).await; }
+    },
+    Some(("revoke", revoke_matches)) => {
+      if let Some(config) = config(revoke_matches) { revoke(&config, revoke_matches).await; }
+    },
+    _ => {
+      println!(".. --help for help.");
+    }
+  }
+}
+
+fn config(sub_matches: &ArgMatches) -> Option<Config> {
+  match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => Some(c),
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      None
+    }
+  }
+}
+
+/// Send `request` to admin route `path` of the server given by --server. If there isn't one, the db is opened
+/// and `handle` is used instead, which fails if a server is running.
+async fn perform<Req: Serialize, Resp: DeserializeOwned>(
+    config: &Config, sub_matches: &ArgMatches, path: &str, request: &Req, handle: fn(&mut Db, &Req) -> InfuResult<Resp>) -> InfuResult<Resp> {
+  let db_dir = &config.get_string("db_dir").unwrap();
+  if let Some(server_url) = sub_matches.value_of("server_url") {
+    let body = admin_client::post(server_url, db_dir, path, request).await?;
+    return serde_json::from_str::<Resp>(&body).map_err(|e| format!("The server response could not be read: {e}").into());
+  }
+  let kv_store_config = KVStoreConfig::from_config(config)?;
+  let mut db = Db::new(db_dir, &kv_store_config)
+    .map_err(|e| format!("Failed to initialize db: {e}\nIf the Infumap web server is running, use --server to manage API tokens through it."))?;
+  handle(&mut db, request)
+}
+
+async fn create(config: &Config, sub_matches: &ArgMatches) {
+  let request = CreateUserApiTokenRequest {
+    username: sub_matches.value_of("username").unwrap().to_string(),
+    name: sub_matches.value_of("name").unwrap().to_string(),
+    read_only: sub_matches.is_present("read_only"),
+    scope_item_id: sub_matches.value_of("scope_item_id").map(String::from)
+  };
+  match perform::<_, CreateUserApiTokenResponse>(config, sub_matches, "/admin/create-api-token", &request, handle_create_api_token).await {
+    Ok(response) => {
+      println!("Created API token '{}' for user '{}'. It will not be shown again:", response.api_token.id, request.username);
+      println!("{}", response.token);
+    },
+    Err(e) => { println!("Failed to create API token: {e}"); }
+  }
+}
+
+async fn list(config: &Config, sub_matches: &ArgMatches) {
+  let request = ListUserApiTokensRequest { username: sub_matches.value_of("username").unwrap().to_string() };
+  let response = match perform::<_, ListUserApiTokensResponse>(config, sub_matches, "/admin/list-api-tokens", &request, |db, request| handle_list_api_tokens(db, request)).await {
+    Ok(response) => response,
+    Err(e) => { println!("Failed to list API tokens: {e}"); return; }
+  };
+
+  if response.api_tokens.is_empty() {
+    println!("User '{}' has no API tokens.", request.username);
+    return;
+  }
+  for api_token in &response.api_tokens {
+    let access = if api_token.read_only { "read-only" } else { "read-write" };
+    let scope = match &api_token.scope_item_id {
+      Some(scope_item_id) => format!(", scoped to item '{scope_item_id}'"),
+      None => String::new()
+    };
+    let last_used = match api_token.last_used {
+      Some(last_used) => format!("last used {last_used}"),
+      None => String::from("never used")
+    };
+    println!("{} '{}': {access}{scope}, created {}, {last_used}", api_token.id, api_token.name, api_token.created);
+  }
+}
+
+async fn revoke(config: &Config, sub_matches: &ArgMatches) {
+  let request = RevokeUserApiTokenRequest {
+    username: sub_matches.value_of("username").unwrap().to_string(),
+    id: sub_matches.value_of("id").unwrap().to_string()
+  };
+  match perform::<_, RevokeUserApiTokenResponse>(config, sub_matches, "/admin/revoke-api-token", &request, handle_revoke_api_token).await {
+    Ok(response) if response.revoked => { println!("Revoked API token '{}'.", request.id); },
+    Ok(_) => { println!("User '{}' has no API token '{}'.", request.username, request.id); },
+    Err(e) => { println!("Failed to revoke API token: {e}"); }
+  }
+}
@@ -0,0 +1,123 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{ArgMatches, App, Arg};
+use config::Config;
+use crate::config::setup_config;
+use crate::storage::cache::FileCache;
+use crate::web::routes::admin::{CacheStatsResponse, ClearCacheResponse};
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("cache")
+    .about("Manage the file cache of scaled images")
+    .subcommand_required(true)
+    .subcommand(App::new("clear")
+      .about("Remove everything from the file cache")
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg()))
+    .subcommand(App::new("stats")
+      .about("Show the size of the file cache, and the hit, miss and eviction counts of a running server")
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg().required(true)))
+}
+
+fn settings_path_arg<'a>() -> Arg<'a> {
+  Arg::new("settings_path")
+    .short('s')
+    .long("settings")
+    .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                  "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+    .takes_value(true)
+    .multiple_values(false)
+    .required(false)
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  match sub_matches.subcommand() {
+    Some(("clear", clear_matches)) => {
+      if let Some(config) = config(clear_matches) { clear(&config, clear_matches).await; }
+    },
+    Some(("stats", stats_matches)) => {
+      if let Some(config) = config(stats_matches) { stats(&config, stats_matches).await; }
+    },
+    _ => {
+      println!(".. --help for help.");
+    }
+  }
+}
+
+fn config(sub_matches: &ArgMatches) -> Option<Config> {
+  match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => Some(c),
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      None
+    }
+  }
+}
+
+async fn clear(config: &Config, sub_matches: &ArgMatches) {
+  if let Some(server_url) = sub_matches.value_of("server_url") {
+    let db_dir = &config.get_string("db_dir").unwrap();
+    match admin_client::post(server_url, db_dir, "/admin/clear-cache", &()).await {
+      Ok(body) => {
+        match serde_json::from_str::<ClearCacheResponse>(&body) {
+          Ok(response) => { println!("Removed {} files from the file cache.", response.removed_count); },
+          Err(e) => { println!("The server response could not be read: {e}"); }
+        }
+      },
+      Err(e) => { println!("Failed to clear file cache: {e}"); }
+    }
+    return;
+  }
+
+  let cache_dir = config.get_string("cache_dir").unwrap();
+  let cache_max_bytes = config.get_int("cache_max_bytes").unwrap();
+  let file_cache = match FileCache::new(&cache_dir, cache_max_bytes.max(0) as u64) {
+    Ok(file_cache) => file_cache,
+    Err(e) => {
+      println!("Failed to initialize file cache: {e}");
+      return;
+    }
+  };
+  match file_cache.clear() {
+    Ok(count) => { println!("Removed {} files from the file cache.", count); },
+    Err(e) => { println!("Failed to clear file cache: {e}"); }
+  }
+  println!("If the Infumap web server is running, use --server to clear the cache through it, so its record of the cache stays accurate.");
+}
+
+async fn stats(config: &Config, sub_matches: &ArgMatches) {
+  let server_url = sub_matches.value_of("server_url").unwrap();
+  let db_dir = &config.get_string("db_dir").unwrap();
+  match admin_client::post(server_url, db_dir, "/admin/cache-stats", &()).await {
+    Ok(body) => {
+      match serde_json::from_str::<CacheStatsResponse>(&body) {
+        Ok(s) => {
+          println!("Files: {}", s.entry_count);
+          println!("Size: {} of {} bytes", s.size_bytes, s.max_bytes);
+          println!("Hits: {}", s.hits);
+          println!("Misses: {}", s.misses);
+          println!("Evictions: {}", s.evictions);
+        },
+        Err(e) => { println!("The server response could not be read: {e}"); }
+      }
+    },
+    Err(e) => { println!("Failed to get file cache stats: {e}"); }
+  }
+}
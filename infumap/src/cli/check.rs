@@ -0,0 +1,93 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{ArgMatches, App, Arg};
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::web::routes::admin::CheckResponse;
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("check")
+    .about("Check the item trees of all users for orphans, dangling parents, cycles, duplicate ordering keys and invalid fields")
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(admin_client::server_url_arg())
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let db_dir = &config.get_string("db_dir").unwrap();
+
+  let problems = if let Some(server_url) = sub_matches.value_of("server_url") {
+    match admin_client::post(server_url, db_dir, "/admin/check", &()).await {
+      Ok(body) => {
+        match serde_json::from_str::<CheckResponse>(&body) {
+          Ok(response) => response.problems,
+          Err(e) => { println!("The server response could not be read: {e}"); return; }
+        }
+      },
+      Err(e) => { println!("Failed to check db: {e}"); return; }
+    }
+  } else {
+    let kv_store_config = match KVStoreConfig::from_config(&config) {
+      Ok(c) => c,
+      Err(e) => {
+        println!("Could not setup configuration {e}");
+        return;
+      }
+    };
+
+    let mut db = match Db::new(db_dir, &kv_store_config) {
+      Ok(db) => db,
+      Err(e) => {
+        println!("Failed to initialize db: {e}");
+        println!("If the Infumap web server is running, use --server to check through it.");
+        return;
+      }
+    };
+
+    match db.check() {
+      Ok(problems) => problems,
+      Err(e) => { println!("Failed to check db: {e}"); return; }
+    }
+  };
+
+  if problems.is_empty() {
+    println!("No problems found.");
+    return;
+  }
+  for problem in &problems {
+    println!("{problem}");
+  }
+  println!("{} problems found.", problems.len());
+  std::process::exit(1);
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::sync::Mutex;
+use clap::{ArgMatches, App, Arg};
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::storage::file::FileStore;
+use crate::web::background;
+use crate::web::routes::admin::{GcRequest, GcResponse};
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("gc")
+    .about(concat!("Delete file data that no file or image item refers to, such as that of uploads that failed part way through, ",
+                   "and report file and image items whose data is missing"))
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(Arg::new("dry_run")
+      .long("dry-run")
+      .help("Report what would be deleted, without deleting anything.")
+      .takes_value(false)
+      .required(false))
+    .arg(admin_client::server_url_arg())
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let dry_run = sub_matches.is_present("dry_run");
+  let db_dir = &config.get_string("db_dir").unwrap();
+
+  let response = if let Some(server_url) = sub_matches.value_of("server_url") {
+    match admin_client::post(server_url, db_dir, "/admin/gc", &GcRequest { dry_run }).await {
+      Ok(body) => {
+        match serde_json::from_str::<GcResponse>(&body) {
+          Ok(response) => response,
+          Err(e) => { println!("The server response could not be read: {e}"); return; }
+        }
+      },
+      Err(e) => { println!("Failed to collect garbage: {e}"); return; }
+    }
+  } else {
+    let kv_store_config = match KVStoreConfig::from_config(&config) {
+      Ok(c) => c,
+      Err(e) => {
+        println!("Could not setup configuration {e}");
+        return;
+      }
+    };
+
+    let db = match Db::new(db_dir, &kv_store_config) {
+      Ok(db) => db,
+      Err(e) => {
+        println!("Failed to initialize db: {e}");
+        println!("If the Infumap web server is running, use --server to collect garbage through it.");
+        return;
+      }
+    };
+
+    let file_store = match FileStore::from_config(&config, &kv_store_config).await {
+      Ok(file_store) => file_store,
+      Err(e) => {
+        println!("Failed to initialize file store: {e}");
+        return;
+      }
+    };
+
+    match background::collect_garbage(&Mutex::new(db), &file_store, dry_run).await {
+      Ok(report) => GcResponse::from(report),
+      Err(e) => { println!("Failed to collect garbage: {e}"); return; }
+    }
+  };
+
+  for id in &response.orphaned_ids {
+    println!("Item '{id}' does not exist, but has file data.");
+  }
+  for id in &response.missing_ids {
+    println!("Item '{id}' has no file data.");
+  }
+  let action = if dry_run { "Would delete" } else { "Deleted" };
+  println!("{} the file data of {} items that no longer exist, and {} unreferenced blobs, freeing {} bytes.",
+           action, response.orphaned_ids.len(), response.unreferenced_blob_count, response.reclaimable_bytes);
+  if !response.missing_ids.is_empty() {
+    println!("{} items have no file data.", response.missing_ids.len());
+  }
+}
@@ -0,0 +1,119 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{ArgMatches, App, Arg};
+use config::Config;
+use crate::config::setup_config;
+use crate::web::routes::admin::{ClearLoginFailuresRequest, ClearLoginFailuresResponse, LoginFailuresResponse};
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("login-failures")
+    .about("Show or clear the failed login attempts a running server is tracking, which block logins once there are too many")
+    .subcommand_required(true)
+    .subcommand(App::new("list")
+      .about("Show the recent failed login attempts of each username and client IP address, and whether logins are currently blocked")
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg().required(true)))
+    .subcommand(App::new("clear")
+      .about("Forget the failed login attempts of a username or IP address, unblocking it")
+      .arg(Arg::new("key")
+        .help("The username or IP address.")
+        .takes_value(true)
+        .required(true))
+      .arg(settings_path_arg())
+      .arg(admin_client::server_url_arg().required(true)))
+}
+
+fn settings_path_arg<'a>() -> Arg<'a> {
+  Arg::new("settings_path")
+    .short('s')
+    .long("settings")
+    .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                  "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+    .takes_value(true)
+    .multiple_values(false)
+    .required(false)
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  match sub_matches.subcommand() {
+    Some(("list", list_matches)) => {
+      if let Some(config) = config(list_matches) { list(&config, list_matches).await; }
+    },
+    Some(("clear", clear_matches)) => {
+      if let Some(config) = config(clear_matches) { clear(&config, clear_matches).await; }
+    },
+    _ => {
+      println!(".. --help for help.");
+    }
+  }
+}
+
+fn config(sub_matches: &ArgMatches) -> Option<Config> {
+  match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => Some(c),
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      None
+    }
+  }
+}
+
+async fn list(config: &Config, sub_matches: &ArgMatches) {
+  let server_url = sub_matches.value_of("server_url").unwrap();
+  let db_dir = &config.get_string("db_dir").unwrap();
+  let response = match admin_client::post(server_url, db_dir, "/admin/login-failures", &()).await {
+    Ok(body) => {
+      match serde_json::from_str::<LoginFailuresResponse>(&body) {
+        Ok(response) => response,
+        Err(e) => { println!("The server response could not be read: {e}"); return; }
+      }
+    },
+    Err(e) => { println!("Failed to get login failures: {e}"); return; }
+  };
+
+  if response.entries.is_empty() {
+    println!("There have been no recent failed logins.");
+    return;
+  }
+  for entry in &response.entries {
+    let kind = if entry.is_ip { "IP address" } else { "Username" };
+    let blocked = if entry.blocked_until > response.now {
+      format!(", blocked for another {}s", entry.blocked_until - response.now)
+    } else {
+      String::new()
+    };
+    println!("{kind} '{}': {} failures, the last {}s ago{blocked}", entry.key, entry.count, response.now - entry.last_failure);
+  }
+}
+
+async fn clear(config: &Config, sub_matches: &ArgMatches) {
+  let server_url = sub_matches.value_of("server_url").unwrap();
+  let db_dir = &config.get_string("db_dir").unwrap();
+  let key = sub_matches.value_of("key").unwrap().to_string();
+  match admin_client::post(server_url, db_dir, "/admin/clear-login-failures", &ClearLoginFailuresRequest { key: key.clone() }).await {
+    Ok(body) => {
+      match serde_json::from_str::<ClearLoginFailuresResponse>(&body) {
+        Ok(response) if response.cleared => { println!("Cleared the failed logins of '{key}'."); },
+        Ok(_) => { println!("There were no failed logins for '{key}'."); },
+        Err(e) => { println!("The server response could not be read: {e}"); }
+      }
+    },
+    Err(e) => { println!("Failed to clear login failures: {e}"); }
+  }
+}
@@ -0,0 +1,78 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::{ArgMatches, App, Arg};
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::storage::file::FileStore;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("migrate-files")
+    .about(concat!("Convert the files directory from being keyed by item id to being content addressed, so that items with ",
+                   "identical data share a single copy. The web server must not be running"))
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let kv_store_config = match KVStoreConfig::from_config(&config) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  // Nothing else may use the files directory while it is converted. It has no lock of its own, but
+  // anything that does is holding the db directory lock.
+  let _db = match Db::new(&config.get_string("db_dir").unwrap(), &kv_store_config) {
+    Ok(db) => db,
+    Err(e) => {
+      println!("Failed to initialize db: {e}");
+      println!("The Infumap web server must be stopped before migrating the files directory.");
+      return;
+    }
+  };
+
+  match FileStore::migrate_to_content_addressed(&config, &kv_store_config).await {
+    Ok(stats) => {
+      println!("The data of {} items is now held in {} blobs, saving {} bytes.", stats.item_count, stats.blob_count, stats.saved_bytes());
+      if !config.get_bool("files_content_addressed").unwrap() {
+        println!("Set files_content_addressed = true in your settings before starting the web server.");
+      }
+    },
+    Err(e) => {
+      println!("Failed to migrate files directory: {e}");
+      println!("Migration can be resumed by running this command again.");
+    }
+  }
+}
@@ -14,4 +14,16 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-pub mod add_user;
\ No newline at end of file
+pub mod add_user;
+pub mod admin_client;
+pub mod api_token;
+pub mod cache;
+pub mod check;
+pub mod compact;
+pub mod gc;
+pub mod login_failures;
+pub mod migrate_files;
+pub mod purge_trash;
+pub mod reset_totp;
+pub mod rotate_keys;
+pub mod verify_files;
\ No newline at end of file
@@ -0,0 +1,112 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Mutex;
+use clap::{ArgMatches, App, Arg};
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::storage::file::FileStore;
+use crate::web::background;
+use crate::web::routes::admin::{PurgeTrashRequest, PurgeTrashResponse};
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("purge-trash")
+    .about("Permanently delete items, and their file data, that have been in the trash for longer than the retention period")
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(Arg::new("older_than_days")
+      .long("older-than-days")
+      .help("Purge items that have been in the trash for more than this many days, instead of the trash_retention_days setting. 0 empties the trash.")
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(admin_client::server_url_arg())
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let older_than_days = match sub_matches.value_of("older_than_days") {
+    Some(days) => match days.parse::<i64>() {
+      Ok(days) if days >= 0 => days,
+      _ => {
+        println!("--older-than-days must be a non-negative number of days, not '{days}'.");
+        return;
+      }
+    },
+    None => config.get_int("trash_retention_days").unwrap()
+  };
+
+  let db_dir = &config.get_string("db_dir").unwrap();
+
+  if let Some(server_url) = sub_matches.value_of("server_url") {
+    match admin_client::post(server_url, db_dir, "/admin/purge-trash", &PurgeTrashRequest { older_than_days }).await {
+      Ok(body) => {
+        match serde_json::from_str::<PurgeTrashResponse>(&body) {
+          Ok(response) => { println!("Purged {} items from the trash.", response.purged_count); },
+          Err(e) => { println!("The trash was purged, but the server response could not be read: {e}"); }
+        }
+      },
+      Err(e) => { println!("Failed to purge trash: {e}"); }
+    }
+    return;
+  }
+
+  let kv_store_config = match KVStoreConfig::from_config(&config) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let db = match Db::new(db_dir, &kv_store_config) {
+    Ok(db) => db,
+    Err(e) => {
+      println!("Failed to initialize db: {e}");
+      println!("If the Infumap web server is running, use --server to purge the trash through it.");
+      return;
+    }
+  };
+
+  let file_store = match FileStore::from_config(&config, &kv_store_config).await {
+    Ok(file_store) => file_store,
+    Err(e) => {
+      println!("Failed to initialize file store: {e}");
+      return;
+    }
+  };
+
+  match background::purge_trash(&Mutex::new(db), &file_store, older_than_days).await {
+    Ok(count) => { println!("Purged {count} items from the trash."); },
+    Err(e) => { println!("Failed to purge trash: {e}"); }
+  }
+}
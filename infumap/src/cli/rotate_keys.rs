@@ -0,0 +1,177 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use clap::{ArgMatches, App, Arg};
+use config::Config;
+use crate::config::setup_config;
+use crate::storage::cache::FileCache;
+use crate::storage::db::{totp_secret_aad, user_keyring, Db};
+use crate::storage::db::item::{is_data_item, Item};
+use crate::storage::db::item_db::ItemDb;
+use crate::storage::db::kv_store::{JsonLogSerializable, KVStore, KVStoreConfig};
+use crate::storage::db::trash::TrashEntry;
+use crate::storage::db::trash_db::TrashDb;
+use crate::storage::db::user::User;
+use crate::storage::db::user_db;
+use crate::storage::encryption::{EncryptionKey, Keyring};
+use crate::storage::file::FileStore;
+use crate::util::infu::InfuResult;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("rotate-keys")
+    .about(concat!("Give each user a new data key, and re-encrypt all their data with it. Data that is not yet encrypted is ",
+                   "encrypted, so this is also how encryption is enabled for existing data. The web server must not be running"))
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(Arg::new("new_server_key")
+      .long("new-server-key")
+      .help(concat!("Replace the server key (the encryption_key setting) with this one, given as 64 hex digits. Required if ",
+                    "encryption_key is not set. The encryption_key setting must be updated to match once this completes."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let kv_store_config = match KVStoreConfig::from_config(&config) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let new_server_key = match sub_matches.value_of("new_server_key").map(EncryptionKey::from_hex) {
+    None => None,
+    Some(Ok(key)) => Some(key),
+    Some(Err(e)) => {
+      println!("Invalid --new-server-key: {e}");
+      return;
+    }
+  };
+  let replacing_server_key = new_server_key.is_some();
+
+  match rotate_keys(&config, &kv_store_config, new_server_key).await {
+    Ok(user_count) => {
+      println!("The data of {} users is now encrypted with new keys.", user_count);
+      if replacing_server_key {
+        println!("Set encryption_key to the new server key before starting the web server.");
+      }
+    },
+    Err(e) => {
+      println!("Failed to rotate keys: {e}");
+      println!("If keys were partly rotated, run the command again with the same arguments to finish. Until then, some data may not be readable.");
+    }
+  }
+}
+
+/// Returns the number of users whose data was re-encrypted.
+///
+/// Each user is first given their new data key, which is recorded along with their old ones, so that data that has not
+/// been re-encrypted yet can still be read if this is interrupted. Their old keys are dropped once all their data has
+/// been re-encrypted.
+async fn rotate_keys(config: &Config, kv_store_config: &KVStoreConfig, new_server_key: Option<EncryptionKey>) -> InfuResult<usize> {
+  let server_keyring = match (new_server_key, &kv_store_config.server_keyring) {
+    (Some(key), _) => Keyring::from(key),
+    (None, Some(keyring)) => keyring.clone(),
+    (None, None) => return Err("There is no server key. Either set encryption_key, or give a new key with --new-server-key.".into())
+  };
+  // If this is resuming an interrupted rotation, some data may already be encrypted with the new server key.
+  let mut known_server_keys = server_keyring.keys().to_vec();
+  if let Some(keyring) = &kv_store_config.server_keyring {
+    known_server_keys.extend(keyring.keys().iter().cloned());
+  }
+  let known_server_keyring = Keyring::new(known_server_keys)?;
+
+  let db_dir = config.get_string("db_dir").unwrap();
+  let users_encrypted = KVStore::<User>::is_encrypted(&db_dir, user_db::LOG_FILENAME)?;
+  let mut db = Db::new(&db_dir, &KVStoreConfig {
+    server_keyring: if users_encrypted { Some(known_server_keyring.clone()) } else { None },
+    ..kv_store_config.clone()
+  })?;
+
+  let mut users = db.user.get_iter().map(|(_, user)| user.clone()).collect::<Vec<User>>();
+  users.sort_by(|a, b| a.username.cmp(&b.username));
+  for user in users.iter_mut() {
+    let mut keys = match user_keyring(user, Some(&known_server_keyring))? {
+      Some(keyring) => keyring.keys().to_vec(),
+      None => vec![]
+    };
+    keys.insert(0, EncryptionKey::generate());
+    user.data_keys = keys.iter()
+      .map(|key| server_keyring.current().wrap_key(key, &user.id))
+      .collect::<InfuResult<Vec<String>>>()?;
+    if let Some(totp_secret) = &user.totp_secret {
+      let aad = totp_secret_aad(&user.id);
+      let ciphertext = base64::decode(totp_secret).map_err(|e| format!("The TOTP secret of user '{}' is not valid base64: {}", user.username, e))?;
+      let secret = known_server_keyring.decrypt(&ciphertext, aad.as_bytes())?;
+      user.totp_secret = Some(base64::encode(server_keyring.encrypt(&secret, aad.as_bytes())?));
+    }
+    db.user.update(user.clone())?;
+  }
+  db.user.reencrypt(Some(server_keyring.clone()))?;
+  db.session.reencrypt(Some(server_keyring.clone()))?;
+  db.api_token.reencrypt(Some(server_keyring.clone()))?;
+
+  let file_store = FileStore::from_config(config, &KVStoreConfig { server_keyring: Some(server_keyring.clone()), ..kv_store_config.clone() }).await?;
+  for user in users.iter_mut() {
+    let current = user_keyring(user, Some(&server_keyring))?.unwrap();
+    let keyring = Keyring::from(current.current().clone());
+    let items = reencrypt_log::<Item>(&db_dir, &ItemDb::log_filename(&user.id), kv_store_config, &current, &keyring)?;
+    reencrypt_log::<TrashEntry>(&db_dir, &TrashDb::log_filename(&user.id), kv_store_config, &current, &keyring)?;
+    for (id, item) in items.get_iter() {
+      if is_data_item(&item.item_type) && !file_store.reencrypt(id, &current, &keyring).await? {
+        println!("Warning: item '{}' of user '{}' has no file data.", id, user.username);
+      }
+    }
+    user.data_keys.truncate(1);
+    db.user.update(user.clone())?;
+    println!("Re-encrypted the data of user '{}'.", user.username);
+  }
+
+  // Cached files were encrypted with the old keys, if at all.
+  let cache_dir = config.get_string("cache_dir").unwrap();
+  let cache_max_bytes = config.get_int("cache_max_bytes").unwrap();
+  FileCache::new(&cache_dir, cache_max_bytes.max(0) as u64)?.clear()?;
+
+  Ok(users.len())
+}
+
+/// Encrypt log `log_filename` with the current key of `keyring`, returning it. It may be encrypted with any of the
+/// keys of `current`, or not encrypted.
+fn reencrypt_log<T>(db_dir: &str, log_filename: &str, kv_store_config: &KVStoreConfig, current: &Keyring, keyring: &Keyring) -> InfuResult<KVStore<T>>
+    where T: JsonLogSerializable<T> {
+  let encrypted = KVStore::<T>::is_encrypted(db_dir, log_filename)?;
+  let mut store = KVStore::<T>::init(db_dir, log_filename, kv_store_config, if encrypted { Some(current.clone()) } else { None })?;
+  store.reencrypt(Some(keyring.clone()))?;
+  Ok(store)
+}
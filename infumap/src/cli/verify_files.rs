@@ -0,0 +1,110 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::sync::Mutex;
+use clap::{ArgMatches, App, Arg};
+use crate::config::setup_config;
+use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
+use crate::storage::file::FileStore;
+use crate::web::background;
+use crate::web::routes::admin::VerifyFilesResponse;
+use super::admin_client;
+
+
+pub fn make_clap_subcommand<'a>() -> App<'a> {
+  App::new("verify-files")
+    .about(concat!("Read the data of all file and image items, and check that it is the size, and has the content hash, recorded ",
+                   "when it was uploaded - reporting items whose data is missing, truncated or corrupt"))
+    .arg(Arg::new("settings_path")
+      .short('s')
+      .long("settings")
+      .help(concat!("Path to a toml settings configuration file. If not specified and the required configuration values are not available ",
+                    "via env vars, ~/.infumap/settings.toml will be used. If it does not exist, it will created with default values."))
+      .takes_value(true)
+      .multiple_values(false)
+      .required(false))
+    .arg(admin_client::server_url_arg())
+}
+
+pub async fn execute(sub_matches: &ArgMatches) {
+  let config = match setup_config(sub_matches.value_of("settings_path")) {
+    Ok(c) => c,
+    Err(e) => {
+      println!("Could not setup configuration {e}");
+      return;
+    }
+  };
+
+  let db_dir = &config.get_string("db_dir").unwrap();
+
+  let response = if let Some(server_url) = sub_matches.value_of("server_url") {
+    match admin_client::post(server_url, db_dir, "/admin/verify-files", &()).await {
+      Ok(body) => {
+        match serde_json::from_str::<VerifyFilesResponse>(&body) {
+          Ok(response) => response,
+          Err(e) => { println!("The server response could not be read: {e}"); return; }
+        }
+      },
+      Err(e) => { println!("Failed to verify files: {e}"); return; }
+    }
+  } else {
+    let kv_store_config = match KVStoreConfig::from_config(&config) {
+      Ok(c) => c,
+      Err(e) => {
+        println!("Could not setup configuration {e}");
+        return;
+      }
+    };
+
+    let db = match Db::new(db_dir, &kv_store_config) {
+      Ok(db) => db,
+      Err(e) => {
+        println!("Failed to initialize db: {e}");
+        println!("If the Infumap web server is running, use --server to verify files through it.");
+        return;
+      }
+    };
+
+    let file_store = match FileStore::from_config(&config, &kv_store_config).await {
+      Ok(file_store) => file_store,
+      Err(e) => {
+        println!("Failed to initialize file store: {e}");
+        return;
+      }
+    };
+
+    match background::verify_files(&Mutex::new(db), &file_store).await {
+      Ok((checked_count, unhashed_count, problems)) => VerifyFilesResponse { checked_count, unhashed_count, problems },
+      Err(e) => { println!("Failed to verify files: {e}"); return; }
+    }
+  };
+
+  println!("Checked the data of {} items.", response.checked_count);
+  if response.unhashed_count > 0 {
+    println!("{} items were uploaded before content hashes were recorded, so only their size was checked.", response.unhashed_count);
+  }
+  if response.problems.is_empty() {
+    println!("No problems found.");
+    return;
+  }
+  for problem in &response.problems {
+    println!("{problem}");
+  }
+  println!("{} problems found.", response.problems.len());
+  std::process::exit(1);
+}
@@ -15,7 +15,8 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::os::unix::prelude::FileExt;
-use config::{Config, FileFormat};
+use config::builder::DefaultState;
+use config::{Config, ConfigBuilder, ConfigError, FileFormat};
 use crate::util::infu::InfuResult;
 
 
@@ -133,14 +134,21 @@ pub fn setup_config(settings_path: Option<&str>) -> InfuResult<Config> {
     }
   };
 
+  let config_builder = match config_builder_with_defaults() {
+    Ok(b) => b,
+    Err(e) => {
+      return Err(format!("An error occurred setting configuration defaults: '{e}'").into());
+    }
+  };
+
   let config_builder =
     if let Some(path) = settings_path_maybe {
       println!("Using settings from path: {path}, and overriding with env vars if set.");
-      Config::builder()
+      config_builder
         .add_source(config::File::new(&path, FileFormat::Toml))
     } else {
       println!("Not using settings file - taking all settings from env vars.");
-      Config::builder()
+      config_builder
     }
     .add_source(config::Environment::with_prefix(CONFIG_PREFIX));
 
@@ -156,3 +164,26 @@ pub fn setup_config(settings_path: Option<&str>) -> InfuResult<Config> {
 
   Ok(config)
 }
+
+/// Defaults for optional settings, which may be missing from settings files created by earlier versions.
+fn config_builder_with_defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+  Config::builder()
+    .set_default("log_compaction_dead_ratio", 0.5)?
+    .set_default("log_fsync", true)?
+    .set_default("log_history_window_days", 30)?
+    .set_default("trash_retention_days", 30)?
+    .set_default("files_gc_interval_hours", 0)?
+    .set_default("max_upload_size_mb", 100)?
+    .set_default("cache_max_bytes", 1024 * 1024 * 1024)?
+    .set_default("files_content_addressed", false)?
+    .set_default("files_verify_on_read", false)?
+    .set_default("files_backend", "local")?
+    .set_default("s3_endpoint", "")?
+    .set_default("s3_region", "us-east-1")?
+    .set_default("s3_bucket", "")?
+    .set_default("s3_access_key_id", "")?
+    .set_default("s3_secret_access_key", "")?
+    .set_default("encryption_key", "")?
+    .set_default("secure_cookies", false)?
+    .set_default("client_ip_header", "")
+}
@@ -33,6 +33,17 @@ async fn main() {
     .version("0.1.0")
     .subcommand(web::make_clap_subcommand())
     .subcommand(cli::add_user::make_clap_subcommand())
+    .subcommand(cli::reset_totp::make_clap_subcommand())
+    .subcommand(cli::api_token::make_clap_subcommand())
+    .subcommand(cli::compact::make_clap_subcommand())
+    .subcommand(cli::purge_trash::make_clap_subcommand())
+    .subcommand(cli::gc::make_clap_subcommand())
+    .subcommand(cli::verify_files::make_clap_subcommand())
+    .subcommand(cli::check::make_clap_subcommand())
+    .subcommand(cli::cache::make_clap_subcommand())
+    .subcommand(cli::login_failures::make_clap_subcommand())
+    .subcommand(cli::migrate_files::make_clap_subcommand())
+    .subcommand(cli::rotate_keys::make_clap_subcommand())
     .get_matches();
 
   // test();
@@ -42,7 +53,40 @@ async fn main() {
       web::execute(arg_sub_matches).await
     },
     Some(("add-user", arg_sub_matches)) => {
-      cli::add_user::execute(arg_sub_matches)
+      cli::add_user::execute(arg_sub_matches).await
+    },
+    Some(("reset-totp", arg_sub_matches)) => {
+      cli::reset_totp::execute(arg_sub_matches).await
+    },
+    Some(("api-token", arg_sub_matches)) => {
+      cli::api_token::execute(arg_sub_matches).await
+    },
+    Some(("compact", arg_sub_matches)) => {
+      cli::compact::execute(arg_sub_matches).await
+    },
+    Some(("purge-trash", arg_sub_matches)) => {
+      cli::purge_trash::execute(arg_sub_matches).await
+    },
+    Some(("gc", arg_sub_matches)) => {
+      cli::gc::execute(arg_sub_matches).await
+    },
+    Some(("verify-files", arg_sub_matches)) => {
+      cli::verify_files::execute(arg_sub_matches).await
+    },
+    Some(("check", arg_sub_matches)) => {
+      cli::check::execute(arg_sub_matches).await
+    },
+    Some(("cache", arg_sub_matches)) => {
+      cli::cache::execute(arg_sub_matches).await
+    },
+    Some(("login-failures", arg_sub_matches)) => {
+      cli::login_failures::execute(arg_sub_matches).await
+    },
+    Some(("migrate-files", arg_sub_matches)) => {
+      cli::migrate_files::execute(arg_sub_matches).await
+    },
+    Some(("rotate-keys", arg_sub_matches)) => {
+      cli::rotate_keys::execute(arg_sub_matches).await
     },
     _ => {
       println!(".. --help for help.");
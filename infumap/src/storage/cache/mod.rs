@@ -14,17 +14,372 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
-use crate::util::{fs::expand_tilde, infu::InfuResult};
+use image::{DynamicImage, ImageOutputFormat};
+use image::imageops::FilterType;
+use image::io::Reader;
+use serde::{Deserialize, Serialize};
 
+use crate::storage::encryption::Keyring;
+use crate::util::geometry::Dimensions;
+use crate::util::infu::InfuResult;
+use crate::util::uid::{new_uid, Uid};
+use crate::util::fs::expand_tilde;
+
+/// The widths at which scaled versions of images are generated. A request for any other width is
+/// served the next largest of these, so the number of versions of an image that are cached is bounded.
+pub const SCALED_WIDTHS_PX: [u32; 5] = [120, 240, 480, 960, 1920];
+
+/// Thumbnails fit within a square of this size.
+const THUMBNAIL_SIZE_PX: u32 = 32;
+
+const JPEG_QUALITY: u8 = 85;
+
+
+pub struct ImageMetadata {
+  pub size_px: Dimensions<i64>,
+  /// A base64 encoded JPEG, small enough to be sent along with the item.
+  pub thumbnail: String,
+}
+
+/// Read the dimensions of the image `file`, and create a thumbnail of it.
+pub fn image_metadata(file: File) -> InfuResult<ImageMetadata> {
+  let img = decode(BufReader::new(file))?;
+  let mut thumbnail = vec![];
+  img.thumbnail(THUMBNAIL_SIZE_PX, THUMBNAIL_SIZE_PX).to_rgb8()
+    .write_to(&mut Cursor::new(&mut thumbnail), ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+  Ok(ImageMetadata {
+    size_px: Dimensions { w: img.width() as i64, h: img.height() as i64 },
+    thumbnail: base64::encode(thumbnail)
+  })
+}
+
+/// The width of the scaled version of an image `image_width_px` wide that should be served for a request
+/// for it at `requested_width_px`, or None if the original is no larger than that and should be served.
+pub fn scaled_width_for(requested_width_px: u32, image_width_px: i64) -> Option<u32> {
+  SCALED_WIDTHS_PX.iter()
+    .find(|w| **w >= requested_width_px)
+    .filter(|w| (**w as i64) < image_width_px)
+    .copied()
+}
+
+
+pub struct ScaledImage {
+  pub data: ScaledImageData,
+  pub size: u64,
+  pub last_modified: SystemTime,
+  pub mime_type: &'static str,
+}
+
+/// Scaled versions of images whose data is encrypted are cached encrypted with the same key, so are decrypted
+/// into memory when opened. They are not large.
+pub enum ScaledImageData {
+  File(File),
+  Decrypted(Vec<u8>),
+}
+
+/// Scaled versions of images are written as JPEG, unless they have transparency, which PNG preserves.
+const SCALED_FORMATS: [(&str, &str); 2] = [("jpg", "image/jpeg"), ("png", "image/png")];
+
+/// Records when each cached file was last used, so the least recently used can be evicted first.
+/// It is kept in the cache directory, so recency survives restarts.
+const INDEX_FILENAME: &str = "index.json";
+
+#[derive(Serialize, Deserialize, Clone)]
+struct IndexEntry {
+  #[serde(rename="sizeBytes")]
+  size_bytes: u64,
+  #[serde(rename="lastAccessedMs")]
+  last_accessed_ms: u64,
+}
+
+#[derive(Default)]
+struct Index {
+  /// Keyed by path relative to the cache directory.
+  entries: HashMap<String, IndexEntry>,
+  total_bytes: u64,
+  /// Whether there are changes that have not been written to the index file.
+  dirty: bool,
+}
+
+impl Index {
+  fn insert(&mut self, key: String, entry: IndexEntry) {
+    self.total_bytes += entry.size_bytes;
+    if let Some(old) = self.entries.insert(key, entry) {
+      self.total_bytes -= old.size_bytes;
+    }
+    self.dirty = true;
+  }
+
+  fn remove(&mut self, key: &str) {
+    if let Some(old) = self.entries.remove(key) {
+      self.total_bytes -= old.size_bytes;
+      self.dirty = true;
+    }
+  }
+}
+
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+  pub entry_count: usize,
+  pub size_bytes: u64,
+  pub max_bytes: u64,
+}
+
+/// Files derived from file store data, such as scaled versions of images. Everything here can be
+/// regenerated, so the total size is bounded by evicting the least recently used files - see `evict`.
 pub struct FileCache {
-  _cache_dir: PathBuf
+  cache_dir: PathBuf,
+  max_bytes: u64,
+  index: Mutex<Index>,
+  hits: AtomicU64,
+  misses: AtomicU64,
+  evictions: AtomicU64,
 }
 
 impl FileCache {
-  pub fn new(cache_dir: &str) -> InfuResult<FileCache> {
-    let _cache_dir = expand_tilde(cache_dir).ok_or(format!("File cache path '{}' is not valid.", cache_dir))?;
-    Ok(FileCache { _cache_dir })
+  /// The index is reconciled with the contents of `cache_dir`, so files that were written but not yet
+  /// recorded in the index when the server last stopped are accounted for.
+  pub fn new(cache_dir: &str, max_bytes: u64) -> InfuResult<FileCache> {
+    let cache_dir = expand_tilde(cache_dir).ok_or(format!("File cache path '{}' is not valid.", cache_dir))?;
+    fs::create_dir_all(&cache_dir)?;
+
+    let mut recorded = match fs::read_to_string(cache_dir.join(INDEX_FILENAME)) {
+      Ok(s) => match serde_json::from_str::<HashMap<String, IndexEntry>>(&s) {
+        Ok(entries) => entries,
+        Err(e) => {
+          warn!("File cache index could not be read, recency of cached files is lost: {}", e);
+          HashMap::new()
+        }
+      },
+      Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+      Err(e) => return Err(e.into())
+    };
+
+    let mut index = Index::default();
+    for dir_entry in fs::read_dir(&cache_dir)? {
+      let dir_entry = dir_entry?;
+      if !dir_entry.file_type()?.is_dir() { continue; }
+      for file_entry in fs::read_dir(dir_entry.path())? {
+        let file_entry = file_entry?;
+        let path = file_entry.path();
+        if path.extension().map(|e| e == "tmp").unwrap_or(false) {
+          // Left behind by a write that did not complete.
+          let _ = fs::remove_file(&path);
+          continue;
+        }
+        let metadata = file_entry.metadata()?;
+        let key = index_key(&cache_dir, &path)?;
+        let last_accessed_ms = match recorded.remove(&key) {
+          Some(entry) => entry.last_accessed_ms,
+          None => millis_since_epoch(metadata.modified()?)?
+        };
+        index.insert(key, IndexEntry { size_bytes: metadata.len(), last_accessed_ms });
+      }
+    }
+
+    Ok(FileCache {
+      cache_dir, max_bytes, index: Mutex::new(index),
+      hits: AtomicU64::new(0), misses: AtomicU64::new(0), evictions: AtomicU64::new(0)
+    })
+  }
+
+  /// Open the version of image `id` scaled to `width_px`, if it is in the cache. `keyring` is that of the owner
+  /// of the image, if their data is encrypted.
+  pub fn open_scaled(&self, id: &Uid, width_px: u32, keyring: Option<&Keyring>) -> InfuResult<Option<ScaledImage>> {
+    for (extension, mime_type) in SCALED_FORMATS {
+      let path = self.path_for(id, width_px, extension)?;
+      if let Some((mut file, size, last_modified)) = self.open(&path)? {
+        let (data, size) = match keyring {
+          Some(keyring) => {
+            let mut encrypted = vec![];
+            file.read_to_end(&mut encrypted)?;
+            match keyring.decrypt(&encrypted, index_key(&self.cache_dir, &path)?.as_bytes()) {
+              Ok(decrypted) => { let size = decrypted.len() as u64; (ScaledImageData::Decrypted(decrypted), size) },
+              Err(e) => {
+                // Most likely written before the key was rotated. It can be recreated.
+                warn!("Could not decrypt cached file '{}', removing it: {}", path.display(), e);
+                fs::remove_file(&path)?;
+                continue;
+              }
+            }
+          },
+          None => (ScaledImageData::File(file), size)
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        return Ok(Some(ScaledImage { data, size, last_modified, mime_type }));
+      }
+    }
+    self.misses.fetch_add(1, Ordering::Relaxed);
+    Ok(None)
+  }
+
+  /// Create the version of image `id` scaled to `width_px` from the `original` image data, and open it.
+  /// This is safe to call concurrently for the same image - the version is written to a temporary file
+  /// and renamed into place, so it is never seen partially written. If `keyring` is given, it is written
+  /// encrypted with its current key.
+  pub fn create_scaled(&self, id: &Uid, width_px: u32, original: &[u8], keyring: Option<&Keyring>) -> InfuResult<ScaledImage> {
+    let img = decode(Cursor::new(original))?;
+    let height_px = ((img.height() as u64 * width_px as u64) / img.width() as u64).max(1) as u32;
+    let scaled = img.resize_exact(width_px, height_px, FilterType::Lanczos3);
+    let (extension, format, scaled) =
+      if scaled.color().has_alpha() { ("png", ImageOutputFormat::Png, DynamicImage::ImageRgba8(scaled.to_rgba8())) }
+      else { ("jpg", ImageOutputFormat::Jpeg(JPEG_QUALITY), DynamicImage::ImageRgb8(scaled.to_rgb8())) };
+    let mime_type = SCALED_FORMATS.iter().find(|(e, _)| *e == extension).unwrap().1;
+
+    let path = self.path_for(id, width_px, extension)?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    let mut temp_path = path.clone();
+    temp_path.set_extension(format!("{}.tmp", new_uid()));
+    let written = (|| -> InfuResult<Option<Vec<u8>>> {
+      let mut writer = BufWriter::new(File::create(&temp_path)?);
+      let data = match keyring {
+        Some(keyring) => {
+          let mut data = vec![];
+          scaled.write_to(&mut Cursor::new(&mut data), format)?;
+          writer.write_all(&keyring.encrypt(&data, index_key(&self.cache_dir, &path)?.as_bytes())?)?;
+          Some(data)
+        },
+        None => {
+          scaled.write_to(&mut writer, format)?;
+          None
+        }
+      };
+      writer.flush()?;
+      fs::rename(&temp_path, &path)?;
+      Ok(data)
+    })();
+    let decrypted = match written {
+      Ok(decrypted) => decrypted,
+      Err(e) => {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+      }
+    };
+
+    let (file, size, last_modified) = self.open(&path)?
+      .ok_or(format!("Scaled version of image '{}' was removed as it was written.", id))?;
+    Ok(match decrypted {
+      Some(decrypted) => ScaledImage { size: decrypted.len() as u64, data: ScaledImageData::Decrypted(decrypted), last_modified, mime_type },
+      None => ScaledImage { data: ScaledImageData::File(file), size, last_modified, mime_type }
+    })
+  }
+
+  /// Remove the least recently used files until the cache is no larger than its maximum size, then
+  /// write the index if it has changed. Returns the number of files removed.
+  pub fn evict(&self) -> InfuResult<usize> {
+    let mut index = self.index.lock().unwrap();
+    let mut evicted = 0;
+    if index.total_bytes > self.max_bytes {
+      let mut by_recency = index.entries.iter()
+        .map(|(key, entry)| (key.clone(), entry.clone()))
+        .collect::<Vec<_>>();
+      by_recency.sort_by_key(|(_, entry)| entry.last_accessed_ms);
+      for (key, _) in by_recency {
+        if index.total_bytes <= self.max_bytes { break; }
+        match fs::remove_file(self.cache_dir.join(&key)) {
+          Ok(_) => {},
+          Err(e) if e.kind() == ErrorKind::NotFound => {},
+          Err(e) => {
+            warn!("Could not evict '{}' from the file cache: {}", key, e);
+            continue;
+          }
+        }
+        index.remove(&key);
+        evicted += 1;
+      }
+      self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+    if index.dirty {
+      self.write_index(&mut index)?;
+    }
+    Ok(evicted)
+  }
+
+  /// Remove everything from the cache. Returns the number of files removed.
+  pub fn clear(&self) -> InfuResult<usize> {
+    let mut index = self.index.lock().unwrap();
+    let count = index.entries.len();
+    for dir_entry in fs::read_dir(&self.cache_dir)? {
+      let path = dir_entry?.path();
+      if path.is_dir() {
+        fs::remove_dir_all(&path)?;
+      }
+    }
+    *index = Index::default();
+    self.write_index(&mut index)?;
+    Ok(count)
   }
+
+  pub fn stats(&self) -> CacheStats {
+    let index = self.index.lock().unwrap();
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      evictions: self.evictions.load(Ordering::Relaxed),
+      entry_count: index.entries.len(),
+      size_bytes: index.total_bytes,
+      max_bytes: self.max_bytes,
+    }
+  }
+
+  /// Open the cached file at `path`, recording the access, or None if it is not there.
+  fn open(&self, path: &Path) -> InfuResult<Option<(File, u64, SystemTime)>> {
+    let key = index_key(&self.cache_dir, path)?;
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == ErrorKind::NotFound => {
+        // The file may have been evicted after being written, but before being recorded.
+        self.index.lock().unwrap().remove(&key);
+        return Ok(None);
+      },
+      Err(e) => return Err(e.into())
+    };
+    let metadata = file.metadata()?;
+    let last_accessed_ms = millis_since_epoch(SystemTime::now())?;
+    self.index.lock().unwrap().insert(key, IndexEntry { size_bytes: metadata.len(), last_accessed_ms });
+    Ok(Some((file, metadata.len(), metadata.modified()?)))
+  }
+
+  fn write_index(&self, index: &mut Index) -> InfuResult<()> {
+    let path = self.cache_dir.join(INDEX_FILENAME);
+    let mut temp_path = path.clone();
+    temp_path.set_extension("tmp");
+    fs::write(&temp_path, serde_json::to_string(&index.entries)?)?;
+    fs::rename(&temp_path, &path)?;
+    index.dirty = false;
+    Ok(())
+  }
+
+  fn path_for(&self, id: &Uid, width_px: u32, extension: &str) -> InfuResult<PathBuf> {
+    if id.len() < 2 || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+      return Err(format!("'{}' is not a valid file id.", id).into());
+    }
+    let mut path = self.cache_dir.clone();
+    path.push(&id[..2]);
+    path.push(format!("{}_{}.{}", id, width_px, extension));
+    Ok(path)
+  }
+}
+
+fn index_key(cache_dir: &Path, path: &Path) -> InfuResult<String> {
+  let relative = path.strip_prefix(cache_dir).map_err(|_| format!("'{}' is not in the file cache.", path.display()))?;
+  Ok(relative.to_str().ok_or(format!("'{}' is not a valid file cache path.", path.display()))?.to_owned())
+}
+
+fn millis_since_epoch(time: SystemTime) -> InfuResult<u64> {
+  Ok(time.duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64)
+}
+
+fn decode<R: BufRead + Seek>(data: R) -> InfuResult<DynamicImage> {
+  Ok(Reader::new(data).with_guessed_format()?.decode()?)
 }
@@ -0,0 +1,119 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use serde_json::{Map, Value};
+
+use crate::util::infu::InfuResult;
+use crate::util::json;
+use crate::util::uid::Uid;
+use super::kv_store::JsonLogSerializable;
+
+
+const ALL_JSON_FIELDS: [&str; 9] = ["__recordType", "id", "userId", "name", "tokenHash", "created", "lastUsed", "readOnly", "scopeItemId"];
+
+/// A long lived credential for scripts and integrations, which is accepted in place of a session.
+pub struct ApiToken {
+  pub id: Uid,
+  pub user_id: Uid,
+  pub name: String,
+  /// SHA-256 hash of the token string. The token string itself is not stored.
+  pub token_hash: String,
+  pub created: i64,
+  /// When the token was last used, or None if it never has been. This is only updated every few minutes
+  /// (see `ApiTokenDb::authenticate`).
+  pub last_used: Option<i64>,
+  /// If true, the token can only be used to read items and files.
+  pub read_only: bool,
+  /// If set, the token can only be used to access this item and the items under it.
+  pub scope_item_id: Option<Uid>,
+}
+
+impl Clone for ApiToken {
+  fn clone(&self) -> Self {
+    Self {
+      id: self.id.clone(),
+      user_id: self.user_id.clone(),
+      name: self.name.clone(),
+      token_hash: self.token_hash.clone(),
+      created: self.created,
+      last_used: self.last_used,
+      read_only: self.read_only,
+      scope_item_id: self.scope_item_id.clone()
+    }
+  }
+}
+
+impl JsonLogSerializable<ApiToken> for ApiToken {
+  fn value_type_identifier() -> &'static str {
+    "api-token"
+  }
+
+  fn get_id(&self) -> &String {
+    &self.id
+  }
+
+  fn to_json(&self) -> InfuResult<Map<String, Value>> {
+    let mut result = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("entry")));
+    result.insert(String::from("id"), Value::String(self.id.clone()));
+    result.insert(String::from("userId"), Value::String(self.user_id.clone()));
+    result.insert(String::from("name"), Value::String(self.name.clone()));
+    result.insert(String::from("tokenHash"), Value::String(self.token_hash.clone()));
+    result.insert(String::from("created"), Value::Number(self.created.into()));
+    if let Some(last_used) = self.last_used { result.insert(String::from("lastUsed"), Value::Number(last_used.into())); }
+    result.insert(String::from("readOnly"), Value::Bool(self.read_only));
+    if let Some(scope_item_id) = &self.scope_item_id { result.insert(String::from("scopeItemId"), Value::String(scope_item_id.clone())); }
+    Ok(result)
+  }
+
+  fn from_json(map: &Map<String, Value>) -> InfuResult<ApiToken> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?;
+    Ok(ApiToken {
+      id: json::get_string_field(map, "id")?.ok_or("'id' field was missing.")?,
+      user_id: json::get_string_field(map, "userId")?.ok_or("'userId' field was missing.")?,
+      name: json::get_string_field(map, "name")?.ok_or("'name' field was missing.")?,
+      token_hash: json::get_string_field(map, "tokenHash")?.ok_or("'tokenHash' field was missing.")?,
+      created: json::get_integer_field(map, "created")?.ok_or("'created' field was missing.")?,
+      last_used: json::get_integer_field(map, "lastUsed")?,
+      read_only: json::get_bool_field(map, "readOnly")?.ok_or("'readOnly' field was missing.")?,
+      scope_item_id: json::get_string_field(map, "scopeItemId")?,
+    })
+  }
+
+  fn create_json_update(old: &ApiToken, new: &ApiToken) -> InfuResult<Map<String, Value>> {
+    if old.id != new.id { return Err("Attempt was made to create an ApiToken update record from instances with non-matching ids.".into()); }
+    if old.user_id != new.user_id || old.token_hash != new.token_hash || old.created != new.created ||
+       old.read_only != new.read_only || old.scope_item_id != new.scope_item_id {
+      return Err(format!("Attempt was made to modify an immutable field of API token '{}'.", new.id).into());
+    }
+    let mut result: Map<String, Value> = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("update")));
+    result.insert(String::from("id"), Value::String(new.id.clone()));
+    if old.name != new.name { result.insert(String::from("name"), Value::String(new.name.clone())); }
+    if old.last_used != new.last_used {
+      if let Some(last_used) = new.last_used { result.insert(String::from("lastUsed"), Value::Number(last_used.into())); }
+    }
+    Ok(result)
+  }
+
+  fn apply_json_update(&mut self, map: &Map<String, Value>) -> InfuResult<()> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?;
+    if let Some(v) = json::get_string_field(map, "name")? { self.name = v; }
+    if let Some(v) = json::get_integer_field(map, "lastUsed")? { self.last_used = Some(v); }
+    Ok(())
+  }
+}
@@ -0,0 +1,125 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::storage::encryption::Keyring;
+use crate::util::infu::InfuResult;
+use crate::util::uid::{new_uid, Uid};
+use super::api_token::ApiToken;
+use super::kv_store::{KVStore, KVStoreConfig};
+
+
+pub const LOG_FILENAME: &str = "api_tokens.json";
+
+/// All token strings start with this, which distinguishes them from session ids.
+pub const TOKEN_PREFIX: &str = "infu_";
+
+/// The last used time of a token is only updated once it is at least this old, so that not every request
+/// writes to the log.
+const LAST_USED_RESOLUTION_SECS: i64 = 60*5;
+
+/// Db for ApiToken instances. Tokens are stored hashed, and the log is also encrypted with the server key if
+/// there is one. As with sessions, no history of changes is kept.
+/// Not threadsafe.
+pub struct ApiTokenDb {
+  store: KVStore<ApiToken>,
+  id_by_hash: HashMap<String, Uid>,
+}
+
+impl ApiTokenDb {
+  pub fn init(db_dir: &str, config: &KVStoreConfig) -> InfuResult<ApiTokenDb> {
+    let config = KVStoreConfig { history_window_secs: 0, ..config.clone() };
+    let store: KVStore<ApiToken> = KVStore::init_with_server_key(db_dir, LOG_FILENAME, &config)?;
+    let id_by_hash = store.get_iter()
+      .map(|(id, token)| (token.token_hash.clone(), id.clone()))
+      .collect::<HashMap<String, Uid>>();
+    Ok(ApiTokenDb { store, id_by_hash })
+  }
+
+  /// Create a new token for user `user_id`. Returns the token string along with it, which can't be retrieved later.
+  pub fn create(&mut self, user_id: &str, name: &str, read_only: bool, scope_item_id: Option<&str>) -> InfuResult<(ApiToken, String)> {
+    let token_string = format!("{}{}", TOKEN_PREFIX, new_uid());
+    let api_token = ApiToken {
+      id: new_uid(),
+      user_id: String::from(user_id),
+      name: String::from(name),
+      token_hash: hash_token(&token_string),
+      created: unix_now()?,
+      last_used: None,
+      read_only,
+      scope_item_id: scope_item_id.map(String::from)
+    };
+    self.store.add(api_token.clone())?;
+    self.id_by_hash.insert(api_token.token_hash.clone(), api_token.id.clone());
+    Ok((api_token, token_string))
+  }
+
+  /// The token with token string `token_string`, if there is one. This records that the token has been used.
+  pub fn authenticate(&mut self, token_string: &str) -> InfuResult<Option<ApiToken>> {
+    let id = match self.id_by_hash.get(&hash_token(token_string)) {
+      Some(id) => id.clone(),
+      None => return Ok(None)
+    };
+    let mut api_token = self.store.get(&id).ok_or(format!("API token '{}' does not exist.", id))?.clone();
+    let now = unix_now()?;
+    if api_token.last_used.map(|last_used| now - last_used >= LAST_USED_RESOLUTION_SECS).unwrap_or(true) {
+      api_token.last_used = Some(now);
+      self.store.update(api_token.clone())?;
+    }
+    Ok(Some(api_token))
+  }
+
+  /// The tokens of user `user_id`, most recently created first.
+  pub fn get_user_tokens(&self, user_id: &str) -> Vec<ApiToken> {
+    let mut tokens = self.store.get_iter()
+      .filter(|(_, api_token)| api_token.user_id == user_id)
+      .map(|(_, api_token)| api_token.clone())
+      .collect::<Vec<ApiToken>>();
+    tokens.sort_by_key(|api_token| std::cmp::Reverse(api_token.created));
+    tokens
+  }
+
+  /// Revoke token `id` of user `user_id`. Returns false if they have no such token.
+  pub fn revoke(&mut self, user_id: &str, id: &str) -> InfuResult<bool> {
+    let token_hash = match self.store.get(id) {
+      Some(api_token) if api_token.user_id == user_id => api_token.token_hash.clone(),
+      _ => return Ok(false)
+    };
+    self.store.remove(id)?;
+    self.id_by_hash.remove(&token_hash);
+    Ok(true)
+  }
+
+  /// Rewrite the log encrypted with `keyring`, see KVStore::reencrypt.
+  pub fn reencrypt(&mut self, keyring: Option<Keyring>) -> InfuResult<()> {
+    self.store.reencrypt(keyring)
+  }
+}
+
+fn hash_token(token_string: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(token_string);
+  format!("{:x}", hasher.finalize())
+}
+
+fn unix_now() -> InfuResult<i64> {
+  Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64)
+}
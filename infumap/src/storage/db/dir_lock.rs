@@ -0,0 +1,49 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::fs::{File, OpenOptions};
+use fs2::FileExt;
+
+use crate::util::fs::expand_tilde;
+use crate::util::infu::InfuResult;
+
+
+const LOCK_FILENAME: &str = "infumap.lock";
+
+/// An exclusive advisory lock on a db directory, held until the instance is dropped. Only the
+/// holder may open the logs in the directory, which stops two processes from appending to the
+/// same log and a process from working with a stale view of data written by another.
+pub struct DbDirLock {
+  _file: File
+}
+
+impl DbDirLock {
+  pub fn acquire(db_dir: &str) -> InfuResult<DbDirLock> {
+    let mut lock_path = expand_tilde(db_dir).ok_or("Could not interpret path.")?;
+    lock_path.push(LOCK_FILENAME);
+    let file = OpenOptions::new().create(true).write(true).truncate(false).open(&lock_path)?;
+    match file.try_lock_exclusive() {
+      Ok(_) => Ok(DbDirLock { _file: file }),
+      Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+        Err(format!(
+          "The db directory '{}' is in use by another Infumap process (is 'infumap web' running?). Only one process may open it at a time.",
+          db_dir).into())
+      },
+      Err(e) => Err(format!("Could not lock db directory '{}': {}", db_dir, e).into())
+    }
+  }
+}
@@ -129,10 +129,26 @@ const ITEM_TYPE_TABLE: &'static str = "table";
 const ITEM_TYPE_IMAGE: &'static str = "image";
 const ITEM_TYPE_RATING: &'static str = "rating";
 
-fn is_data_item(item_type: &str) -> bool {
+pub fn is_data_item(item_type: &str) -> bool {
   item_type == ITEM_TYPE_FILE || item_type == ITEM_TYPE_IMAGE
 }
 
+pub fn is_image_item(item_type: &str) -> bool {
+  item_type == ITEM_TYPE_IMAGE
+}
+
+/// Items that can have children.
+pub fn is_container_item(item_type: &str) -> bool {
+  item_type == ITEM_TYPE_PAGE || item_type == ITEM_TYPE_TABLE
+}
+
+/// Items that can have attachments.
+pub fn is_attachments_item(item_type: &str) -> bool {
+  item_type == ITEM_TYPE_FILE || item_type == ITEM_TYPE_NOTE ||
+  item_type == ITEM_TYPE_PAGE || item_type == ITEM_TYPE_TABLE ||
+  item_type == ITEM_TYPE_IMAGE
+}
+
 fn is_x_sizeable_item(item_type: &str) -> bool {
   item_type == ITEM_TYPE_FILE || item_type == ITEM_TYPE_NOTE ||
   item_type == ITEM_TYPE_PAGE || item_type == ITEM_TYPE_TABLE ||
@@ -149,14 +165,14 @@ fn is_titled_item(item_type: &str) -> bool {
   item_type == ITEM_TYPE_IMAGE
 }
 
-const ALL_JSON_FIELDS: [&'static str; 26] = ["__recordType",
+const ALL_JSON_FIELDS: [&'static str; 27] = ["__recordType",
   "itemType", "ownerId", "id", "parentId", "relationshipToParent",
   "creationDate", "lastModifiedDate", "ordering", "title",
   "spatialPositionGr", "spatialWidthGr", "innerSpatialWidthGr",
   "naturalAspect", "backgroundColorIndex", "popupPositionGr",
   "popupAlignmentPoint", "popupWidthGr", "url",
   "originalCreationDate", "spatialHeightGr", "imageSizePx",
-  "thumbnail", "mimeType", "fileSizeBytes", "rating"];
+  "thumbnail", "mimeType", "fileSizeBytes", "contentHash", "rating"];
 
 
 /// All-encompassing Item type and corresponding serialization / validation logic.
@@ -194,6 +210,8 @@ pub struct Item {
   pub original_creation_date: Option<i64>,
   pub mime_type: Option<String>,
   pub file_size_bytes: Option<i64>,
+  /// The hex encoded SHA-256 hash of the data. Not present for items uploaded before this was recorded.
+  pub content_hash: Option<String>,
 
   // page
   pub inner_spatial_width_gr: Option<i64>,
@@ -236,6 +254,7 @@ impl Clone for Item {
       original_creation_date: self.original_creation_date.clone(),
       mime_type: self.mime_type.clone(),
       file_size_bytes: self.file_size_bytes.clone(),
+      content_hash: self.content_hash.clone(),
       inner_spatial_width_gr: self.inner_spatial_width_gr.clone(),
       natural_aspect: self.natural_aspect.clone(),
       background_color_index: self.background_color_index.clone(),
@@ -353,6 +372,11 @@ impl JsonLogSerializable<Item> for Item {
         cannot_modify_err("fileSizeBytes", &old.id)?;
       }
     }
+    if let Some(new_content_hash) = &new.content_hash {
+      if match &old.content_hash { Some(o) => o != new_content_hash, None => { true } } {
+        cannot_modify_err("contentHash", &old.id)?;
+      }
+    }
 
     // page
     if let Some(new_inner_spatial_width_gr) = new.inner_spatial_width_gr {
@@ -502,6 +526,9 @@ impl JsonLogSerializable<Item> for Item {
     if let Ok(v) = json::get_integer_field(map, "fileSizeBytes") {
       if v.is_some() { cannot_update_err("fileSizeBytes", &self.id)?; }
     }
+    if let Ok(v) = json::get_string_field(map, "contentHash") {
+      if v.is_some() { cannot_update_err("contentHash", &self.id)?; }
+    }
 
     // page
     if let Ok(v_maybe) = json::get_integer_field(map, "innerSpatialWidthGr") {
@@ -633,6 +660,10 @@ fn to_json(item: &Item) -> InfuResult<serde_json::Map<String, serde_json::Value>
     if !is_data_item(&item.item_type) { unexpected_field_err("fileSizeBytes", &item.id, &item.item_type)? }
     result.insert(String::from("fileSizeBytes"), Value::Number(file_size_bytes.into()));
   }
+  if let Some(content_hash) = &item.content_hash {
+    if !is_data_item(&item.item_type) { unexpected_field_err("contentHash", &item.id, &item.item_type)? }
+    result.insert(String::from("contentHash"), Value::String(content_hash.clone()));
+  }
 
   // page
   if let Some(inner_spatial_width_gr) = item.inner_spatial_width_gr {
@@ -760,6 +791,10 @@ fn from_json(map: &serde_json::Map<String, serde_json::Value>) -> InfuResult<Ite
       Some(v) => { if is_data_item(&item_type) { Ok(Some(v)) } else { Err(not_applicable_err("fileSizeBytes", &item_type, &id)) } },
       None => { if is_data_item(&item_type) { Err(expected_for_err("fileSizeBytes", &item_type, &id)) } else { Ok(None) } }
     }?,
+    content_hash: match json::get_string_field(map, "contentHash")? {
+      Some(v) => { if is_data_item(&item_type) { Ok(Some(v)) } else { Err(not_applicable_err("contentHash", &item_type, &id)) } },
+      None => Ok(None)
+    }?,
 
     // page
     inner_spatial_width_gr: match json::get_integer_field(map, "innerSpatialWidthGr")? {
@@ -14,19 +14,38 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
+use crate::storage::encryption::Keyring;
+use crate::util::fs::expand_tilde;
 use crate::util::infu::InfuResult;
+use crate::util::ordering::new_ordering_at_end;
 use crate::util::uid::Uid;
-use super::item::RelationshipToParent;
-use super::kv_store::{KVStore, JsonLogSerializable};
+use super::item::{is_attachments_item, is_container_item, is_data_item, RelationshipToParent};
+use super::kv_store::{KVStore, KVStoreConfig, JsonLogSerializable, LogHistory};
 use super::item::Item;
 
 
+/// The items changed by `ItemDb::restore`, along with how they were before, so that the restore can be undone
+/// with `ItemDb::undo_restore`.
+pub struct RestoredItems {
+  /// The id of each item, and the item as it was before - or None if it was added. In the order they were changed.
+  applied: Vec<(Uid, Option<Item>)>,
+}
+
+impl RestoredItems {
+  pub fn ids(&self) -> Vec<Uid> {
+    self.applied.iter().map(|(id, _)| id.clone()).collect()
+  }
+}
+
+
 /// Db for Item instances.
 /// Not threadsafe.
 pub struct ItemDb {
   db_dir: String,
+  config: KVStoreConfig,
   store_by_user_id: HashMap<Uid, KVStore<Item>>,
 
   // indexes
@@ -36,9 +55,10 @@ pub struct ItemDb {
 }
 
 impl ItemDb {
-  pub fn init(db_dir: &str) -> ItemDb {
+  pub fn init(db_dir: &str, config: &KVStoreConfig) -> ItemDb {
     ItemDb {
       db_dir: String::from(db_dir),
+      config: config.clone(),
       store_by_user_id: HashMap::new(),
       owner_id_by_item_id: HashMap::new(),
       children_of: HashMap::new(),
@@ -50,22 +70,22 @@ impl ItemDb {
     self.store_by_user_id.contains_key(user_id)
   }
 
-  pub fn load_user_items(&mut self, user_id: &str, creating: bool) -> InfuResult<()> {
+  /// `keyring` is that of the user, if their data is encrypted.
+  pub fn load_user_items(&mut self, user_id: &str, creating: bool, keyring: Option<Keyring>) -> InfuResult<()> {
     info!("Loading items for user {}{}.", user_id, if creating { " (creating)" } else { "" });
 
-    let log_filename = String::from("items_") + &user_id + ".json";
+    let log_filename = Self::log_filename(user_id);
 
-    if creating {
-      if std::path::Path::new(&log_filename).exists() {
-        return Err(format!("Items log file already exists for user '{}'.", user_id).into());
-      }
-    } else {
-      if std::path::Path::new(&log_filename).exists() {
-        return Err(format!("Items log file does not exist for user '{}'.", user_id).into());
-      }
+    let mut log_path = expand_tilde(&self.db_dir).ok_or("Could not interpret path.")?;
+    log_path.push(&log_filename);
+    if creating && log_path.exists() {
+      return Err(format!("Items log file already exists for user '{}'.", user_id).into());
+    }
+    if !creating && !log_path.exists() {
+      return Err(format!("Items log file does not exist for user '{}'.", user_id).into());
     }
 
-    let store: KVStore<Item> = KVStore::init(&self.db_dir, &log_filename)?;
+    let store: KVStore<Item> = KVStore::init(&self.db_dir, &log_filename, &self.config, keyring)?;
     for (_id, item) in store.get_iter() {
       self.add_to_indexes(item)?;
     }
@@ -74,6 +94,16 @@ impl ItemDb {
     Ok(())
   }
 
+  pub fn log_filename(user_id: &str) -> String {
+    String::from("items_") + user_id + ".json"
+  }
+
+  pub fn compact_user_items(&mut self, user_id: &str) -> InfuResult<()> {
+    self.store_by_user_id.get_mut(user_id)
+      .ok_or(format!("Item store has not been loaded for user '{}'.", user_id))?
+      .compact()
+  }
+
   fn add_to_indexes(&mut self, item: &Item) -> InfuResult<()> {
     self.owner_id_by_item_id.insert(item.id.clone(), item.owner_id.clone());
     match &item.parent_id {
@@ -100,11 +130,6 @@ impl ItemDb {
         if item.relationship_to_parent != RelationshipToParent::NoParent {
           return Err(format!("Relationship to parent for root page item '{}' must be 'no-parent', not '{}'.", item.id, item.relationship_to_parent.to_string()).into());
         }
-        // By convention, root level items are children of themselves.
-        match self.children_of.get_mut(&item.id) {
-          Some(children) => { children.push(item.id.clone()); },
-          None => { self.children_of.insert(item.id.clone(), vec![item.id.clone()]); }
-        }
       }
     }
     Ok(())
@@ -144,27 +169,84 @@ impl ItemDb {
         if item.relationship_to_parent != RelationshipToParent::NoParent {
           return Err(format!("Relationship to parent for root page item '{}' must be 'no-parent', not '{}'.", item.id, item.relationship_to_parent.to_string()).into());
         }
-        // By convention, root level items are children of themselves.
-        let child_list = self.children_of.remove(&item.id)
-          .ok_or(format!("Root item '{}' is missing a children_of index.", item.id))?;
-        let updated_child_list = child_list.iter()
-          .filter(|el| **el == item.id).map(|v| v.clone()).collect::<Vec<String>>();
-        if updated_child_list.len() > 0 {
-          self.children_of.insert(item.id.clone(), updated_child_list);
-        }
       }
     }
 
     Ok(())
   }
 
+  /// Check that the parent of `item` exists, belongs to the same user, can have children or attachments
+  /// (depending on the relationship), and is not `item` itself or one of its descendants.
+  fn validate_parent(&self, item: &Item) -> InfuResult<()> {
+    let parent_id = match &item.parent_id {
+      Some(parent_id) => parent_id,
+      None => return Ok(())
+    };
+    let parent = self.get(parent_id)
+      .map_err(|e| format!("Parent '{}' of item '{}' is not valid: {}", parent_id, item.id, e))?;
+    validate_relationship(item, parent)?;
+
+    let mut ancestor = parent;
+    loop {
+      if ancestor.id == item.id {
+        return Err(format!("Item '{}' cannot be moved under '{}' because that is one of its descendants.", item.id, parent_id).into());
+      }
+      match &ancestor.parent_id {
+        Some(id) => { ancestor = self.get(id)?; },
+        None => return Ok(())
+      }
+    }
+  }
+
   pub fn add(&mut self, item: Item) -> InfuResult<()> {
+    self.validate_parent(&item)?;
     self.store_by_user_id.get_mut(&item.owner_id)
       .ok_or(format!("Item store has not been loaded for user '{}'.", item.owner_id))?
       .add(item.clone())?;
     self.add_to_indexes(&item)
   }
 
+  /// Remove item `id` and, recursively, all its children and attachments. Root items (a user's
+  /// root page) cannot be removed. Returns the removed items, each one after its descendants.
+  pub fn remove_with_descendants(&mut self, id: &Uid) -> InfuResult<Vec<Item>> {
+    let item = self.get(id)?;
+    if item.parent_id.is_none() {
+      return Err(format!("Item '{}' is a root item, and cannot be removed.", id).into());
+    }
+
+    // Breadth first, then reversed, so that children and attachments are removed before their parent.
+    let mut to_remove = vec![id.clone()];
+    let mut i = 0;
+    while i < to_remove.len() {
+      let parent_id = &to_remove[i];
+      let mut descendants = vec![];
+      if let Some(children) = self.children_of.get(parent_id) { descendants.extend(children.iter().cloned()); }
+      if let Some(attachments) = self.attachments_of.get(parent_id) { descendants.extend(attachments.iter().cloned()); }
+      for descendant in descendants {
+        if to_remove.contains(&descendant) {
+          return Err(format!("Item '{}' is reachable from item '{}' more than once - the item tree is corrupt.", descendant, id).into());
+        }
+        to_remove.push(descendant);
+      }
+      i += 1;
+    }
+
+    let mut removed = vec![];
+    for remove_id in to_remove.iter().rev() {
+      removed.push(self.remove(remove_id)?);
+    }
+    Ok(removed)
+  }
+
+  fn remove(&mut self, id: &Uid) -> InfuResult<Item> {
+    let item = self.get(id)?.clone();
+    self.store_by_user_id.get_mut(&item.owner_id)
+      .ok_or(format!("Item store has not been loaded for user '{}'.", item.owner_id))?
+      .remove(id)?;
+    self.remove_from_indexes(&item)?;
+    Ok(item)
+  }
+
   pub fn update(&mut self, item: &Item) -> InfuResult<()> {
     // TODO (LOW): implementation of PartialEq would be better.
     let old_item = self.store_by_user_id.get(&item.owner_id)
@@ -176,13 +258,54 @@ impl ItemDb {
       return Err(format!("Attempt was made to update item '{}', but nothing has changed.", item.id).into());
     }
 
-    self.remove_from_indexes(&item)?;
+    if old_item.parent_id != item.parent_id || old_item.relationship_to_parent != item.relationship_to_parent {
+      if old_item.parent_id.is_none() || item.parent_id.is_none() {
+        return Err(format!("Attempt was made to change whether item '{}' is a root item.", item.id).into());
+      }
+      self.validate_parent(item)?;
+    }
+
+    // The indexes reflect the item as it was, which may have had a different parent. They are only changed once
+    // the update has been written, so that they still match the store if it can't be.
+    let old_item = old_item.clone();
     self.store_by_user_id.get_mut(&item.owner_id)
       .ok_or(format!("Item store has not been loaded for user '{}'.", item.owner_id))?
       .update(item.clone())?;
+    self.remove_from_indexes(&old_item)?;
     self.add_to_indexes(item)
   }
 
+  /// Move item `id` (along with its children and attachments) to be a child or attachment of `new_parent_id`,
+  /// placed after any existing children or attachments there. Returns the moved item.
+  pub fn move_item(&mut self, id: &Uid, new_parent_id: &Uid, relationship_to_parent: RelationshipToParent) -> InfuResult<Item> {
+    let mut item = self.get(id)?.clone();
+    if item.parent_id.is_none() {
+      return Err(format!("Item '{}' is a root item, and cannot be moved.", id).into());
+    }
+    let siblings = match relationship_to_parent {
+      RelationshipToParent::Child => self.children_of.get(new_parent_id),
+      RelationshipToParent::Attachment => self.attachments_of.get(new_parent_id),
+      RelationshipToParent::NoParent => {
+        return Err(format!("Item '{}' cannot be moved to be a root item.", id).into());
+      }
+    };
+    let sibling_orderings = siblings.unwrap_or(&vec![]).iter()
+      .filter(|sibling_id| *sibling_id != id)
+      .map(|sibling_id| self.get(sibling_id).map(|sibling| &sibling.ordering))
+      .collect::<InfuResult<Vec<&Vec<u8>>>>()?;
+    let ordering = new_ordering_at_end(&sibling_orderings);
+
+    if item.parent_id.as_ref() == Some(new_parent_id) && item.relationship_to_parent == relationship_to_parent && item.ordering == ordering {
+      return Ok(item);
+    }
+    item.parent_id = Some(new_parent_id.clone());
+    item.relationship_to_parent = relationship_to_parent;
+    item.ordering = ordering;
+    item.last_modified_date = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    self.update(&item)?;
+    Ok(item)
+  }
+
   pub fn get(&self, id: &Uid) -> InfuResult<&Item> {
     let owner_id = self.owner_id_by_item_id.get(id)
       .ok_or(format!("Unknown item '{}' - corresponding user item store might not be loaded.", id))?;
@@ -191,6 +314,13 @@ impl ItemDb {
     Ok(store.get(id).ok_or(format!("Item with id '{}' is missing.", id))?)
   }
 
+  /// All items of user `user_id`, whose item store must be loaded.
+  pub fn get_all(&self, user_id: &str) -> InfuResult<Vec<&Item>> {
+    let store = self.store_by_user_id.get(user_id)
+      .ok_or(format!("Item store is not loaded for user '{}'.", user_id))?;
+    Ok(store.get_iter().map(|(_, item)| item).collect::<Vec<&Item>>())
+  }
+
   pub fn get_children(&mut self, parent_id: &Uid) -> InfuResult<Vec<&Item>> {
     let owner_id = self.owner_id_by_item_id.get(parent_id)
       .ok_or(format!("Unknown item '{}' - corresponding user item store might not be loaded.", parent_id))?;
@@ -216,4 +346,682 @@ impl ItemDb {
       .ok_or(format!("One or more attachments of '{}' are missing.", parent_id))?;
     Ok(attachments)
   }
+
+  /// The log records of item `id`, which is or was owned by `owner_id`, oldest first, with the
+  /// time each was written.
+  pub fn get_history(&self, owner_id: &str, id: &str) -> InfuResult<LogHistory> {
+    self.store_by_user_id.get(owner_id)
+      .ok_or(format!("Item store is not loaded for user '{}'.", owner_id))?
+      .get_history(id)
+  }
+
+  /// Restore item `id`, owned by `owner_id`, to how it was at `timestamp`. If `include_descendants`
+  /// is set, the items that were then its children and attachments (recursively) are restored too,
+  /// including any that have since been moved elsewhere. Items added under it since are left where
+  /// they are. Either all the items are restored, or (if that would leave the item tree invalid, or
+  /// writing to the log fails) none are. Returns the items that were changed.
+  pub fn restore(&mut self, owner_id: &str, id: &str, timestamp: i64, include_descendants: bool) -> InfuResult<RestoredItems> {
+    let past_items = self.store_by_user_id.get(owner_id)
+      .ok_or(format!("Item store is not loaded for user '{}'.", owner_id))?
+      .get_all_at(timestamp)?;
+    if !past_items.contains_key(id) {
+      return Err(format!("Item '{}' did not exist at time {}.", id, timestamp).into());
+    }
+
+    // Breadth first, so that every item is restored after its parent.
+    let mut to_restore = vec![String::from(id)];
+    if include_descendants {
+      let mut past_descendants_of: HashMap<&str, Vec<&Uid>> = HashMap::new();
+      for item in past_items.values() {
+        if let Some(parent_id) = &item.parent_id {
+          past_descendants_of.entry(parent_id.as_str()).or_default().push(&item.id);
+        }
+      }
+      let mut i = 0;
+      while i < to_restore.len() {
+        if let Some(ids) = past_descendants_of.get(to_restore[i].as_str()) {
+          to_restore.extend(ids.iter().map(|id| (*id).clone()));
+        }
+        i += 1;
+      }
+    }
+
+    self.validate_restore(&to_restore, &past_items)?;
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    // The items that have been changed, along with how they were before, so that the changes can be undone.
+    let mut applied: Vec<(Uid, Option<Item>)> = vec![];
+    for restore_id in to_restore {
+      let mut item = past_items.get(&restore_id).unwrap().clone();
+      item.last_modified_date = now;
+      let current_maybe = self.store_by_user_id.get(owner_id).unwrap().get(&restore_id).cloned();
+      let result = match &current_maybe {
+        Some(current) => {
+          let mut current = current.clone();
+          current.last_modified_date = now;
+          if Item::create_json_update(&current, &item)?.len() == 2 {
+            // "__recordType" and "id" only - nothing to restore.
+            continue;
+          }
+          self.update(&item)
+        },
+        None => self.add(item)
+      };
+      if let Err(e) = result {
+        return Err(match self.undo_restore(RestoredItems { applied }) {
+          Ok(()) => format!("Could not restore item '{}', so no items were restored: {}", restore_id, e).into(),
+          Err(undo_e) => format!("Could not restore item '{}': {}. Undoing the items already restored also failed: {}", restore_id, e, undo_e).into()
+        });
+      }
+      applied.push((restore_id, current_maybe));
+    }
+
+    Ok(RestoredItems { applied })
+  }
+
+  /// Check that restoring the items `to_restore` to how they are in `past_items` would leave a valid item tree:
+  /// that the parent of each exists (now, or because it is restored too) and can have it as a child or attachment,
+  /// that none would be under itself, that none would stop or start being a root item, and that none is a file or
+  /// image item that has since been deleted - along with its data, which can't be restored.
+  fn validate_restore(&self, to_restore: &[Uid], past_items: &HashMap<String, Item>) -> InfuResult<()> {
+    let restoring = to_restore.iter().collect::<HashSet<&Uid>>();
+    let resulting = |id: &Uid| -> Option<&Item> {
+      if restoring.contains(id) { past_items.get(id) } else { self.get(id).ok() }
+    };
+    for restore_id in to_restore {
+      let item = past_items.get(restore_id).unwrap();
+      match self.get(restore_id) {
+        Ok(current) => {
+          if current.parent_id.is_none() != item.parent_id.is_none() {
+            return Err(format!("Cannot restore item '{}' because that would change whether it is a root item.", restore_id).into());
+          }
+        },
+        Err(_) => {
+          if is_data_item(&item.item_type) {
+            return Err(format!("Cannot restore item '{}' because it has been deleted, and its data along with it.", restore_id).into());
+          }
+        }
+      }
+      let parent_id = match &item.parent_id {
+        Some(parent_id) => parent_id,
+        None => continue
+      };
+      let parent = resulting(parent_id)
+        .ok_or(format!("Cannot restore item '{}' because its parent '{}' no longer exists.", restore_id, parent_id))?;
+      validate_relationship(item, parent)?;
+
+      let mut visited = HashSet::new();
+      let mut ancestor = parent;
+      loop {
+        if &ancestor.id == restore_id {
+          return Err(format!("Cannot restore item '{}' because it would be under itself.", restore_id).into());
+        }
+        if !visited.insert(&ancestor.id) {
+          return Err(format!("Item '{}' is part of a cycle - the item tree is corrupt.", ancestor.id).into());
+        }
+        match &ancestor.parent_id {
+          Some(id) => match resulting(id) {
+            Some(next) => { ancestor = next; },
+            None => break
+          },
+          None => break
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Put the items changed by a restore back how they were, most recently changed first.
+  pub fn undo_restore(&mut self, restored: RestoredItems) -> InfuResult<()> {
+    for (id, previous) in restored.applied.into_iter().rev() {
+      match previous {
+        Some(previous) => self.update(&previous)?,
+        None => { self.remove(&id)?; }
+      }
+    }
+    Ok(())
+  }
+
+  /// Check the items of user `user_id` for problems: fields that are invalid for the item type, parents that
+  /// don't exist or can't have the item as a child or attachment, cycles, items that are not reachable from a
+  /// root item, duplicate ordering keys within a parent, and indexes that don't match the items. Returns a
+  /// description of each problem found.
+  pub fn verify(&self, user_id: &str) -> InfuResult<Vec<String>> {
+    let store = self.store_by_user_id.get(user_id)
+      .ok_or(format!("Item store is not loaded for user '{}'.", user_id))?;
+    let items = store.get_iter().collect::<HashMap<&Uid, &Item>>();
+    let mut problems = vec![];
+
+    for item in items.values() {
+      if item.owner_id != user_id {
+        problems.push(format!("Item '{}' is in the item store of user '{}', but is owned by '{}'.", item.id, user_id, item.owner_id));
+      }
+      if let Err(e) = item.to_json().and_then(|json| Item::from_json(&json)) {
+        problems.push(format!("Item '{}' has fields that are not valid for an item of type '{}': {}", item.id, item.item_type, e));
+      }
+      if self.owner_id_by_item_id.get(&item.id).map(|owner_id| owner_id.as_str()) != Some(user_id) {
+        problems.push(format!("Item '{}' is missing from the owner index.", item.id));
+      }
+
+      let parent_id = match &item.parent_id {
+        Some(parent_id) => parent_id,
+        None => continue
+      };
+      let (siblings_index, can_have_relationship): (_, fn(&str) -> bool) = match item.relationship_to_parent {
+        RelationshipToParent::Child => (&self.children_of, is_container_item),
+        RelationshipToParent::Attachment => (&self.attachments_of, is_attachments_item),
+        RelationshipToParent::NoParent => {
+          problems.push(format!("Item '{}' has a parent, but a 'no-parent' relationship to it.", item.id));
+          continue;
+        }
+      };
+      if !siblings_index.get(parent_id).map(|ids| ids.contains(&item.id)).unwrap_or(false) {
+        problems.push(format!("Item '{}' is missing from the {} index of its parent '{}'.", item.id, item.relationship_to_parent.to_string(), parent_id));
+      }
+      match items.get(parent_id) {
+        None => {
+          problems.push(format!("Item '{}' has parent '{}', which does not exist.", item.id, parent_id));
+        },
+        Some(parent) => {
+          if !can_have_relationship(&parent.item_type) {
+            problems.push(format!("Item '{}' has a '{}' relationship to parent '{}', which is not possible for an item of type '{}'.",
+              item.id, item.relationship_to_parent.to_string(), parent_id, parent.item_type));
+          }
+        }
+      }
+    }
+
+    // Index entries that don't correspond to an item with that parent and relationship.
+    for (index_name, index, relationship_to_parent) in [
+        ("child", &self.children_of, RelationshipToParent::Child),
+        ("attachment", &self.attachments_of, RelationshipToParent::Attachment)] {
+      for (parent_id, ids) in index {
+        for id in ids {
+          let indexed_correctly = match items.get(id) {
+            Some(item) => item.parent_id.as_ref() == Some(parent_id) && item.relationship_to_parent == relationship_to_parent,
+            // The indexes are shared by all users - entries for the items of other users are checked with those users.
+            None => self.owner_id_by_item_id.contains_key(id) || !items.contains_key(parent_id)
+          };
+          if !indexed_correctly {
+            problems.push(format!("The {} index of '{}' lists item '{}', which is not its {}.", index_name, parent_id, id, index_name));
+          }
+        }
+      }
+    }
+
+    // Walk up from each item until a root item (ok), a missing parent (orphaned), an item already on the
+    // path (cycle) or an item whose reachability is already known is reached.
+    #[derive(Clone, Copy, PartialEq)]
+    enum Reachability { Rooted, Orphaned, InCycle }
+    let mut reachability: HashMap<&Uid, Reachability> = HashMap::new();
+    for start_id in items.keys() {
+      let mut path: Vec<&Uid> = vec![];
+      let mut current_id = *start_id;
+      let outcome = loop {
+        if let Some(known) = reachability.get(current_id) {
+          break if *known == Reachability::Rooted { Reachability::Rooted } else { Reachability::Orphaned };
+        }
+        if let Some(position) = path.iter().position(|id| *id == current_id) {
+          let mut cycle = path.split_off(position);
+          for id in &cycle { reachability.insert(id, Reachability::InCycle); }
+          cycle.sort();
+          problems.push(format!("Items [{}] form a cycle.", cycle.iter().map(|id| id.as_str()).collect::<Vec<&str>>().join(", ")));
+          break Reachability::Orphaned;
+        }
+        path.push(current_id);
+        match &items.get(current_id).unwrap().parent_id {
+          None => break Reachability::Rooted,
+          Some(parent_id) => match items.get_key_value(parent_id) {
+            Some((parent_id, _)) => { current_id = parent_id; },
+            None => break Reachability::Orphaned
+          }
+        }
+      };
+      for id in path {
+        if outcome == Reachability::Orphaned {
+          problems.push(format!("Item '{}' is not reachable from a root item.", id));
+        }
+        reachability.insert(id, outcome);
+      }
+    }
+
+    let mut ids_by_ordering: HashMap<(&Uid, &str, &Vec<u8>), Vec<&str>> = HashMap::new();
+    for item in items.values() {
+      if let Some(parent_id) = &item.parent_id {
+        ids_by_ordering.entry((parent_id, item.relationship_to_parent.to_string(), &item.ordering)).or_default().push(&item.id);
+      }
+    }
+    for ((parent_id, relationship_to_parent, ordering), mut ids) in ids_by_ordering {
+      if ids.len() > 1 {
+        ids.sort();
+        problems.push(format!("Items [{}] have the same ordering {:?} within the {} items of '{}'.", ids.join(", "), ordering, relationship_to_parent, parent_id));
+      }
+    }
+
+    problems.sort();
+    Ok(problems)
+  }
+}
+
+
+/// Check that `parent` belongs to the same user as `item`, and can have it as a child or attachment.
+fn validate_relationship(item: &Item, parent: &Item) -> InfuResult<()> {
+  if parent.owner_id != item.owner_id {
+    return Err(format!("Parent '{}' of item '{}' belongs to a different user.", parent.id, item.id).into());
+  }
+  match item.relationship_to_parent {
+    RelationshipToParent::Child => {
+      if !is_container_item(&parent.item_type) {
+        return Err(format!("Item '{}' cannot be a child of '{}' because items of type '{}' cannot have children.", item.id, parent.id, parent.item_type).into());
+      }
+    },
+    RelationshipToParent::Attachment => {
+      if !is_attachments_item(&parent.item_type) {
+        return Err(format!("Item '{}' cannot be attached to '{}' because items of type '{}' cannot have attachments.", item.id, parent.id, parent.item_type).into());
+      }
+    },
+    RelationshipToParent::NoParent => {
+      return Err(format!("'no-parent' relationship to parent for item '{}' is not valid because it is not a root item.", item.id).into());
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use crate::util::geometry::{Vector, GRID_SIZE};
+  use crate::util::infu::InfuResult;
+  use crate::util::uid::{new_uid, Uid};
+  use super::super::item::{AlignmentPoint, Item, RelationshipToParent};
+  use super::super::kv_store::KVStoreConfig;
+  use super::ItemDb;
+
+  /// An ItemDb in a temporary directory, with the item store for one user loaded.
+  struct TestDb {
+    dir: PathBuf,
+    db: ItemDb,
+    owner_id: Uid,
+  }
+
+  impl TestDb {
+    fn new() -> TestDb {
+      let mut dir = std::env::temp_dir();
+      dir.push(format!("infumap-item-db-test-{}", new_uid()));
+      std::fs::create_dir(&dir).unwrap();
+      let owner_id = new_uid();
+      let mut db = ItemDb::init(dir.to_str().unwrap(), &config());
+      db.load_user_items(&owner_id, true, None).unwrap();
+      TestDb { dir, db, owner_id }
+    }
+
+    /// Add an item bypassing validation of its parent, to set up trees that ItemDb would not allow.
+    fn add_unchecked(&mut self, item: Item) {
+      self.db.store_by_user_id.get_mut(&item.owner_id).unwrap().add(item.clone()).unwrap();
+      self.db.add_to_indexes(&item).unwrap();
+    }
+
+    fn problems(&self) -> Vec<String> {
+      self.db.verify(&self.owner_id).unwrap()
+    }
+  }
+
+  impl Drop for TestDb {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  fn config() -> KVStoreConfig {
+    KVStoreConfig { compaction_dead_ratio: 0.0, fsync: false, history_window_secs: 0, server_keyring: None }
+  }
+
+  fn page(owner_id: &str, parent_id: Option<&Uid>, ordering: u8) -> Item {
+    Item {
+      item_type: String::from("page"),
+      owner_id: String::from(owner_id),
+      id: new_uid(),
+      parent_id: parent_id.cloned(),
+      relationship_to_parent: if parent_id.is_some() { RelationshipToParent::Child } else { RelationshipToParent::NoParent },
+      creation_date: 0,
+      last_modified_date: 0,
+      ordering: vec![ordering],
+      spatial_position_gr: Vector { x: 0, y: 0 },
+      spatial_width_gr: Some(60 * GRID_SIZE),
+      spatial_height_gr: None,
+      title: Some(String::from("page")),
+      original_creation_date: None,
+      mime_type: None,
+      file_size_bytes: None,
+      content_hash: None,
+      inner_spatial_width_gr: Some(60 * GRID_SIZE),
+      natural_aspect: Some(2.0),
+      background_color_index: Some(0),
+      popup_position_gr: Some(Vector { x: 30 * GRID_SIZE, y: 15 * GRID_SIZE }),
+      popup_alignment_point: Some(AlignmentPoint::Center),
+      popup_width_gr: Some(10 * GRID_SIZE),
+      url: None,
+      image_size_px: None,
+      thumbnail: None,
+      rating: None,
+    }
+  }
+
+  fn note(owner_id: &str, parent_id: &Uid, relationship_to_parent: RelationshipToParent, ordering: u8) -> Item {
+    Item {
+      item_type: String::from("note"),
+      owner_id: String::from(owner_id),
+      id: new_uid(),
+      parent_id: Some(parent_id.clone()),
+      relationship_to_parent,
+      creation_date: 0,
+      last_modified_date: 0,
+      ordering: vec![ordering],
+      spatial_position_gr: Vector { x: 0, y: 0 },
+      spatial_width_gr: Some(10 * GRID_SIZE),
+      spatial_height_gr: None,
+      title: Some(String::from("note")),
+      original_creation_date: None,
+      mime_type: None,
+      file_size_bytes: None,
+      content_hash: None,
+      inner_spatial_width_gr: None,
+      natural_aspect: None,
+      background_color_index: None,
+      popup_position_gr: None,
+      popup_alignment_point: None,
+      popup_width_gr: None,
+      url: Some(String::from("")),
+      image_size_px: None,
+      thumbnail: None,
+      rating: None,
+    }
+  }
+
+  fn child_ids(db: &mut ItemDb, parent_id: &Uid) -> InfuResult<Vec<Uid>> {
+    Ok(db.get_children(parent_id)?.iter().map(|item| item.id.clone()).collect())
+  }
+
+  fn attachment_ids(db: &ItemDb, parent_id: &Uid) -> InfuResult<Vec<Uid>> {
+    Ok(db.get_attachments(parent_id)?.iter().map(|item| item.id.clone()).collect())
+  }
+
+  #[test]
+  fn root_item_is_not_a_child_of_itself() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let root_id = root.id.clone();
+    t.db.add(root).unwrap();
+    assert!(child_ids(&mut t.db, &root_id).unwrap().is_empty());
+    assert!(t.db.children_of.is_empty());
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn updating_root_item_keeps_it_indexed() {
+    let mut t = TestDb::new();
+    let mut root = page(&t.owner_id, None, 128);
+    t.db.add(root.clone()).unwrap();
+    root.title = Some(String::from("renamed"));
+    t.db.update(&root).unwrap();
+    assert_eq!(t.db.get(&root.id).unwrap().title, Some(String::from("renamed")));
+    assert!(child_ids(&mut t.db, &root.id).unwrap().is_empty());
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn adding_and_removing_child_updates_children_index() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let child = note(&t.owner_id, &root.id, RelationshipToParent::Child, 128);
+    t.db.add(root.clone()).unwrap();
+    t.db.add(child.clone()).unwrap();
+    assert_eq!(child_ids(&mut t.db, &root.id).unwrap(), vec![child.id.clone()]);
+
+    t.db.remove_with_descendants(&child.id).unwrap();
+    assert!(child_ids(&mut t.db, &root.id).unwrap().is_empty());
+    assert!(!t.db.children_of.contains_key(&root.id));
+    assert!(t.db.get(&child.id).is_err());
+    assert!(t.db.get(&root.id).is_ok());
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn removing_root_item_is_refused() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    t.db.add(root.clone()).unwrap();
+    assert!(t.db.remove_with_descendants(&root.id).is_err());
+    assert!(t.db.get(&root.id).is_ok());
+  }
+
+  #[test]
+  fn removing_item_removes_descendants_from_all_indexes() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let container = page(&t.owner_id, Some(&root.id), 128);
+    let child = note(&t.owner_id, &container.id, RelationshipToParent::Child, 128);
+    let attachment = note(&t.owner_id, &container.id, RelationshipToParent::Attachment, 128);
+    for item in [&root, &container, &child, &attachment] { t.db.add((*item).clone()).unwrap(); }
+
+    let removed = t.db.remove_with_descendants(&container.id).unwrap();
+    assert_eq!(removed.len(), 3);
+    assert_eq!(removed.last().unwrap().id, container.id);
+    assert!(t.db.children_of.is_empty());
+    assert!(t.db.attachments_of.is_empty());
+    assert_eq!(t.db.owner_id_by_item_id.len(), 1);
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn moving_item_between_child_and_attachment_updates_indexes() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let container = page(&t.owner_id, Some(&root.id), 128);
+    let item = note(&t.owner_id, &root.id, RelationshipToParent::Child, 129);
+    for i in [&root, &container, &item] { t.db.add((*i).clone()).unwrap(); }
+
+    t.db.move_item(&item.id, &container.id, RelationshipToParent::Attachment).unwrap();
+    assert_eq!(child_ids(&mut t.db, &root.id).unwrap(), vec![container.id.clone()]);
+    assert_eq!(attachment_ids(&t.db, &container.id).unwrap(), vec![item.id.clone()]);
+
+    t.db.move_item(&item.id, &container.id, RelationshipToParent::Child).unwrap();
+    assert!(attachment_ids(&t.db, &container.id).unwrap().is_empty());
+    assert_eq!(child_ids(&mut t.db, &container.id).unwrap(), vec![item.id.clone()]);
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn moving_item_under_its_descendant_is_refused() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let outer = page(&t.owner_id, Some(&root.id), 128);
+    let inner = page(&t.owner_id, Some(&outer.id), 128);
+    for i in [&root, &outer, &inner] { t.db.add((*i).clone()).unwrap(); }
+
+    assert!(t.db.move_item(&outer.id, &inner.id, RelationshipToParent::Child).is_err());
+    assert!(t.db.move_item(&outer.id, &outer.id, RelationshipToParent::Child).is_err());
+    assert_eq!(child_ids(&mut t.db, &root.id).unwrap(), vec![outer.id.clone()]);
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn restore_that_would_create_cycle_changes_nothing() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let a = page(&t.owner_id, Some(&root.id), 128);
+    let b = page(&t.owner_id, Some(&a.id), 128);
+    let c = note(&t.owner_id, &b.id, RelationshipToParent::Child, 128);
+    for i in [&root, &a, &b, &c] { t.db.add((*i).clone()).unwrap(); }
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+
+    // root -> b -> a -> c, so restoring b under a would put b under itself.
+    t.db.move_item(&b.id, &root.id, RelationshipToParent::Child).unwrap();
+    t.db.move_item(&a.id, &b.id, RelationshipToParent::Child).unwrap();
+    t.db.move_item(&c.id, &a.id, RelationshipToParent::Child).unwrap();
+    assert!(t.db.restore(&t.owner_id, &b.id, timestamp, true).is_err());
+    assert_eq!(child_ids(&mut t.db, &root.id).unwrap(), vec![b.id.clone()]);
+    assert_eq!(child_ids(&mut t.db, &a.id).unwrap(), vec![c.id.clone()]);
+    assert!(t.problems().is_empty());
+
+    // Restoring a along with its descendants puts everything back as it was.
+    let restored = t.db.restore(&t.owner_id, &a.id, timestamp, true).unwrap();
+    let mut restored_ids = restored.ids();
+    restored_ids.sort();
+    let mut expected = vec![a.id.clone(), b.id.clone(), c.id.clone()];
+    expected.sort();
+    assert_eq!(restored_ids, expected);
+    assert_eq!(child_ids(&mut t.db, &b.id).unwrap(), vec![c.id.clone()]);
+    assert!(t.problems().is_empty());
+
+    // Which can be undone.
+    t.db.undo_restore(restored).unwrap();
+    assert_eq!(child_ids(&mut t.db, &root.id).unwrap(), vec![b.id.clone()]);
+    assert_eq!(child_ids(&mut t.db, &b.id).unwrap(), vec![a.id.clone()]);
+    assert_eq!(child_ids(&mut t.db, &a.id).unwrap(), vec![c.id.clone()]);
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn load_user_items_checks_for_log_in_db_dir() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let child = note(&t.owner_id, &root.id, RelationshipToParent::Child, 128);
+    t.db.add(root.clone()).unwrap();
+    t.db.add(child.clone()).unwrap();
+
+    let mut reloaded = ItemDb::init(t.dir.to_str().unwrap(), &config());
+    assert!(reloaded.load_user_items(&t.owner_id, true, None).is_err());
+    assert!(reloaded.load_user_items(&new_uid(), false, None).is_err());
+    reloaded.load_user_items(&t.owner_id, false, None).unwrap();
+    assert_eq!(child_ids(&mut reloaded, &root.id).unwrap(), vec![child.id.clone()]);
+    assert!(reloaded.verify(&t.owner_id).unwrap().is_empty());
+  }
+
+  #[test]
+  fn verify_reports_dangling_parent() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    t.db.add(root).unwrap();
+    let orphan = note(&t.owner_id, &new_uid(), RelationshipToParent::Child, 128);
+    t.add_unchecked(orphan.clone());
+
+    let problems = t.problems();
+    assert_eq!(problems.len(), 2);
+    assert!(problems.iter().any(|p| p.contains(&orphan.id) && p.contains("which does not exist")));
+    assert!(problems.iter().any(|p| p.contains(&orphan.id) && p.contains("not reachable from a root item")));
+  }
+
+  #[test]
+  fn verify_reports_cycle_and_items_under_it() {
+    let mut t = TestDb::new();
+    let mut a = page(&t.owner_id, None, 128);
+    let b = page(&t.owner_id, Some(&a.id), 128);
+    a.parent_id = Some(b.id.clone());
+    a.relationship_to_parent = RelationshipToParent::Child;
+    let under = note(&t.owner_id, &a.id, RelationshipToParent::Child, 129);
+    t.add_unchecked(a.clone());
+    t.add_unchecked(b.clone());
+    t.add_unchecked(under.clone());
+
+    let problems = t.problems();
+    assert_eq!(problems.len(), 2);
+    assert!(problems.iter().any(|p| p.contains("form a cycle") && p.contains(&a.id) && p.contains(&b.id)));
+    assert!(problems.iter().any(|p| p.contains(&under.id) && p.contains("not reachable from a root item")));
+  }
+
+  #[test]
+  fn verify_reports_duplicate_ordering() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let first = note(&t.owner_id, &root.id, RelationshipToParent::Child, 130);
+    let second = note(&t.owner_id, &root.id, RelationshipToParent::Child, 130);
+    let attachment = note(&t.owner_id, &root.id, RelationshipToParent::Attachment, 130);
+    for i in [&root, &first, &second, &attachment] { t.db.add((*i).clone()).unwrap(); }
+
+    let problems = t.problems();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("same ordering") && problems[0].contains(&first.id) && problems[0].contains(&second.id));
+  }
+
+  #[test]
+  fn verify_reports_child_of_item_that_cannot_have_children() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let parent_note = note(&t.owner_id, &root.id, RelationshipToParent::Child, 128);
+    let child = note(&t.owner_id, &parent_note.id, RelationshipToParent::Child, 128);
+    let attachment = note(&t.owner_id, &parent_note.id, RelationshipToParent::Attachment, 128);
+    t.db.add(root).unwrap();
+    t.db.add(parent_note).unwrap();
+    t.db.add(attachment).unwrap();
+    assert!(t.db.add(child.clone()).is_err());
+    t.add_unchecked(child.clone());
+
+    let problems = t.problems();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains(&child.id) && problems[0].contains("not possible for an item of type 'note'"));
+  }
+
+  #[test]
+  fn verify_reports_stale_index_entries() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    t.db.add(root.clone()).unwrap();
+    t.db.children_of.insert(root.id.clone(), vec![new_uid()]);
+
+    let problems = t.problems();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("child index"));
+  }
+
+  #[test]
+  fn failed_update_leaves_indexes_unchanged() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let a = page(&t.owner_id, Some(&root.id), 128);
+    let mut child = note(&t.owner_id, &root.id, RelationshipToParent::Child, 129);
+    for i in [&root, &a, &child] { t.db.add((*i).clone()).unwrap(); }
+
+    // Appending to the log fails if it has been replaced by a directory.
+    let log_path = t.dir.join(ItemDb::log_filename(&t.owner_id));
+    std::fs::remove_file(&log_path).unwrap();
+    std::fs::create_dir(&log_path).unwrap();
+    child.parent_id = Some(a.id.clone());
+    assert!(t.db.update(&child).is_err());
+
+    let mut root_children = child_ids(&mut t.db, &root.id).unwrap();
+    root_children.sort();
+    let mut expected = vec![a.id.clone(), child.id.clone()];
+    expected.sort();
+    assert_eq!(root_children, expected);
+    assert!(child_ids(&mut t.db, &a.id).unwrap().is_empty());
+    assert!(t.problems().is_empty());
+  }
+
+  #[test]
+  fn deleted_file_item_is_not_restored() {
+    let mut t = TestDb::new();
+    let root = page(&t.owner_id, None, 128);
+    let a = page(&t.owner_id, Some(&root.id), 128);
+    let mut file = note(&t.owner_id, &a.id, RelationshipToParent::Child, 128);
+    file.item_type = String::from("file");
+    file.mime_type = Some(String::from("text/plain"));
+    file.file_size_bytes = Some(1);
+    file.url = None;
+    file.original_creation_date = Some(0);
+    for i in [&root, &a, &file] { t.db.add((*i).clone()).unwrap(); }
+    let timestamp = std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64;
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+
+    t.db.remove_with_descendants(&a.id).unwrap();
+    let e = t.db.restore(&t.owner_id, &a.id, timestamp, true).err().unwrap().to_string();
+    assert!(e.contains(&file.id) && e.contains("has been deleted"), "{}", e);
+    assert!(t.db.get(&a.id).is_err());
+    assert!(child_ids(&mut t.db, &root.id).unwrap().is_empty());
+
+    // Without its descendants, the page can be restored.
+    assert_eq!(t.db.restore(&t.owner_id, &a.id, timestamp, false).unwrap().ids(), vec![a.id.clone()]);
+    assert!(t.problems().is_empty());
+  }
 }
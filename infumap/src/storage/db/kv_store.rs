@@ -18,16 +18,19 @@ use std::any::Any;
 use std::collections::HashMap;
 use std::collections::hash_map::Iter;
 use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::BufReader;
+use std::time::SystemTime;
 
+use config::Config;
 use serde::ser::SerializeStruct;
 use serde::Serialize;
 use serde_json::{self, Value, Map};
 use serde_json::Value::Object;
 
+use crate::storage::encryption::{EncryptionKey, Keyring};
 use crate::util::infu::{InfuError, InfuResult};
 use crate::util::fs::expand_tilde;
 use crate::util::uid::Uid;
@@ -46,86 +49,263 @@ pub trait JsonLogSerializable<T> {
 }
 
 
+/// The version of the log format written by this implementation.
+///  - 0: Newline delimited JSON records.
+///  - 1: As for version 0, but every record, including the descriptor, ends with a `__checksum` field.
+///    Entry, update and delete records appended to the log also have a `__timestamp` field.
+const LOG_VERSION: i64 = 1;
+
+/// The version of the log format of encrypted logs.
+///  - 2: As for version 1, but each record after the descriptor is encrypted. Such a record is written as the
+///    base64 encoded result of `EncryptionKey::encrypt`, with the log filename as the associated data.
+///  - 3: As for version 2, but the associated data is that given by `record_aad`, which includes the position of
+///    the record in the log. So records can't be reordered, replayed or removed undetected - other than removing
+///    those at the end of the log.
+///
+/// Version 2 logs are rewritten as version 3 when loaded.
+const ENCRYPTED_LOG_VERSION: i64 = 3;
+const ENCRYPTED_LOG_VERSION_2: i64 = 2;
+
 struct DescriptorRecord {
+  version: i64,
   value_type: String
 }
 
 impl Serialize for DescriptorRecord {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
     const NUM_FIELDS: usize = 3;
-    const VERSION: i64 = 0;
     let mut state = serializer.serialize_struct("Color", NUM_FIELDS)?;
     state.serialize_field("__recordType", "descriptor")?;
-    state.serialize_field("version", &VERSION)?;
+    state.serialize_field("version", &self.version)?;
     state.serialize_field("valueType", &self.value_type)?;
     state.end()
   }
 }
 
 
-struct DeleteRecord {
-  id: String
+/// Log records relating to a single entry, each with the time it was written, if known.
+pub type LogHistory = Vec<(Option<i64>, Map<String, Value>)>;
+
+
+/// Settings that control how a KVStore maintains its log file.
+#[derive(Clone)]
+pub struct KVStoreConfig {
+  /// Once the fraction of log records that no longer contribute to the current state reaches
+  /// this value, the log is compacted automatically. A value of 0 disables automatic compaction.
+  pub compaction_dead_ratio: f64,
+  /// Whether each appended record is flushed through to disk before the write returns.
+  pub fsync: bool,
+  /// Compaction keeps the records written in this many seconds before it runs, so that the
+  /// history of changes made over this period remains available.
+  pub history_window_secs: i64,
+  /// The key set by the encryption_key setting, or None if data is not encrypted. This encrypts the user log,
+  /// and the data key of each user, which encrypts the logs of their items and trash.
+  pub server_keyring: Option<Keyring>,
 }
 
-impl Serialize for DeleteRecord {
-  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
-    const NUM_FIELDS: usize = 2;
-    let mut state = serializer.serialize_struct("Color", NUM_FIELDS)?;
-    state.serialize_field("__recordType", "delete")?;
-    state.serialize_field("id", &self.id)?;
-    state.end()
+impl KVStoreConfig {
+  pub fn from_config(config: &Config) -> InfuResult<KVStoreConfig> {
+    let compaction_dead_ratio = config.get_float("log_compaction_dead_ratio")
+      .map_err(|e| format!("Could not read 'log_compaction_dead_ratio' setting: {}", e))?;
+    if !(0.0..1.0).contains(&compaction_dead_ratio) {
+      return Err(format!("'log_compaction_dead_ratio' setting must be >= 0 and < 1, not {}.", compaction_dead_ratio).into());
+    }
+    let fsync = config.get_bool("log_fsync")
+      .map_err(|e| format!("Could not read 'log_fsync' setting: {}", e))?;
+    let history_window_days = config.get_int("log_history_window_days")
+      .map_err(|e| format!("Could not read 'log_history_window_days' setting: {}", e))?;
+    if history_window_days < 0 {
+      return Err(format!("'log_history_window_days' setting must not be negative, not {}.", history_window_days).into());
+    }
+    let encryption_key = config.get_string("encryption_key")
+      .map_err(|e| format!("Could not read 'encryption_key' setting: {}", e))?;
+    let server_keyring = if encryption_key.is_empty() { None } else {
+      Some(Keyring::from(EncryptionKey::from_hex(&encryption_key).map_err(|e| format!("'encryption_key' setting is not valid: {}", e))?))
+    };
+    Ok(KVStoreConfig { compaction_dead_ratio, fsync, history_window_secs: history_window_days * 60 * 60 * 24, server_keyring })
   }
 }
 
 
+/// Logs are not compacted automatically until at least this many records have been appended since
+/// they were last compacted (or loaded) - the saving is not worth the rewrite.
+const MIN_RECORDS_FOR_AUTO_COMPACTION: usize = 1000;
+
+const CHECKSUM_FIELD: &str = "__checksum";
+const TIMESTAMP_FIELD: &str = "__timestamp";
+
+
 /// A pretty naive KV store implementation, but it'll probably be good enough indefinitely.
-/// TODO (MEDIUM): Lock mechanism to ensure only one KVStore instance is accessing files at any given time.
+/// Only one KVStore instance may access a given log at a time - this is enforced by the DbDirLock held by Db.
 pub struct KVStore<T> where T: JsonLogSerializable<T> {
   log_path: PathBuf,
-  map: HashMap<String, T>
+  config: KVStoreConfig,
+  /// The keys records are encrypted with, or None if the log is not encrypted.
+  keyring: Option<Keyring>,
+  map: HashMap<String, T>,
+  /// The number of entry, update and delete records in the log.
+  record_count: usize,
+  appended_since_compaction: usize,
 }
 
 impl<T> KVStore<T> where T: JsonLogSerializable<T> {
-  pub fn init(db_dir: &str, log_filename: &str) -> InfuResult<KVStore<T>> {
+  /// If `keyring` is given, the log must be encrypted with one of its keys (or not yet exist), otherwise it
+  /// must not be encrypted. A log is converted from one to the other by `reencrypt`.
+  pub fn init(db_dir: &str, log_filename: &str, config: &KVStoreConfig, keyring: Option<Keyring>) -> InfuResult<KVStore<T>> {
     let mut log_path = expand_tilde(db_dir).ok_or("Could not interpret path.")?;
     log_path.push(log_filename);
-    let path = log_path.as_path().to_str().unwrap();
-    if !std::path::Path::new(path).exists() {
-      let file = File::create(path)?;
-      let mut writer = BufWriter::new(file);
-      let descriptor = DescriptorRecord { value_type: String::from(T::value_type_identifier()) };
-      writer.write_all(serde_json::to_string(&descriptor)?.as_bytes())?;
-      writer.write_all("\n".as_bytes())?;
+    if !log_path.exists() {
+      Self::create_log(&log_path, keyring.is_some())?;
+    }
+    let (map, record_count, version) = Self::read_log(&log_path, keyring.as_ref())?;
+    if version == 0 {
+      Self::upgrade_log_from_v0(&log_path)?;
     }
-    let map = Self::read_log(path)?;
-    Ok(Self { log_path, map })
+    let mut store = Self { log_path, config: config.clone(), keyring, map, record_count, appended_since_compaction: record_count };
+    if version == ENCRYPTED_LOG_VERSION_2 {
+      store.reencrypt(store.keyring.clone())?;
+      info!("Upgraded log '{}' from version {} to version {}.", store.log_filename(), ENCRYPTED_LOG_VERSION_2, ENCRYPTED_LOG_VERSION);
+    }
+    store.compact_if_required();
+    Ok(store)
+  }
+
+  /// As `init`, for a log that is encrypted with the server key of `config`, if there is one. If there is, but the
+  /// log is not yet encrypted (e.g. because encryption has just been enabled by rotate-keys), it is encrypted here.
+  pub fn init_with_server_key(db_dir: &str, log_filename: &str, config: &KVStoreConfig) -> InfuResult<KVStore<T>> {
+    let encrypted = Self::is_encrypted(db_dir, log_filename)?;
+    let mut store = Self::init(db_dir, log_filename, config, if encrypted { config.server_keyring.clone() } else { None })?;
+    if !encrypted && config.server_keyring.is_some() {
+      store.reencrypt(config.server_keyring.clone())?;
+    }
+    Ok(store)
   }
 
   pub fn add(&mut self, entry: T) -> InfuResult<()> {
     if self.map.contains_key(entry.get_id()) {
       return Err(format!("Entry with id {} already exists.", entry.get_id()).into());
     }
-    let file = OpenOptions::new().append(true).open(&self.log_path)?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(serde_json::to_string(&entry.to_json()?)?.as_bytes())?;
-    writer.write_all("\n".as_bytes())?;
+    self.append_record(entry.to_json()?)?;
     self.map.insert(entry.get_id().clone(), entry);
+    self.compact_if_required();
     Ok(())
   }
 
-  pub fn _remove(&mut self, id: &str) -> InfuResult<()> {
+  pub fn remove(&mut self, id: &str) -> InfuResult<()> {
     if !self.map.contains_key(id) {
       return Err(format!("Entry with id {} does not exist.", id).into());
     }
-    let file = OpenOptions::new().append(true).open(&self.log_path)?;
+    let mut delete_record = Map::new();
+    delete_record.insert(String::from("__recordType"), Value::String(String::from("delete")));
+    delete_record.insert(String::from("id"), Value::String(String::from(id)));
+    self.append_record(delete_record)?;
+    self.map.remove(id).ok_or(format!("Entry with id {} does not exist (internal logic error).", id))?;
+    self.compact_if_required();
+    Ok(())
+  }
+
+  /// Append a single record to the log, stamped with the current time. The record and its
+  /// terminating newline are written with one call so a crash can only ever leave a truncated
+  /// final line, which is detected and set aside when the log is next read. If the write fails
+  /// (e.g. the disk is full), the log is truncated back to where it was, so that a partially
+  /// written line can't have later records appended to it.
+  fn append_record(&mut self, mut record: Map<String, Value>) -> InfuResult<()> {
+    record.insert(String::from(TIMESTAMP_FIELD), Value::Number(unix_now()?.into()));
+    let line = self.encode_record(&with_checksum(&serde_json::to_string(&record)?), self.record_count + 1)?;
+    let mut file = OpenOptions::new().append(true).open(&self.log_path)?;
+    let original_len = file.metadata()?.len();
+    let result = file.write_all(format!("{}\n", line).as_bytes())
+      .and_then(|_| if self.config.fsync { file.sync_data() } else { Ok(()) });
+    if let Err(e) = result {
+      if let Err(truncate_e) = file.set_len(original_len) {
+        return Err(format!("Failed to append to log '{}': {}. Truncating it back to its original length also failed: {}",
+                           self.log_path.display(), e, truncate_e).into());
+      }
+      return Err(e.into());
+    }
+    self.record_count += 1;
+    self.appended_since_compaction += 1;
+    Ok(())
+  }
+
+  /// Create a log containing just a descriptor record. This is done via a rename, so there is
+  /// never a log file on disk without a descriptor.
+  fn create_log(log_path: &Path, encrypted: bool) -> InfuResult<()> {
+    let tmp_path = sibling_path(log_path, "creating");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(format!("{}\n", Self::descriptor_line(encrypted)?).as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, log_path)?;
+    sync_parent_dir(log_path)
+  }
+
+  /// Rewrite the log as a descriptor record, followed by one entry record per value as it was at
+  /// the start of the history window, followed by the records written during the history window
+  /// unchanged. With no history window, this is just one entry record per current value.
+  /// The new log is written and synced to a temporary file which is then renamed over the old
+  /// one, so if the process dies part way through, the old log is left intact.
+  pub fn compact(&mut self) -> InfuResult<()> {
+    let mut base = HashMap::new();
+    let mut history: Vec<String> = vec![];
+    if self.config.history_window_secs > 0 {
+      let cutoff = unix_now()? - self.config.history_window_secs;
+      self.for_each_logged_record(|line, kvs, timestamp| {
+        if history.is_empty() && timestamp.unwrap_or(0) <= cutoff {
+          Self::read_log_record(&mut base, kvs)?;
+        } else {
+          history.push(String::from(line));
+        }
+        Ok(true)
+      })?;
+    }
+    let base = if self.config.history_window_secs > 0 { &base } else { &self.map };
+
+    let tmp_path = sibling_path(&self.log_path, "compacting");
+    let file = File::create(&tmp_path)?;
     let mut writer = BufWriter::new(file);
-    let delete_record = DeleteRecord { id: String::from(id) };
-    writer.write_all(serde_json::to_string(&delete_record)?.as_bytes())?;
+    writer.write_all(Self::descriptor_line(self.keyring.is_some())?.as_bytes())?;
     writer.write_all("\n".as_bytes())?;
-    self.map.remove(id).ok_or(format!("Entry with id {} does not exist (internal logic error).", id))?;
+    let mut ids = base.keys().collect::<Vec<&String>>();
+    ids.sort();
+    let mut records = vec![];
+    for id in ids {
+      records.push(with_checksum(&serde_json::to_string(&base.get(id).unwrap().to_json()?)?));
+    }
+    for (idx, record) in records.iter().chain(history.iter()).enumerate() {
+      writer.write_all(self.encode_record(record, idx + 1)?.as_bytes())?;
+      writer.write_all("\n".as_bytes())?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, &self.log_path)?;
+    sync_parent_dir(&self.log_path)?;
+
+    let compacted_record_count = base.len() + history.len();
+    info!("Compacted log '{}' from {} to {} records.", self.log_filename(), self.record_count, compacted_record_count);
+    self.record_count = compacted_record_count;
+    self.appended_since_compaction = 0;
     Ok(())
   }
 
+  fn compact_if_required(&mut self) {
+    if self.config.compaction_dead_ratio <= 0.0 || self.appended_since_compaction < MIN_RECORDS_FOR_AUTO_COMPACTION {
+      return;
+    }
+    let dead_count = self.record_count - self.map.len();
+    if (dead_count as f64) / (self.record_count as f64) < self.config.compaction_dead_ratio {
+      return;
+    }
+    // The log is still valid if compaction fails, so this is not an error for the triggering operation.
+    if let Err(e) = self.compact() {
+      error!("Automatic compaction of log '{}' failed: {}", self.log_filename(), e);
+    }
+  }
+
+  fn log_filename(&self) -> String {
+    self.log_path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+  }
+
   pub fn get_iter(&self) -> Iter<String, T> {
     self.map.iter()
   }
@@ -134,15 +314,89 @@ impl<T> KVStore<T> where T: JsonLogSerializable<T> {
     self.map.get(id)
   }
 
+  /// The log records that relate to entry `id`, oldest first, together with the time each was
+  /// written (None for records written by a version of Infumap that did not record this, or
+  /// those that summarize state prior to the history window).
+  pub fn get_history(&self, id: &str) -> InfuResult<LogHistory> {
+    let mut result = vec![];
+    self.for_each_logged_record(|_line, kvs, timestamp| {
+      if kvs.get("id").and_then(|v| v.as_str()) == Some(id) {
+        result.push((timestamp, kvs.clone()));
+      }
+      Ok(true)
+    })?;
+    Ok(result)
+  }
+
+  /// The time the most recent log record relating to each entry was written, by id, as for `get_history`. This
+  /// reads the log once, so is preferable to calling `get_history` for many entries.
+  pub fn get_last_written(&self) -> InfuResult<HashMap<String, Option<i64>>> {
+    let mut result = HashMap::new();
+    self.for_each_logged_record(|_line, kvs, timestamp| {
+      if let Some(id) = kvs.get("id").and_then(|v| v.as_str()) {
+        result.insert(String::from(id), timestamp);
+      }
+      Ok(true)
+    })?;
+    Ok(result)
+  }
+
+  /// All values as they were at `timestamp`, reconstructed from the log. Records without a
+  /// timestamp are taken to precede all others.
+  pub fn get_all_at(&self, timestamp: i64) -> InfuResult<HashMap<String, T>> {
+    let mut result = HashMap::new();
+    self.for_each_logged_record(|_line, kvs, record_timestamp| {
+      if record_timestamp.unwrap_or(0) > timestamp { return Ok(false); }
+      Self::read_log_record(&mut result, kvs)?;
+      Ok(true)
+    })?;
+    Ok(result)
+  }
+
+  /// Call `f` with each record in the log, in order, until it returns false. `f` is passed the
+  /// text of the record (decrypted, if the log is encrypted), the record with its checksum and timestamp
+  /// fields removed, and the timestamp. Unlike read_log, this expects the log to be well formed - which it is
+  /// once loaded.
+  fn for_each_logged_record<F>(&self, mut f: F) -> InfuResult<()>
+      where F: FnMut(&str, &Map<String, Value>, Option<i64>) -> InfuResult<bool> {
+    let reader = BufReader::new(File::open(&self.log_path)?);
+    let log_filename = self.log_filename();
+    // The version given by the descriptor, once it has been read.
+    let mut version = None;
+    let mut position = 0;
+    for (idx, line) in reader.lines().enumerate() {
+      let line = line?;
+      if line.trim().is_empty() { continue; }
+      let line = match version {
+        None => line,
+        Some(version) => {
+          position += 1;
+          let aad = record_aad(&log_filename, version, position);
+          String::from_utf8(decode_record(line.as_bytes(), self.keyring.as_ref(), &aad)?)
+            .map_err(|e| format!("Log record on line {} of '{}' is not valid UTF-8: {}", idx + 1, self.log_path.display(), e))?
+        }
+      };
+      let mut kvs: Map<String, Value> = serde_json::from_str(&line)
+        .map_err(|e| format!("Log record on line {} of '{}' could not be parsed: {}", idx + 1, self.log_path.display(), e))?;
+      if version.is_none() {
+        version = Some(kvs.get("version").and_then(|v| v.as_i64()).unwrap_or(0));
+      }
+      if kvs.remove(CHECKSUM_FIELD).is_some() && !checksum_is_valid(line.as_bytes()) {
+        return Err(format!("Checksum of the record on line {} of '{}' does not match its content.", idx + 1, self.log_path.display()).into());
+      }
+      let timestamp = kvs.remove(TIMESTAMP_FIELD).and_then(|v| v.as_i64());
+      if !f(&line, &kvs, timestamp)? { break; }
+    }
+    Ok(())
+  }
+
   pub fn update(&mut self, updated: T) -> InfuResult<()> {
     let update_record = T::create_json_update(
       self.map.get(updated.get_id()).ok_or(format!("Entry with id {} does not exist.",
       updated.get_id()))?, &updated)?;
-    let file = OpenOptions::new().append(true).open(&self.log_path)?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(serde_json::to_string(&update_record)?.as_bytes())?;
-    writer.write_all("\n".as_bytes())?;
+    self.append_record(update_record)?;
     self.map.insert(updated.get_id().clone(), updated);
+    self.compact_if_required();
     Ok(())
   }
 
@@ -161,8 +415,9 @@ impl<T> KVStore<T> where T: JsonLogSerializable<T> {
           .ok_or(InfuError::new("Descriptor log record does not specify a version."))?
           .as_i64()
           .ok_or(InfuError::new("Descriptor version does not have type 'number'."))?;
-        if version != 0 {
-          return Err("Descriptor version is not 0.".into());
+        if version != 0 && version != LOG_VERSION && !is_encrypted_version(version) {
+          return Err(format!("Descriptor version is {}, but only versions 0, {}, {} and {} are supported.",
+                             version, LOG_VERSION, ENCRYPTED_LOG_VERSION_2, ENCRYPTED_LOG_VERSION).into());
         }
         let value_type = kvs
           .get("valueType")
@@ -217,22 +472,611 @@ impl<T> KVStore<T> where T: JsonLogSerializable<T> {
     Ok(())
   }
 
-  fn read_log(path: &str) -> InfuResult<HashMap<String, T>> {
-    let f = BufReader::new(File::open(path)?);
-    let deserializer = serde_json::Deserializer::from_reader(f);
-    let iterator = deserializer.into_iter::<serde_json::Value>();
+  /// Read the log at `path`, returning the current state, the number of non-descriptor records
+  /// and the log format version. The log must be encrypted if and only if `keyring` is given.
+  fn read_log(path: &Path, keyring: Option<&Keyring>) -> InfuResult<(HashMap<String, T>, usize, i64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let log_filename = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
 
     let mut result: HashMap<String, T> = HashMap::new();
+    let mut record_count = 0;
+    let mut offset: u64 = 0;
+    let mut line_number = 0;
+    let mut unterminated_final_record = false;
+    let mut version = None;
+    let mut buf = vec![];
 
-    for item in iterator {
-      match item? {
-        Object(kvs) => { Self::read_log_record(&mut result, &kvs)?; },
-        unexpected_type => {
-          return Err(format!("Log record has JSON type '{:?}', but 'Object' was expected.", unexpected_type.type_id()).into());
+    loop {
+      buf.clear();
+      let num_read = reader.read_until(b'\n', &mut buf)?;
+      if num_read == 0 { break; }
+      line_number += 1;
+      let terminated = buf.last() == Some(&b'\n');
+      let line = if terminated { &buf[..num_read-1] } else { &buf[..] };
+      if line.iter().all(|b| b.is_ascii_whitespace()) {
+        offset += num_read as u64;
+        continue;
+      }
+
+      let decrypted;
+      let line = if let Some(version) = version.filter(|v| is_encrypted_version(*v)) {
+        decrypted = match decode_record(line, keyring, &record_aad(&log_filename, version, record_count + 1)) {
+          Ok(decrypted) => decrypted,
+          Err(_) if !terminated => {
+            // As below, this is an interrupted write.
+            Self::set_aside_torn_tail(path, offset, line)?;
+            break;
+          },
+          Err(e) => return Err(format!("Log record on line {} of '{}' could not be read: {}", line_number, path.display(), e).into())
+        };
+        &decrypted[..]
+      } else { line };
+
+      let mut kvs = match serde_json::from_slice::<Value>(line) {
+        Ok(Object(kvs)) => kvs,
+        Ok(unexpected_type) => {
+          return Err(format!("Log record on line {} of '{}' has JSON type '{:?}', but 'Object' was expected.",
+                             line_number, path.display(), unexpected_type.type_id()).into());
+        },
+        Err(e) => {
+          if !terminated {
+            // Only the final line can be unterminated. A record is always written together with its
+            // newline, so this is the remains of a write that was interrupted by a crash.
+            Self::set_aside_torn_tail(path, offset, line)?;
+            break;
+          }
+          return Err(format!("Log record on line {} of '{}' could not be parsed: {}", line_number, path.display(), e).into());
+        }
+      };
+
+      let is_descriptor = kvs.get("__recordType").and_then(|v| v.as_str()) == Some("descriptor");
+      let checksum_required =
+        if is_descriptor { kvs.get("version").and_then(|v| v.as_i64()) != Some(0) }
+        else { version != Some(0) };
+      kvs.remove(TIMESTAMP_FIELD);
+      if kvs.remove(CHECKSUM_FIELD).is_some() {
+        if !checksum_is_valid(line) {
+          return Err(format!("Checksum of the record on line {} of '{}' does not match its content. The record is corrupt or has been edited.",
+                             line_number, path.display()).into());
         }
+      } else if checksum_required {
+        return Err(format!("The record on line {} of '{}' does not have a checksum.", line_number, path.display()).into());
       }
+
+      Self::read_log_record(&mut result, &kvs)
+        .map_err(|e| format!("Could not apply log record on line {} of '{}': {}", line_number, path.display(), e))?;
+      if is_descriptor {
+        version = kvs.get("version").and_then(|v| v.as_i64());
+        let encrypted = version.map(is_encrypted_version).unwrap_or(false);
+        if encrypted && keyring.is_none() {
+          return Err(format!("Log '{}' is encrypted, but no key is available to decrypt it. Is the encryption_key setting missing?", path.display()).into());
+        }
+        if !encrypted && keyring.is_some() {
+          return Err(format!("Log '{}' is not encrypted. Run 'infumap rotate-keys' to encrypt existing data.", path.display()).into());
+        }
+      } else {
+        if version.is_none() {
+          return Err(format!("The first record in '{}' is not a descriptor.", path.display()).into());
+        }
+        record_count += 1;
+      }
+      unterminated_final_record = !terminated;
+      offset += num_read as u64;
     }
 
-    Ok(result)
+    if unterminated_final_record {
+      // The record is complete, only the newline is missing. Add it so the next append starts a new line.
+      let mut file = OpenOptions::new().append(true).open(path)?;
+      file.write_all("\n".as_bytes())?;
+      file.sync_data()?;
+    }
+
+    let version = version.ok_or(format!("Log '{}' does not contain a descriptor record.", path.display()))?;
+    Ok((result, record_count, version))
+  }
+
+  /// Rewrite a version 0 log as a version 1 log, keeping every record so that no history is lost.
+  /// The original is kept alongside as a backup.
+  fn upgrade_log_from_v0(path: &Path) -> InfuResult<()> {
+    let backup_path = sibling_path(path, "v0-backup");
+    std::fs::copy(path, &backup_path)?;
+
+    let tmp_path = sibling_path(path, "upgrading");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for line in BufReader::new(File::open(path)?).lines() {
+      let line = line?;
+      let record = line.trim();
+      if record.is_empty() { continue; }
+      let kvs: Map<String, Value> = serde_json::from_str(record)?;
+      if kvs.get("__recordType").and_then(|v| v.as_str()) == Some("descriptor") {
+        writer.write_all(Self::descriptor_line(false)?.as_bytes())?;
+      } else {
+        writer.write_all(with_checksum(record).as_bytes())?;
+      }
+      writer.write_all("\n".as_bytes())?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)?;
+    info!("Upgraded log '{}' from version 0 to version {}. The original has been kept at '{}'.",
+          path.display(), LOG_VERSION, backup_path.display());
+    Ok(())
+  }
+
+  /// Rewrite the log with every record encrypted with the current key of `keyring`, or not encrypted if it
+  /// is None. All records are kept, unlike for compaction. As for compaction, the new log is written to a
+  /// temporary file which is then renamed over the old one.
+  pub fn reencrypt(&mut self, keyring: Option<Keyring>) -> InfuResult<()> {
+    let mut records = vec![];
+    self.for_each_logged_record(|line, kvs, _timestamp| {
+      if kvs.get("__recordType").and_then(|v| v.as_str()) != Some("descriptor") {
+        records.push(String::from(line));
+      }
+      Ok(true)
+    })?;
+    self.keyring = keyring;
+
+    let tmp_path = sibling_path(&self.log_path, "reencrypting");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(Self::descriptor_line(self.keyring.is_some())?.as_bytes())?;
+    writer.write_all("\n".as_bytes())?;
+    for (idx, record) in records.iter().enumerate() {
+      writer.write_all(self.encode_record(record, idx + 1)?.as_bytes())?;
+      writer.write_all("\n".as_bytes())?;
+    }
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, &self.log_path)?;
+    sync_parent_dir(&self.log_path)
+  }
+
+  /// Whether the log `log_filename` in `db_dir` is encrypted. False if it does not exist.
+  pub fn is_encrypted(db_dir: &str, log_filename: &str) -> InfuResult<bool> {
+    let mut log_path = expand_tilde(db_dir).ok_or("Could not interpret path.")?;
+    log_path.push(log_filename);
+    if !log_path.exists() {
+      return Ok(false);
+    }
+    let descriptor = BufReader::new(File::open(&log_path)?).lines().next().unwrap_or(Ok(String::new()))?;
+    let kvs: Map<String, Value> = serde_json::from_str(&descriptor)
+      .map_err(|e| format!("Descriptor of '{}' could not be parsed: {}", log_path.display(), e))?;
+    Ok(kvs.get("version").and_then(|v| v.as_i64()).map(is_encrypted_version).unwrap_or(false))
+  }
+
+  /// The text of `record` as written to the log at `position` (see `record_aad`), i.e. encrypted if the log is
+  /// encrypted.
+  fn encode_record(&self, record: &str, position: usize) -> InfuResult<String> {
+    match &self.keyring {
+      Some(keyring) => Ok(base64::encode(keyring.encrypt(record.as_bytes(), &record_aad(&self.log_filename(), ENCRYPTED_LOG_VERSION, position))?)),
+      None => Ok(String::from(record))
+    }
+  }
+
+  fn descriptor_line(encrypted: bool) -> InfuResult<String> {
+    let descriptor = DescriptorRecord {
+      version: if encrypted { ENCRYPTED_LOG_VERSION } else { LOG_VERSION },
+      value_type: String::from(T::value_type_identifier())
+    };
+    Ok(with_checksum(&serde_json::to_string(&descriptor)?))
+  }
+
+  /// Move an incomplete final record out of the log at `path` into a separate file alongside it,
+  /// leaving the log ending with the last complete record.
+  fn set_aside_torn_tail(path: &Path, offset: u64, tail: &[u8]) -> InfuResult<()> {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    let torn_path = sibling_path(path, &format!("torn-{}", timestamp));
+    let mut torn_file = File::create(&torn_path)?;
+    torn_file.write_all(tail)?;
+    torn_file.sync_all()?;
+
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(offset)?;
+    file.sync_all()?;
+
+    warn!("Dropped {} bytes of incomplete trailing record from log '{}', most likely due to a crash mid-write. These have been saved to '{}'.",
+          tail.len(), path.display(), torn_path.display());
+    Ok(())
+  }
+}
+
+
+/// Add a checksum field to the end of the serialized JSON object `record`. The checksum is a
+/// CRC32 of the exact text of the record without it, so verification does not depend on how
+/// the record would be re-serialized.
+fn with_checksum(record: &str) -> String {
+  let crc = crc32fast::hash(record.as_bytes());
+  format!("{},\"{}\":\"{:08x}\"}}", &record[..record.len()-1], CHECKSUM_FIELD, crc)
+}
+
+/// Whether `line` is a record with a checksum field as written by `with_checksum`, that matches its content.
+fn checksum_is_valid(line: &[u8]) -> bool {
+  // i.e. ,"__checksum":"xxxxxxxx"}
+  let prefix = format!(",\"{}\":\"", CHECKSUM_FIELD);
+  let suffix_len = prefix.len() + 10;
+  if line.len() < suffix_len + 1 || line[line.len()-1] != b'}' { return false; }
+  let suffix_start = line.len() - suffix_len;
+  if !line[suffix_start..].starts_with(prefix.as_bytes()) { return false; }
+  let expected = match std::str::from_utf8(&line[suffix_start+prefix.len()..line.len()-2]) {
+    Ok(s) => s,
+    Err(_) => return false
+  };
+  let mut hasher = crc32fast::Hasher::new();
+  hasher.update(&line[..suffix_start]);
+  hasher.update(b"}");
+  format!("{:08x}", hasher.finalize()) == expected
+}
+
+/// The text of the record on `line` of an encrypted log, which is decrypted with `keyring`. `aad` is as given by
+/// `record_aad`. A record that is not encrypted is not accepted, as it could have been put there by anyone.
+fn decode_record(line: &[u8], keyring: Option<&Keyring>, aad: &[u8]) -> InfuResult<Vec<u8>> {
+  let keyring = match keyring {
+    Some(keyring) => keyring,
+    None => return Ok(line.to_vec())
+  };
+  let ciphertext = base64::decode(line).map_err(|_| "Record is not encrypted.")?;
+  keyring.decrypt(&ciphertext, aad)
+}
+
+/// The associated data of the record at `position` in encrypted log `log_filename` of the given format version.
+/// Positions count from 1, for the first record after the descriptor.
+fn record_aad(log_filename: &str, version: i64, position: usize) -> Vec<u8> {
+  if version == ENCRYPTED_LOG_VERSION_2 {
+    return log_filename.as_bytes().to_vec();
+  }
+  format!("{}:{}", log_filename, position).into_bytes()
+}
+
+fn is_encrypted_version(version: i64) -> bool {
+  version == ENCRYPTED_LOG_VERSION || version == ENCRYPTED_LOG_VERSION_2
+}
+
+/// The path of a file in the same directory as `path`, named by appending `suffix` to its name.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+  let mut result = path.to_path_buf();
+  let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+  result.set_file_name(format!("{}.{}", file_name, suffix));
+  result
+}
+
+fn unix_now() -> InfuResult<i64> {
+  Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64)
+}
+
+fn sync_parent_dir(path: &Path) -> InfuResult<()> {
+  if let Some(dir) = path.parent() {
+    File::open(dir)?.sync_all()?;
+  }
+  Ok(())
+}
+
+
+
+#[cfg(test)]
+mod tests {
+  use std::fs::OpenOptions;
+  use std::io::Write;
+  use std::path::PathBuf;
+  use serde_json::{Map, Value};
+  use crate::storage::encryption::{EncryptionKey, Keyring};
+  use crate::util::infu::InfuResult;
+  use crate::util::json;
+  use crate::util::uid::{new_uid, Uid};
+  use super::{with_checksum, JsonLogSerializable, KVStore, KVStoreConfig};
+
+  const LOG_FILENAME: &str = "test.json";
+
+  struct TestValue {
+    id: Uid,
+    value: String,
+  }
+
+  impl JsonLogSerializable<TestValue> for TestValue {
+    fn value_type_identifier() -> &'static str {
+      "test"
+    }
+
+    fn get_id(&self) -> &Uid {
+      &self.id
+    }
+
+    fn to_json(&self) -> InfuResult<Map<String, Value>> {
+      let mut result = Map::new();
+      result.insert(String::from("__recordType"), Value::String(String::from("entry")));
+      result.insert(String::from("id"), Value::String(self.id.clone()));
+      result.insert(String::from("value"), Value::String(self.value.clone()));
+      Ok(result)
+    }
+
+    fn from_json(map: &Map<String, Value>) -> InfuResult<TestValue> {
+      Ok(TestValue {
+        id: json::get_string_field(map, "id")?.ok_or("'id' field was missing.")?,
+        value: json::get_string_field(map, "value")?.ok_or("'value' field was missing.")?,
+      })
+    }
+
+    fn create_json_update(_old: &TestValue, new: &TestValue) -> InfuResult<Map<String, Value>> {
+      let mut result = Map::new();
+      result.insert(String::from("__recordType"), Value::String(String::from("update")));
+      result.insert(String::from("id"), Value::String(new.id.clone()));
+      result.insert(String::from("value"), Value::String(new.value.clone()));
+      Ok(result)
+    }
+
+    fn apply_json_update(&mut self, map: &Map<String, Value>) -> InfuResult<()> {
+      if let Some(value) = json::get_string_field(map, "value")? { self.value = value; }
+      Ok(())
+    }
+  }
+
+  /// A temporary db directory, removed when dropped.
+  struct TestDir {
+    dir: PathBuf,
+  }
+
+  impl TestDir {
+    fn new() -> TestDir {
+      let mut dir = std::env::temp_dir();
+      dir.push(format!("infumap-kv-store-test-{}", new_uid()));
+      std::fs::create_dir(&dir).unwrap();
+      TestDir { dir }
+    }
+
+    fn path(&self) -> &str {
+      self.dir.to_str().unwrap()
+    }
+
+    fn log_path(&self) -> PathBuf {
+      self.dir.join(LOG_FILENAME)
+    }
+
+    fn init(&self, keyring: Option<Keyring>) -> InfuResult<KVStore<TestValue>> {
+      KVStore::init(self.path(), LOG_FILENAME, &config(), keyring)
+    }
+
+    fn append_to_log(&self, bytes: &[u8]) {
+      OpenOptions::new().append(true).open(self.log_path()).unwrap().write_all(bytes).unwrap();
+    }
+
+    /// The names of the files alongside the log whose names start with `prefix`.
+    fn siblings(&self, prefix: &str) -> Vec<String> {
+      std::fs::read_dir(&self.dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+    }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  fn config() -> KVStoreConfig {
+    KVStoreConfig { compaction_dead_ratio: 0.0, fsync: false, history_window_secs: 0, server_keyring: None }
+  }
+
+  fn value(id: &str, value: &str) -> TestValue {
+    TestValue { id: String::from(id), value: String::from(value) }
+  }
+
+  fn values(store: &KVStore<TestValue>) -> Vec<(String, String)> {
+    let mut result = store.get_iter().map(|(id, v)| (id.clone(), v.value.clone())).collect::<Vec<(String, String)>>();
+    result.sort();
+    result
+  }
+
+  fn expected(values: &[(&str, &str)]) -> Vec<(String, String)> {
+    values.iter().map(|(id, v)| (String::from(*id), String::from(*v))).collect()
+  }
+
+  #[test]
+  fn torn_final_record_is_set_aside() {
+    let dir = TestDir::new();
+    let mut store = dir.init(None).unwrap();
+    store.add(value("a", "1")).unwrap();
+    store.add(value("b", "2")).unwrap();
+    let torn = br#"{"__recordType":"entry","id":"c","val"#;
+    dir.append_to_log(torn);
+
+    let mut store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1"), ("b", "2")]));
+    let torn_files = dir.siblings(&format!("{}.torn-", LOG_FILENAME));
+    assert_eq!(torn_files.len(), 1);
+    assert_eq!(std::fs::read(dir.dir.join(&torn_files[0])).unwrap(), torn.to_vec());
+
+    // The log can be appended to as normal.
+    store.add(value("c", "3")).unwrap();
+    let store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1"), ("b", "2"), ("c", "3")]));
+  }
+
+  #[test]
+  fn torn_final_encrypted_record_is_set_aside() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    let mut store = dir.init(Some(keyring.clone())).unwrap();
+    store.add(value("a", "1")).unwrap();
+    let log = std::fs::read(dir.log_path()).unwrap();
+    let last_line_start = log[..log.len()-1].iter().rposition(|b| *b == b'\n').unwrap() + 1;
+    let partial_record = log[last_line_start..last_line_start + 20].to_vec();
+    dir.append_to_log(&partial_record);
+
+    let mut store = dir.init(Some(keyring.clone())).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1")]));
+    assert_eq!(dir.siblings(&format!("{}.torn-", LOG_FILENAME)).len(), 1);
+    store.add(value("b", "2")).unwrap();
+    let store = dir.init(Some(keyring)).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1"), ("b", "2")]));
+  }
+
+  #[test]
+  fn final_record_without_newline_is_kept() {
+    let dir = TestDir::new();
+    let mut store = dir.init(None).unwrap();
+    store.add(value("a", "1")).unwrap();
+    store.add(value("b", "2")).unwrap();
+    let log = std::fs::read(dir.log_path()).unwrap();
+    std::fs::write(dir.log_path(), &log[..log.len()-1]).unwrap();
+
+    let mut store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1"), ("b", "2")]));
+    assert!(dir.siblings(&format!("{}.torn-", LOG_FILENAME)).is_empty());
+    store.add(value("c", "3")).unwrap();
+    let store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "1"), ("b", "2"), ("c", "3")]));
+  }
+
+  #[test]
+  fn version_0_log_is_upgraded() {
+    let dir = TestDir::new();
+    let v0_log = concat!(
+      "{\"__recordType\":\"descriptor\",\"version\":0,\"valueType\":\"test\"}\n",
+      "{\"__recordType\":\"entry\",\"id\":\"a\",\"value\":\"1\"}\n",
+      "{\"__recordType\":\"entry\",\"id\":\"b\",\"value\":\"2\"}\n",
+      "{\"__recordType\":\"update\",\"id\":\"a\",\"value\":\"3\"}\n",
+      "{\"__recordType\":\"delete\",\"id\":\"b\"}\n");
+    std::fs::write(dir.log_path(), v0_log).unwrap();
+
+    let store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "3")]));
+    // No history is lost.
+    assert_eq!(store.get_history("a").unwrap().len(), 2);
+    assert_eq!(std::fs::read_to_string(dir.dir.join(format!("{}.v0-backup", LOG_FILENAME))).unwrap(), v0_log);
+
+    let upgraded = std::fs::read_to_string(dir.log_path()).unwrap();
+    let lines = upgraded.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].contains("\"version\":1"));
+    assert!(lines.iter().all(|line| line.contains("\"__checksum\":")));
+
+    let mut store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "3")]));
+    store.add(value("c", "4")).unwrap();
+    let store = dir.init(None).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "3"), ("c", "4")]));
+  }
+
+  #[test]
+  fn edited_record_is_detected() {
+    let dir = TestDir::new();
+    let mut store = dir.init(None).unwrap();
+    store.add(value("a", "1")).unwrap();
+    store.add(value("b", "2")).unwrap();
+    let log = std::fs::read_to_string(dir.log_path()).unwrap();
+    std::fs::write(dir.log_path(), log.replace("\"value\":\"1\"", "\"value\":\"9\"")).unwrap();
+
+    let e = dir.init(None).err().unwrap().to_string();
+    assert!(e.contains("line 2") && e.contains("does not match its content"), "{}", e);
+  }
+
+  #[test]
+  fn record_without_checksum_is_rejected() {
+    let dir = TestDir::new();
+    let mut store = dir.init(None).unwrap();
+    store.add(value("a", "1")).unwrap();
+    dir.append_to_log(b"{\"__recordType\":\"entry\",\"id\":\"b\",\"value\":\"2\"}\n");
+
+    let e = dir.init(None).err().unwrap().to_string();
+    assert!(e.contains("line 3") && e.contains("does not have a checksum"), "{}", e);
+  }
+
+  /// Rewrite the log, with the records after the descriptor changed by `f`.
+  fn rewrite_records<F>(dir: &TestDir, f: F) where F: FnOnce(&mut Vec<String>) {
+    let log = std::fs::read_to_string(dir.log_path()).unwrap();
+    let mut lines = log.lines().map(String::from).collect::<Vec<String>>();
+    let mut records = lines.split_off(1);
+    f(&mut records);
+    lines.extend(records);
+    std::fs::write(dir.log_path(), format!("{}\n", lines.join("\n"))).unwrap();
+  }
+
+  fn encrypted_store_with_records(dir: &TestDir, keyring: &Keyring) {
+    let mut store = dir.init(Some(keyring.clone())).unwrap();
+    store.add(value("a", "1")).unwrap();
+    store.update(value("a", "2")).unwrap();
+    store.add(value("b", "3")).unwrap();
+  }
+
+  #[test]
+  fn reordered_encrypted_records_are_detected() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    encrypted_store_with_records(&dir, &keyring);
+    rewrite_records(&dir, |records| records.swap(1, 2));
+    let e = dir.init(Some(keyring)).err().unwrap().to_string();
+    assert!(e.contains("line 3") && e.contains("could not be read"), "{}", e);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn replayed_encrypted_record_is_detected() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    encrypted_store_with_records(&dir, &keyring);
+    // Re-appending the original value of "a" would otherwise revert the update.
+    rewrite_records(&dir, |records| records.push(records[0].clone()));
+    let e = dir.init(Some(keyring)).err().unwrap().to_string();
+    assert!(e.contains("line 5") && e.contains("could not be read"), "{}", e);
+  }
+
+  #[test]
+  fn removed_encrypted_record_is_detected() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    encrypted_store_with_records(&dir, &keyring);
+    rewrite_records(&dir, |records| { records.remove(1); });
+    let e = dir.init(Some(keyring)).err().unwrap().to_string();
+    assert!(e.contains("line 3") && e.contains("could not be read"), "{}", e);
+  }
+
+  #[test]
+  fn encrypted_records_are_readable_after_compaction() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    encrypted_store_with_records(&dir, &keyring);
+    let mut store = dir.init(Some(keyring.clone())).unwrap();
+    store.compact().unwrap();
+    store.add(value("c", "4")).unwrap();
+    let store = dir.init(Some(keyring)).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "2"), ("b", "3"), ("c", "4")]));
+  }
+
+  #[test]
+  fn version_2_log_is_upgraded() {
+    let dir = TestDir::new();
+    let keyring = Keyring::from(EncryptionKey::generate());
+    let encrypt = |record: &str| base64::encode(keyring.encrypt(with_checksum(record).as_bytes(), LOG_FILENAME.as_bytes()).unwrap());
+    let v2_log = format!("{}\n{}\n{}\n",
+      with_checksum("{\"__recordType\":\"descriptor\",\"version\":2,\"valueType\":\"test\"}"),
+      encrypt("{\"__recordType\":\"entry\",\"id\":\"a\",\"value\":\"1\"}"),
+      encrypt("{\"__recordType\":\"update\",\"id\":\"a\",\"value\":\"2\"}"));
+    std::fs::write(dir.log_path(), v2_log).unwrap();
+
+    let mut store = dir.init(Some(keyring.clone())).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "2")]));
+    assert_eq!(store.get_history("a").unwrap().len(), 2);
+    assert!(std::fs::read_to_string(dir.log_path()).unwrap().starts_with("{\"__recordType\":\"descriptor\",\"version\":3,"));
+    store.add(value("b", "3")).unwrap();
+    let store = dir.init(Some(keyring)).unwrap();
+    assert_eq!(values(&store), expected(&[("a", "2"), ("b", "3")]));
+  }
+
+  #[test]
+  fn last_written_matches_history() {
+    let dir = TestDir::new();
+    let mut store = dir.init(None).unwrap();
+    store.add(value("a", "1")).unwrap();
+    store.add(value("b", "2")).unwrap();
+    store.update(value("a", "3")).unwrap();
+    store.remove("b").unwrap();
+    let last_written = store.get_last_written().unwrap();
+    assert_eq!(last_written.len(), 2);
+    for id in ["a", "b"] {
+      assert_eq!(last_written[id], store.get_history(id).unwrap().last().unwrap().0);
+      assert!(last_written[id].is_some());
+    }
+  }
+}
@@ -14,32 +14,540 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::time::SystemTime;
+
+use crate::storage::encryption::{EncryptionKey, Keyring};
+use crate::util::geometry::{Vector, GRID_SIZE};
 use crate::util::infu::InfuResult;
+use crate::util::ordering::new_ordering_at_end;
+use crate::util::uid::{new_uid, Uid};
+use self::api_token::ApiToken;
+use self::api_token_db::ApiTokenDb;
+use self::dir_lock::DbDirLock;
+use self::item::{is_data_item, AlignmentPoint, Item, RelationshipToParent};
 use self::item_db::ItemDb;
+use self::kv_store::KVStoreConfig;
 use self::session_db::SessionDb;
+use self::trash::TrashEntry;
+use self::trash_db::TrashDb;
+use self::user::User;
 use self::user_db::UserDb;
 
 pub mod user;
 pub mod user_db;
 pub mod session;
 pub mod session_db;
+pub mod api_token;
+pub mod api_token_db;
 pub mod item;
 pub mod item_db;
+pub mod trash;
+pub mod trash_db;
 pub mod kv_store;
+pub mod dir_lock;
 
 
 pub struct Db {
   pub user: UserDb,
   pub item: ItemDb,
-  pub session: SessionDb
+  pub session: SessionDb,
+  pub api_token: ApiTokenDb,
+  pub trash: TrashDb,
+  server_keyring: Option<Keyring>,
+  _lock: DbDirLock,
 }
 
 impl Db {
-  pub fn new(db_dir: &str) -> InfuResult<Db> {
+  pub fn new(db_dir: &str, config: &KVStoreConfig) -> InfuResult<Db> {
+    let lock = DbDirLock::acquire(db_dir)?;
     Ok(Db {
-      user: UserDb::init(db_dir)?,
-      session: SessionDb::init(),
-      item: ItemDb::init(db_dir)
+      user: UserDb::init(db_dir, config)?,
+      session: SessionDb::init(db_dir, config)?,
+      api_token: ApiTokenDb::init(db_dir, config)?,
+      item: ItemDb::init(db_dir, config),
+      trash: TrashDb::init(db_dir, config),
+      server_keyring: config.server_keyring.clone(),
+      _lock: lock
     })
   }
+
+  /// Create a new user, along with their item store and root page. If there is a server key, the user is given
+  /// a data key that their data is encrypted with.
+  pub fn add_user(&mut self, username: &str, password: &str) -> InfuResult<User> {
+    let id = new_uid();
+    let data_keys = match &self.server_keyring {
+      Some(server_keyring) => vec![server_keyring.current().wrap_key(&EncryptionKey::generate(), &id)?],
+      None => vec![]
+    };
+    let user = User {
+      id,
+      username: String::from(username),
+      password_hash: User::compute_password_hash(password)?,
+      password_salt: String::new(),
+      root_page_id: new_uid(),
+      trash_page_id: None,
+      data_keys,
+      totp_secret: None,
+      totp_recovery_codes: vec![],
+      totp_last_step: None,
+    };
+
+    self.user.add(user.clone())
+      .map_err(|e| format!("Failed to add new user to store: {}", e))?;
+    let keyring = self.user_keyring(&user.id)?;
+    self.item.load_user_items(&user.id, true, keyring.clone())
+      .map_err(|e| format!("Failed to create item store for user: {}", e))?;
+    self.trash.load_user_trash(&user.id, keyring)
+      .map_err(|e| format!("Failed to create trash store for user: {}", e))?;
+    self.item.add(default_page(&user.id, username, user.root_page_id.clone()))
+      .map_err(|e| format!("Failed to add top level page for user '{}': {}", username, e))?;
+
+    Ok(user)
+  }
+
+  /// Load the item and trash stores of user `user_id`, if this has not already been done.
+  pub fn load_user_if_required(&mut self, user_id: &str) -> InfuResult<()> {
+    if !self.item.user_items_loaded(&String::from(user_id)) {
+      self.item.load_user_items(user_id, false, self.user_keyring(user_id)?)?;
+    }
+    if !self.trash.user_trash_loaded(user_id) {
+      self.trash.load_user_trash(user_id, self.user_keyring(user_id)?)?;
+    }
+    Ok(())
+  }
+
+  /// The keys the data of user `user_id` is encrypted with, or None if it is not encrypted.
+  pub fn user_keyring(&self, user_id: &str) -> InfuResult<Option<Keyring>> {
+    let user = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?;
+    user_keyring(user, self.server_keyring.as_ref())
+  }
+
+  /// Whether there is a server key, which is required for two-factor authentication.
+  pub fn has_server_key(&self) -> bool {
+    self.server_keyring.is_some()
+  }
+
+  /// The TOTP secret of `user`, or None if they do not have two-factor authentication enabled.
+  pub fn totp_secret(&self, user: &User) -> InfuResult<Option<Vec<u8>>> {
+    let encrypted = match &user.totp_secret {
+      Some(encrypted) => encrypted,
+      None => return Ok(None)
+    };
+    let server_keyring = self.server_keyring.as_ref()
+      .ok_or(format!("User '{}' has two-factor authentication enabled, but the encryption_key setting is not set.", user.username))?;
+    let ciphertext = base64::decode(encrypted).map_err(|e| format!("The TOTP secret of user '{}' is not valid base64: {}", user.username, e))?;
+    Ok(Some(server_keyring.decrypt(&ciphertext, totp_secret_aad(&user.id).as_bytes())?))
+  }
+
+  /// Enable two-factor authentication for user `user_id`, with TOTP secret `secret`, given the time step of the code
+  /// that confirmed it, which then can't be used to log in. Returns new recovery codes, which replace any the user
+  /// had. Only their hashes are kept, so these can't be retrieved later.
+  pub fn enable_totp(&mut self, user_id: &str, secret: &[u8], step: i64) -> InfuResult<Vec<String>> {
+    let server_keyring = self.server_keyring.as_ref()
+      .ok_or("Two-factor authentication can't be enabled unless the encryption_key setting is set.")?;
+    let mut user = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.clone();
+    let recovery_codes = user::generate_recovery_codes();
+    user.totp_secret = Some(base64::encode(server_keyring.encrypt(secret, totp_secret_aad(user_id).as_bytes())?));
+    user.totp_recovery_codes = recovery_codes.iter().map(|code| user::hash_recovery_code(code)).collect();
+    user.totp_last_step = Some(user.totp_last_step.map_or(step, |last| last.max(step)));
+    self.user.update(user)?;
+    Ok(recovery_codes)
+  }
+
+  /// If `code` is a TOTP code of user `user_id` that is accepted at `time` (seconds since the epoch), and is for a
+  /// later time step than any code they have used before, record its step and return true.
+  pub fn use_totp_code(&mut self, user_id: &str, code: &str, time: u64) -> InfuResult<bool> {
+    let mut user = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.clone();
+    let secret = self.totp_secret(&user)?.ok_or(format!("User '{}' has no TOTP secret.", user.username))?;
+    let step = match user::totp_step(&user::totp(secret, &user.username)?, code, time) {
+      Some(step) => step,
+      None => return Ok(false)
+    };
+    if user.totp_last_step.map(|last| step <= last).unwrap_or(false) {
+      return Ok(false);
+    }
+    user.totp_last_step = Some(step);
+    self.user.update(user)?;
+    Ok(true)
+  }
+
+  /// If `code` is one of the unused recovery codes of user `user_id`, use it up and return true.
+  pub fn use_totp_recovery_code(&mut self, user_id: &str, code: &str) -> InfuResult<bool> {
+    let mut user = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.clone();
+    let hash = user::hash_recovery_code(code);
+    let remaining = user.totp_recovery_codes.iter().filter(|h| **h != hash).cloned().collect::<Vec<String>>();
+    if remaining.len() == user.totp_recovery_codes.len() {
+      return Ok(false);
+    }
+    user.totp_recovery_codes = remaining;
+    self.user.update(user)?;
+    Ok(true)
+  }
+
+  /// Disable two-factor authentication for user `username`, e.g. if they have lost their authenticator and
+  /// recovery codes. Returns false if it was not enabled.
+  pub fn reset_totp(&mut self, username: &str) -> InfuResult<bool> {
+    let mut user = self.user.get_by_username(username).ok_or(format!("Unknown user '{}'.", username))?.clone();
+    if user.totp_secret.is_none() && user.totp_recovery_codes.is_empty() {
+      return Ok(false);
+    }
+    user.totp_secret = None;
+    user.totp_recovery_codes = vec![];
+    self.user.update(user)?;
+    Ok(true)
+  }
+
+  /// Create an API token for user `user_id`. If `scope_item_id` is specified, it must be an item owned by them.
+  /// Returns the token string, which is shown to the user once - only its hash is kept.
+  pub fn create_api_token(&mut self, user_id: &str, name: &str, read_only: bool, scope_item_id: Option<&str>) -> InfuResult<(ApiToken, String)> {
+    if name.trim().is_empty() {
+      return Err("API token name must not be empty.".into());
+    }
+    if let Some(scope_item_id) = scope_item_id {
+      self.load_user_if_required(user_id)?;
+      let item = self.item.get(&String::from(scope_item_id)).map_err(|_| format!("Unknown item '{}'.", scope_item_id))?;
+      if item.owner_id != user_id {
+        return Err(format!("Unknown item '{}'.", scope_item_id).into());
+      }
+    }
+    self.api_token.create(user_id, name.trim(), read_only, scope_item_id)
+  }
+
+  /// Compact the user log, and the item and trash logs of all users.
+  pub fn compact(&mut self) -> InfuResult<()> {
+    self.user.compact()?;
+    for user_id in self.all_user_ids() {
+      self.load_user_if_required(&user_id)?;
+      self.item.compact_user_items(&user_id)?;
+      self.trash.compact_user_trash(&user_id)?;
+    }
+    Ok(())
+  }
+
+  /// The file and image items of each user - those that should have file data. This fails if the items
+  /// of any user can't be loaded, rather than leave their items out.
+  pub fn data_items_by_user(&mut self) -> InfuResult<Vec<(User, Vec<Item>)>> {
+    let mut result = vec![];
+    for user_id in self.all_user_ids() {
+      self.load_user_if_required(&user_id)?;
+      let items = self.item.get_all(&user_id)?.into_iter()
+        .filter(|item| is_data_item(&item.item_type))
+        .cloned()
+        .collect::<Vec<Item>>();
+      result.push((self.user.get_by_id(&user_id).unwrap().clone(), items));
+    }
+    Ok(result)
+  }
+
+  /// Check the items of all users for problems (see `ItemDb::verify`), and that each user's root and trash
+  /// pages exist and are root items. Returns a description of each problem found.
+  pub fn check(&mut self) -> InfuResult<Vec<String>> {
+    let mut problems = vec![];
+    for user_id in self.all_user_ids() {
+      let user = self.user.get_by_id(&user_id).unwrap().clone();
+      if let Err(e) = self.load_user_if_required(&user_id) {
+        problems.push(format!("User '{}': could not load items: {}", user.username, e));
+        continue;
+      }
+      let mut user_problems = self.item.verify(&user_id)?;
+      for (description, page_id) in [("root", Some(&user.root_page_id)), ("trash", user.trash_page_id.as_ref())] {
+        if let Some(page_id) = page_id {
+          match self.item.get(page_id) {
+            Ok(page) if page.parent_id.is_none() => {},
+            Ok(_) => user_problems.push(format!("The {} page '{}' is not a root item.", description, page_id)),
+            Err(_) => user_problems.push(format!("The {} page '{}' does not exist.", description, page_id))
+          }
+        }
+      }
+      problems.extend(user_problems.iter().map(|problem| format!("User '{}': {}", user.username, problem)));
+    }
+    Ok(problems)
+  }
+
+  /// The id of the trash page of user `user_id`. This is a root level page, created the first time it is needed.
+  pub fn get_or_create_trash_page(&mut self, user_id: &str) -> InfuResult<Uid> {
+    let mut user = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.clone();
+    if let Some(trash_page_id) = &user.trash_page_id {
+      return Ok(trash_page_id.clone());
+    }
+
+    let trash_page_id = new_uid();
+    self.item.add(default_page(user_id, "Trash", trash_page_id.clone()))
+      .map_err(|e| format!("Failed to add trash page for user '{}': {}", user_id, e))?;
+    user.trash_page_id = Some(trash_page_id.clone());
+    self.user.update(user)?;
+    Ok(trash_page_id)
+  }
+
+  /// Move item `id`, owned by `user_id`, along with its children and attachments, to the user's trash page.
+  /// Where it was is remembered, so that it can be put back by `restore_trashed_item`.
+  pub fn trash_item(&mut self, user_id: &str, id: &str) -> InfuResult<()> {
+    let trash_page_id = self.get_or_create_trash_page(user_id)?;
+    let mut item = self.item.get(&String::from(id))?.clone();
+    if item.owner_id != user_id {
+      return Err(format!("Item '{}' is not owned by user '{}'.", id, user_id).into());
+    }
+    let parent_id = match &item.parent_id {
+      Some(parent_id) => parent_id.clone(),
+      None => return Err(format!("Item '{}' is a root item, and cannot be moved to the trash.", id).into())
+    };
+    if self.is_within(&trash_page_id, &parent_id)? {
+      return Err(format!("Item '{}' is already in the trash.", id).into());
+    }
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    self.trash.add(TrashEntry {
+      id: item.id.clone(),
+      owner_id: item.owner_id.clone(),
+      parent_id,
+      relationship_to_parent: item.relationship_to_parent.clone(),
+      ordering: item.ordering.clone(),
+      trashed_date: now
+    })?;
+
+    item.ordering = self.ordering_at_end_of(&trash_page_id, &RelationshipToParent::Child)?;
+    item.parent_id = Some(trash_page_id);
+    item.relationship_to_parent = RelationshipToParent::Child;
+    item.last_modified_date = now;
+    self.item.update(&item)
+  }
+
+  /// Put item `id`, which was moved to the trash of user `user_id`, back where it was. If its original parent
+  /// no longer exists, or is itself in the trash, it is put on the user's root page instead.
+  pub fn restore_trashed_item(&mut self, user_id: &str, id: &str) -> InfuResult<()> {
+    let entry = self.trash.get(user_id, id)?
+      .ok_or(format!("Item '{}' is not in the trash of user '{}'.", id, user_id))?
+      .clone();
+    let trash_page_id = self.get_or_create_trash_page(user_id)?;
+    let mut item = self.item.get(&entry.id)?.clone();
+
+    let original_parent_available =
+      self.item.get(&entry.parent_id).is_ok() && !self.is_within(&trash_page_id, &entry.parent_id)?;
+    if original_parent_available {
+      let ordering_taken = match entry.relationship_to_parent {
+        RelationshipToParent::Attachment => self.item.get_attachments(&entry.parent_id)?,
+        _ => self.item.get_children(&entry.parent_id)?
+      }.iter().any(|sibling| sibling.ordering == entry.ordering);
+      item.ordering =
+        if ordering_taken { self.ordering_at_end_of(&entry.parent_id, &entry.relationship_to_parent)? }
+        else { entry.ordering.clone() };
+      item.parent_id = Some(entry.parent_id.clone());
+      item.relationship_to_parent = entry.relationship_to_parent.clone();
+    } else {
+      let root_page_id = self.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.root_page_id.clone();
+      item.ordering = self.ordering_at_end_of(&root_page_id, &RelationshipToParent::Child)?;
+      item.parent_id = Some(root_page_id);
+      item.relationship_to_parent = RelationshipToParent::Child;
+    }
+    item.last_modified_date = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+
+    self.item.update(&item)?;
+    self.trash.remove(user_id, id)
+  }
+
+  /// Move item `id`, owned by `user_id`, to be a child or attachment of `new_parent_id`. If the item is moved
+  /// out of the trash this way, it is no longer considered to be trashed.
+  pub fn move_item(&mut self, user_id: &str, id: &Uid, new_parent_id: &Uid, relationship_to_parent: RelationshipToParent) -> InfuResult<Item> {
+    if self.item.get(id)?.owner_id != user_id {
+      return Err(format!("Item '{}' is not owned by user '{}'.", id, user_id).into());
+    }
+    let moved = self.item.move_item(id, new_parent_id, relationship_to_parent)?;
+    if self.trash.get(user_id, id)?.is_some() {
+      let trash_page_id = self.get_or_create_trash_page(user_id)?;
+      if !self.is_within(&trash_page_id, new_parent_id)? {
+        self.trash.remove(user_id, id)?;
+      }
+    }
+    Ok(moved)
+  }
+
+  /// Permanently remove everything that was moved to the trash at or before `older_than` (unix time), for all users.
+  /// Returns the removed items, so that any associated file data can be deleted.
+  pub fn purge_trash(&mut self, older_than: i64) -> InfuResult<Vec<Item>> {
+    let mut purged = vec![];
+    for user_id in self.all_user_ids() {
+      if !self.trash.user_trash_loaded(&user_id) {
+        self.trash.load_user_trash(&user_id, self.user_keyring(&user_id)?)?;
+      }
+      let expired_ids = self.trash.get_all(&user_id)?.iter()
+        .filter(|entry| entry.trashed_date <= older_than)
+        .map(|entry| entry.id.clone())
+        .collect::<Vec<Uid>>();
+      if expired_ids.is_empty() {
+        continue;
+      }
+      self.load_user_if_required(&user_id)?;
+      let trash_page_id = self.get_or_create_trash_page(&user_id)?;
+      for id in expired_ids {
+        // The item may have been deleted outright, or moved elsewhere, since it was put in the trash.
+        let still_in_trash = match self.item.get(&id) {
+          Ok(item) => match &item.parent_id {
+            Some(parent_id) => self.is_within(&trash_page_id, parent_id)?,
+            None => false
+          },
+          Err(_) => false
+        };
+        if still_in_trash {
+          purged.extend(self.item.remove_with_descendants(&id)?);
+        }
+        self.trash.remove(&user_id, &id)?;
+      }
+    }
+    Ok(purged)
+  }
+
+  /// Whether `id` is the item `ancestor_id`, or somewhere under it.
+  pub fn is_within(&self, ancestor_id: &Uid, id: &Uid) -> InfuResult<bool> {
+    let mut current_id = id.clone();
+    loop {
+      if &current_id == ancestor_id {
+        return Ok(true);
+      }
+      match &self.item.get(&current_id)?.parent_id {
+        Some(parent_id) => { current_id = parent_id.clone(); },
+        None => return Ok(false)
+      }
+    }
+  }
+
+  fn ordering_at_end_of(&mut self, parent_id: &Uid, relationship_to_parent: &RelationshipToParent) -> InfuResult<Vec<u8>> {
+    let siblings = match relationship_to_parent {
+      RelationshipToParent::Attachment => self.item.get_attachments(parent_id)?,
+      _ => self.item.get_children(parent_id)?
+    };
+    Ok(new_ordering_at_end(&siblings.iter().map(|sibling| &sibling.ordering).collect::<Vec<&Vec<u8>>>()))
+  }
+
+  fn all_user_ids(&self) -> Vec<Uid> {
+    self.user.get_iter().map(|(id, _)| id.clone()).collect::<Vec<Uid>>()
+  }
+}
+
+
+/// The keys the data of `user` is encrypted with, unwrapped with `server_keyring`. None if it is not encrypted.
+pub fn user_keyring(user: &User, server_keyring: Option<&Keyring>) -> InfuResult<Option<Keyring>> {
+  if user.data_keys.is_empty() {
+    return Ok(None);
+  }
+  let server_keyring = server_keyring
+    .ok_or(format!("The data of user '{}' is encrypted, but the encryption_key setting is not set.", user.username))?;
+  let keys = user.data_keys.iter()
+    .map(|wrapped| server_keyring.unwrap_key(wrapped, &user.id))
+    .collect::<InfuResult<Vec<EncryptionKey>>>()
+    .map_err(|e| format!("Could not unwrap data key of user '{}': {}", user.username, e))?;
+  Ok(Some(Keyring::new(keys)?))
+}
+
+/// Associates the encrypted TOTP secret of user `user_id` with them.
+pub fn totp_secret_aad(user_id: &str) -> String {
+  format!("{}-totp", user_id)
+}
+
+fn default_page(owner_id: &str, title: &str, id: Uid) -> Item {
+  Item {
+    item_type: String::from("page"),
+    owner_id: String::from(owner_id),
+    id,
+    parent_id: None,
+    relationship_to_parent: RelationshipToParent::NoParent,
+    creation_date: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
+    last_modified_date: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64,
+    ordering: vec![128],
+    spatial_position_gr: Vector { x: 0, y: 0 },
+    spatial_width_gr: Some(60 * GRID_SIZE),
+    spatial_height_gr: None,
+    title: Some(title.to_string()),
+    original_creation_date: None,
+    mime_type: None,
+    file_size_bytes: None,
+    content_hash: None,
+    inner_spatial_width_gr: Some(60 * GRID_SIZE),
+    natural_aspect: Some(2.0),
+    background_color_index: Some(0),
+    popup_position_gr: Some(Vector { x: 30 * GRID_SIZE, y: 15 * GRID_SIZE }),
+    popup_alignment_point: Some(AlignmentPoint::Center),
+    popup_width_gr: Some(10 * GRID_SIZE),
+    url: None,
+    image_size_px: None,
+    thumbnail: None,
+    rating: None,
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use crate::storage::encryption::{EncryptionKey, Keyring};
+  use crate::util::uid::new_uid;
+  use super::kv_store::KVStoreConfig;
+  use super::{user, Db};
+
+  const TIME: u64 = 1_700_000_000;
+
+  /// A temporary db directory, removed when dropped.
+  struct TestDir {
+    dir: PathBuf,
+    config: KVStoreConfig,
+  }
+
+  impl TestDir {
+    fn new() -> TestDir {
+      let mut dir = std::env::temp_dir();
+      dir.push(format!("infumap-db-test-{}", new_uid()));
+      std::fs::create_dir(&dir).unwrap();
+      let server_keyring = Some(Keyring::from(EncryptionKey::generate()));
+      TestDir { dir, config: KVStoreConfig { compaction_dead_ratio: 0.0, fsync: false, history_window_secs: 0, server_keyring } }
+    }
+
+    fn open(&self) -> Db {
+      Db::new(self.dir.to_str().unwrap(), &self.config).unwrap()
+    }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
+    }
+  }
+
+  #[test]
+  fn totp_code_is_accepted_once() {
+    let dir = TestDir::new();
+    let mut db = dir.open();
+    let user_id = db.add_user("user", "password").unwrap().id;
+    let secret = user::generate_totp_secret();
+    let totp = user::totp(secret.clone(), "user").unwrap();
+    db.enable_totp(&user_id, &secret, (TIME / totp.step) as i64).unwrap();
+
+    // The code that confirmed enabling two-factor authentication can't then be used to log in.
+    assert!(!db.use_totp_code(&user_id, &totp.generate(TIME), TIME).unwrap());
+
+    let later = TIME + 2 * totp.step;
+    let code = totp.generate(later);
+    assert!(db.use_totp_code(&user_id, &code, later).unwrap());
+    assert!(!db.use_totp_code(&user_id, &code, later).unwrap());
+    // Nor can the code of an earlier step that is still accepted, to allow for clock drift.
+    assert!(!db.use_totp_code(&user_id, &totp.generate(later - totp.step), later).unwrap());
+
+    // The step of the last code used is kept.
+    drop(db);
+    let mut db = dir.open();
+    assert!(!db.use_totp_code(&user_id, &code, later).unwrap());
+    let next = later + totp.step;
+    assert!(db.use_totp_code(&user_id, &totp.generate(next), next).unwrap());
+  }
+
+  #[test]
+  fn totp_recovery_code_is_accepted_once() {
+    let dir = TestDir::new();
+    let mut db = dir.open();
+    let user_id = db.add_user("user", "password").unwrap().id;
+    let recovery_codes = db.enable_totp(&user_id, &user::generate_totp_secret(), (TIME / 30) as i64).unwrap();
+
+    assert!(db.use_totp_recovery_code(&user_id, &recovery_codes[0].to_uppercase()).unwrap());
+    assert!(!db.use_totp_recovery_code(&user_id, &recovery_codes[0]).unwrap());
+    assert!(db.use_totp_recovery_code(&user_id, &recovery_codes[1]).unwrap());
+    assert_eq!(db.user.get_by_id(&user_id).unwrap().totp_recovery_codes.len(), recovery_codes.len() - 2);
+  }
 }
@@ -14,15 +14,46 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::util::infu::InfuResult;
+use crate::util::json;
 use crate::util::uid::Uid;
+use super::kv_store::JsonLogSerializable;
+
+
+const ALL_JSON_FIELDS: [&str; 8] = ["__recordType", "id", "userId", "expires", "username", "created", "lastSeen", "userAgent"];
 
+/// The length of a session id hash (see `hash_session_id`).
+pub const HASHED_ID_LEN: usize = 64;
 
 pub struct Session {
+  /// SHA-256 hash of the session id that is given to the client (see `hash_session_id`). The session id itself is
+  /// not stored, since it grants access to the user's data.
   pub id: Uid,
   pub user_id: Uid,
   pub expires: i64,
   pub username: String,
-  pub password: String,
+  pub created: i64,
+  /// When the session was last used. This is only updated every few minutes (see `SessionDb::get_session`).
+  pub last_seen: i64,
+  /// The User-Agent header of the login request, which identifies the device and browser the session is for.
+  pub user_agent: Option<String>,
+}
+
+impl Session {
+  /// An id for the session that can be shown to the user, and used to end it.
+  pub fn public_id(&self) -> String {
+    self.id[..16].to_string()
+  }
+}
+
+/// How session ids are stored.
+pub fn hash_session_id(session_id: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(session_id);
+  format!("{:x}", hasher.finalize())
 }
 
 impl Clone for Session {
@@ -32,7 +63,67 @@ impl Clone for Session {
       user_id: self.user_id.clone(),
       expires: self.expires.clone(),
       username: self.username.clone(),
-      password: self.password.clone()
+      created: self.created,
+      last_seen: self.last_seen,
+      user_agent: self.user_agent.clone()
+    }
+  }
+}
+
+impl JsonLogSerializable<Session> for Session {
+  fn value_type_identifier() -> &'static str {
+    "session"
+  }
+
+  fn get_id(&self) -> &String {
+    &self.id
+  }
+
+  fn to_json(&self) -> InfuResult<Map<String, Value>> {
+    let mut result = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("entry")));
+    result.insert(String::from("id"), Value::String(self.id.clone()));
+    result.insert(String::from("userId"), Value::String(self.user_id.clone()));
+    result.insert(String::from("expires"), Value::Number(self.expires.into()));
+    result.insert(String::from("username"), Value::String(self.username.clone()));
+    result.insert(String::from("created"), Value::Number(self.created.into()));
+    result.insert(String::from("lastSeen"), Value::Number(self.last_seen.into()));
+    if let Some(user_agent) = &self.user_agent { result.insert(String::from("userAgent"), Value::String(user_agent.clone())); }
+    Ok(result)
+  }
+
+  fn from_json(map: &Map<String, Value>) -> InfuResult<Session> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?;
+    Ok(Session {
+      id: json::get_string_field(map, "id")?.ok_or("'id' field was missing.")?,
+      user_id: json::get_string_field(map, "userId")?.ok_or("'userId' field was missing.")?,
+      expires: json::get_integer_field(map, "expires")?.ok_or("'expires' field was missing.")?,
+      username: json::get_string_field(map, "username")?.ok_or("'username' field was missing.")?,
+      created: json::get_integer_field(map, "created")?.ok_or("'created' field was missing.")?,
+      last_seen: json::get_integer_field(map, "lastSeen")?.ok_or("'lastSeen' field was missing.")?,
+      user_agent: json::get_string_field(map, "userAgent")?,
+    })
+  }
+
+  fn create_json_update(old: &Session, new: &Session) -> InfuResult<Map<String, Value>> {
+    if old.id != new.id { return Err("Attempt was made to create a Session update record from instances with non-matching ids.".into()); }
+    if old.user_id != new.user_id || old.created != new.created || old.user_agent != new.user_agent {
+      return Err(format!("Attempt was made to modify an immutable field of session '{}'.", new.id).into());
     }
+    let mut result: Map<String, Value> = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("update")));
+    result.insert(String::from("id"), Value::String(new.id.clone()));
+    if old.expires != new.expires { result.insert(String::from("expires"), Value::Number(new.expires.into())); }
+    if old.username != new.username { result.insert(String::from("username"), Value::String(new.username.clone())); }
+    if old.last_seen != new.last_seen { result.insert(String::from("lastSeen"), Value::Number(new.last_seen.into())); }
+    Ok(result)
+  }
+
+  fn apply_json_update(&mut self, map: &Map<String, Value>) -> InfuResult<()> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?;
+    if let Some(v) = json::get_integer_field(map, "expires")? { self.expires = v; }
+    if let Some(v) = json::get_string_field(map, "username")? { self.username = v; }
+    if let Some(v) = json::get_integer_field(map, "lastSeen")? { self.last_seen = v; }
+    Ok(())
   }
 }
@@ -14,54 +14,104 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 use std::collections::HashMap;
 
+use crate::storage::encryption::Keyring;
 use crate::util::infu::InfuResult;
 use crate::util::uid::{new_uid, Uid};
-use super::session::Session;
+use super::kv_store::{KVStore, KVStoreConfig};
+use super::session::{hash_session_id, Session, HASHED_ID_LEN};
 
 
-/// In memory Db for Session instances.
+pub const LOG_FILENAME: &str = "sessions.json";
+
+const SESSION_LIFETIME_SECS: i64 = 60*60*24*30;
+
+/// The last seen time of a session is only updated once it is at least this old, so that not every request
+/// writes to the log.
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60*5;
+
+/// Db for Session instances, by the hash of their session id. The log is encrypted with the server key, if there
+/// is one. Unlike the other logs, no history of changes is kept, and expired sessions are removed from it when it
+/// is loaded.
+/// Not threadsafe.
 pub struct SessionDb {
-  store: HashMap<Uid, Session>,
+  store: KVStore<Session>,
   ids_by_user: HashMap<String, Vec<String>>,
+  /// TOTP secrets issued by `/account/totp/enrol`, but not yet confirmed, by session id. These are
+  /// deliberately not persisted.
+  pending_totp_secrets: HashMap<Uid, Vec<u8>>,
 }
 
 impl SessionDb {
-  pub fn init() -> SessionDb {
-    SessionDb {
-      store: HashMap::new(),
-      ids_by_user: HashMap::new()
+  pub fn init(db_dir: &str, config: &KVStoreConfig) -> InfuResult<SessionDb> {
+    let config = KVStoreConfig { history_window_secs: 0, ..config.clone() };
+    let mut store: KVStore<Session> = KVStore::init_with_server_key(db_dir, LOG_FILENAME, &config)?;
+
+    let now = unix_now()?;
+    let expired_ids = store.get_iter()
+      .filter(|(_, session)| session.expires < now)
+      .map(|(id, _)| id.clone())
+      .collect::<Vec<Uid>>();
+    for id in &expired_ids {
+      store.remove(id)?;
+    }
+
+    // Earlier versions of Infumap stored session ids, rather than their hashes.
+    let unhashed_ids = store.get_iter()
+      .filter(|(id, _)| id.len() != HASHED_ID_LEN)
+      .map(|(id, _)| id.clone())
+      .collect::<Vec<Uid>>();
+    for id in &unhashed_ids {
+      let mut session = store.get(id).unwrap().clone();
+      store.remove(id)?;
+      session.id = hash_session_id(id);
+      store.add(session)?;
+    }
+
+    if !expired_ids.is_empty() || !unhashed_ids.is_empty() {
+      store.compact()?;
+    }
+
+    let mut ids_by_user: HashMap<String, Vec<String>> = HashMap::new();
+    for (id, session) in store.get_iter() {
+      ids_by_user.entry(session.user_id.clone()).or_default().push(id.clone());
     }
+    Ok(SessionDb { store, ids_by_user, pending_totp_secrets: HashMap::new() })
   }
 
-  pub fn create_session(&mut self, user_id: &str, username: &str, password: &str) -> InfuResult<Session> {
-    const THIRTY_DAYS_AS_SECONDS: u64 = 60*60*24*30;
+  /// Returns the new session, and its session id, which is given to the client - only its hash is kept.
+  pub fn create_session(&mut self, user_id: &str, username: &str, user_agent: Option<&str>) -> InfuResult<(Session, String)> {
+    let now = unix_now()?;
+    let session_id = new_uid();
     let session = Session {
-      id: new_uid(),
+      id: hash_session_id(&session_id),
       user_id: String::from(user_id),
-      expires: (SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)? + Duration::from_secs(THIRTY_DAYS_AS_SECONDS)).as_secs() as i64,
+      expires: now + SESSION_LIFETIME_SECS,
       username: String::from(username),
-      password: String::from(password)
+      created: now,
+      last_seen: now,
+      user_agent: user_agent.map(String::from)
     };
-    self.store.insert(session.id.clone(), session.clone());
+    self.store.add(session.clone())?;
 
     if !self.ids_by_user.contains_key(user_id) {
       self.ids_by_user.insert(String::from(user_id), vec![]);
     }
     self.ids_by_user.get_mut(user_id).unwrap().push(session.id.clone());
 
-    Ok(session)
+    Ok((session, session_id))
   }
 
-  pub fn _delete_session(&mut self, id: &str) -> InfuResult<()> {
+  pub fn delete_session(&mut self, id: &str) -> InfuResult<()> {
     let session =
       if let Some(session) = self.store.get(id) { session }
       else { return Err(format!("Session '{}' does not exist.", id).into()); };
     let user_id = session.user_id.clone();
   
-    self.store.remove(id);
+    self.store.remove(id)?;
+    self.pending_totp_secrets.remove(id);
     let current_ids_for_user =
       if let Some(ids) = self.ids_by_user.remove(&user_id) { ids }
       else { return Err(format!("Session '{}' does not exist in ids_by_user map.", id).into()); };
@@ -75,16 +125,158 @@ impl SessionDb {
     Ok(())
   }
 
-  pub fn get_session(&mut self, id: &Uid) -> InfuResult<Option<Session>> {
-    let session_copy =
-      if let Some(s) = self.store.get(id) { s.clone() }
+  /// Delete all sessions of user `user_id` other than `except_id`. Returns the number deleted.
+  pub fn delete_other_sessions(&mut self, user_id: &str, except_id: &str) -> InfuResult<usize> {
+    let other_ids = match self.ids_by_user.get(user_id) {
+      Some(ids) => ids.iter().filter(|id| *id != except_id).cloned().collect::<Vec<String>>(),
+      None => vec![]
+    };
+    for id in &other_ids {
+      self.delete_session(id)?;
+    }
+    Ok(other_ids.len())
+  }
+
+  /// The session with session id `session_id` (as given to the client, not its hash), if it exists and has not
+  /// expired. This records that the session has been used.
+  pub fn get_session(&mut self, session_id: &str) -> InfuResult<Option<Session>> {
+    let id = hash_session_id(session_id);
+    let mut session_copy =
+      if let Some(s) = self.store.get(&id) { s.clone() }
       else { return Ok(None); };
 
-    if session_copy.expires < SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64 {
-      self._delete_session(id)?;
-      Ok(None)
-    } else {
-      Ok(Some(session_copy))
+    let now = unix_now()?;
+    if session_copy.expires < now {
+      self.delete_session(&id)?;
+      return Ok(None);
+    }
+    if now - session_copy.last_seen >= LAST_SEEN_RESOLUTION_SECS {
+      session_copy.last_seen = now;
+      self.store.update(session_copy.clone())?;
+    }
+    Ok(Some(session_copy))
+  }
+
+  /// The sessions of user `user_id` that have not expired.
+  pub fn get_user_sessions(&self, user_id: &str) -> InfuResult<Vec<Session>> {
+    let now = unix_now()?;
+    Ok(self.ids_by_user.get(user_id).map(|ids| ids.as_slice()).unwrap_or_default().iter()
+      .filter_map(|id| self.store.get(id))
+      .filter(|session| session.expires >= now)
+      .cloned()
+      .collect())
+  }
+
+  pub fn set_pending_totp_secret(&mut self, id: &str, secret: Option<Vec<u8>>) -> InfuResult<()> {
+    if self.store.get(id).is_none() {
+      return Err(format!("Session '{}' does not exist.", id).into());
+    }
+    match secret {
+      Some(secret) => { self.pending_totp_secrets.insert(String::from(id), secret); },
+      None => { self.pending_totp_secrets.remove(id); }
+    }
+    Ok(())
+  }
+
+  pub fn pending_totp_secret(&self, id: &str) -> Option<Vec<u8>> {
+    self.pending_totp_secrets.get(id).cloned()
+  }
+
+  /// Rewrite the log encrypted with `keyring`, see KVStore::reencrypt.
+  pub fn reencrypt(&mut self, keyring: Option<Keyring>) -> InfuResult<()> {
+    self.store.reencrypt(keyring)
+  }
+}
+
+fn unix_now() -> InfuResult<i64> {
+  Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use crate::util::uid::new_uid;
+  use super::super::kv_store::{KVStore, KVStoreConfig};
+  use super::super::session::{hash_session_id, Session};
+  use super::{unix_now, SessionDb, LOG_FILENAME};
+
+  /// A temporary db directory, removed when dropped.
+  struct TestDir {
+    dir: PathBuf,
+  }
+
+  impl TestDir {
+    fn new() -> TestDir {
+      let mut dir = std::env::temp_dir();
+      dir.push(format!("infumap-session-db-test-{}", new_uid()));
+      std::fs::create_dir(&dir).unwrap();
+      TestDir { dir }
+    }
+
+    fn path(&self) -> &str {
+      self.dir.to_str().unwrap()
+    }
+  }
+
+  impl Drop for TestDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.dir);
     }
   }
+
+  fn config() -> KVStoreConfig {
+    KVStoreConfig { compaction_dead_ratio: 0.0, fsync: false, history_window_secs: 0, server_keyring: None }
+  }
+
+  fn session(id: &str, user_id: &str, expires: i64) -> Session {
+    Session {
+      id: String::from(id),
+      user_id: String::from(user_id),
+      expires,
+      username: String::from("user"),
+      created: 0,
+      last_seen: 0,
+      user_agent: None,
+    }
+  }
+
+  #[test]
+  fn unhashed_session_ids_are_migrated() {
+    let dir = TestDir::new();
+    let (session_id, expired_id, user_id) = (new_uid(), new_uid(), new_uid());
+    let now = unix_now().unwrap();
+    {
+      // As written by earlier versions of Infumap.
+      let mut store: KVStore<Session> = KVStore::init(dir.path(), LOG_FILENAME, &config(), None).unwrap();
+      store.add(session(&session_id, &user_id, now + 1000)).unwrap();
+      store.add(session(&expired_id, &user_id, now - 1000)).unwrap();
+    }
+
+    let mut sessions = SessionDb::init(dir.path(), &config()).unwrap();
+    let log = std::fs::read_to_string(dir.dir.join(LOG_FILENAME)).unwrap();
+    assert!(!log.contains(&session_id) && !log.contains(&expired_id), "{}", log);
+    assert!(log.contains(&hash_session_id(&session_id)));
+    assert_eq!(sessions.get_user_sessions(&user_id).unwrap().len(), 1);
+    assert_eq!(sessions.get_session(&session_id).unwrap().unwrap().id, hash_session_id(&session_id));
+    assert!(sessions.get_session(&hash_session_id(&session_id)).unwrap().is_none());
+    assert!(sessions.get_session(&expired_id).unwrap().is_none());
+
+    // Hashed ids are left as they are.
+    let log = std::fs::read_to_string(dir.dir.join(LOG_FILENAME)).unwrap();
+    let mut sessions = SessionDb::init(dir.path(), &config()).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.dir.join(LOG_FILENAME)).unwrap(), log);
+    assert!(sessions.get_session(&session_id).unwrap().is_some());
+  }
+
+  #[test]
+  fn session_id_is_not_stored() {
+    let dir = TestDir::new();
+    let mut sessions = SessionDb::init(dir.path(), &config()).unwrap();
+    let (session, session_id) = sessions.create_session("user-id", "user", None).unwrap();
+    assert_eq!(session.id, hash_session_id(&session_id));
+    assert!(!std::fs::read_to_string(dir.dir.join(LOG_FILENAME)).unwrap().contains(&session_id));
+    assert!(sessions.get_session(&session_id).unwrap().is_some());
+    assert!(sessions.get_session(&session.id).unwrap().is_none());
+  }
 }
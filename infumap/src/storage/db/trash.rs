@@ -0,0 +1,102 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::{Map, Value};
+
+use crate::util::infu::InfuResult;
+use crate::util::json;
+use crate::util::uid::Uid;
+use super::item::RelationshipToParent;
+use super::kv_store::JsonLogSerializable;
+
+
+const ALL_JSON_FIELDS: [&str; 7] = ["__recordType", "id", "ownerId", "parentId", "relationshipToParent", "ordering", "trashedDate"];
+
+/// Where an item was before it was moved to its owner's trash page, so that it can be put back.
+/// Keyed on the id of the trashed item.
+pub struct TrashEntry {
+  pub id: Uid,
+  pub owner_id: Uid,
+  pub parent_id: Uid,
+  pub relationship_to_parent: RelationshipToParent,
+  pub ordering: Vec<u8>,
+  pub trashed_date: i64,
+}
+
+impl Clone for TrashEntry {
+  fn clone(&self) -> Self {
+    Self {
+      id: self.id.clone(),
+      owner_id: self.owner_id.clone(),
+      parent_id: self.parent_id.clone(),
+      relationship_to_parent: self.relationship_to_parent.clone(),
+      ordering: self.ordering.clone(),
+      trashed_date: self.trashed_date
+    }
+  }
+}
+
+impl JsonLogSerializable<TrashEntry> for TrashEntry {
+  fn value_type_identifier() -> &'static str {
+    "trash-entry"
+  }
+
+  fn get_id(&self) -> &String {
+    &self.id
+  }
+
+  fn to_json(&self) -> InfuResult<Map<String, Value>> {
+    let mut result = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("entry")));
+    result.insert(String::from("id"), Value::String(self.id.clone()));
+    result.insert(String::from("ownerId"), Value::String(self.owner_id.clone()));
+    result.insert(String::from("parentId"), Value::String(self.parent_id.clone()));
+    result.insert(String::from("relationshipToParent"), Value::String(String::from(self.relationship_to_parent.to_string())));
+    result.insert(String::from("ordering"), Value::Array(self.ordering.iter().map(|v| Value::Number((*v).into())).collect::<Vec<_>>()));
+    result.insert(String::from("trashedDate"), Value::Number(self.trashed_date.into()));
+    Ok(result)
+  }
+
+  fn from_json(map: &Map<String, Value>) -> InfuResult<TrashEntry> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?; // TODO (LOW): JsonSchema validation.
+    let id = json::get_string_field(map, "id")?.ok_or("'id' field was missing.")?;
+    Ok(TrashEntry {
+      owner_id: json::get_string_field(map, "ownerId")?.ok_or("'ownerId' field was missing.")?,
+      parent_id: json::get_string_field(map, "parentId")?.ok_or("'parentId' field was missing.")?,
+      relationship_to_parent: RelationshipToParent::from_string(
+        &json::get_string_field(map, "relationshipToParent")?.ok_or("'relationshipToParent' field was missing.")?)?,
+      ordering: map.get("ordering")
+        .ok_or(format!("'ordering' field for trash entry '{}' was missing.", &id))?
+        .as_array()
+        .ok_or(format!("'ordering' field for trash entry '{}' was not of type 'array'.", &id))?
+        .iter().map(|v| match v.as_i64() {
+          Some(v) => if (0..=255).contains(&v) { Some(v as u8) } else { None },
+          None => None
+        })
+        .collect::<Option<Vec<_>>>().ok_or(format!("One or more element of the 'ordering' field for trash entry '{}' was invalid.", &id))?,
+      trashed_date: json::get_integer_field(map, "trashedDate")?.ok_or("'trashedDate' field was missing.")?,
+      id,
+    })
+  }
+
+  fn create_json_update(_old: &TrashEntry, _new: &TrashEntry) -> InfuResult<Map<String, Value>> {
+    Err("Trash entries are not updated, only added and removed.".into())
+  }
+
+  fn apply_json_update(&mut self, _map: &Map<String, Value>) -> InfuResult<()> {
+    Err("Trash entries are not updated, only added and removed.".into())
+  }
+}
@@ -0,0 +1,91 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use crate::storage::encryption::Keyring;
+use crate::util::infu::InfuResult;
+use crate::util::uid::Uid;
+use super::kv_store::{KVStore, KVStoreConfig};
+use super::trash::TrashEntry;
+
+
+/// Db for TrashEntry instances. As for items, there is a separate store for each user, loaded on demand.
+/// Not threadsafe.
+pub struct TrashDb {
+  db_dir: String,
+  config: KVStoreConfig,
+  store_by_user_id: HashMap<Uid, KVStore<TrashEntry>>,
+}
+
+impl TrashDb {
+  pub fn init(db_dir: &str, config: &KVStoreConfig) -> TrashDb {
+    TrashDb {
+      db_dir: String::from(db_dir),
+      config: config.clone(),
+      store_by_user_id: HashMap::new()
+    }
+  }
+
+  pub fn user_trash_loaded(&self, user_id: &str) -> bool {
+    self.store_by_user_id.contains_key(user_id)
+  }
+
+  /// `keyring` is that of the user, if their data is encrypted.
+  pub fn load_user_trash(&mut self, user_id: &str, keyring: Option<Keyring>) -> InfuResult<()> {
+    let store: KVStore<TrashEntry> = KVStore::init(&self.db_dir, &Self::log_filename(user_id), &self.config, keyring)?;
+    self.store_by_user_id.insert(String::from(user_id), store);
+    Ok(())
+  }
+
+  pub fn log_filename(user_id: &str) -> String {
+    String::from("trash_") + user_id + ".json"
+  }
+
+  pub fn compact_user_trash(&mut self, user_id: &str) -> InfuResult<()> {
+    self.store_mut(user_id)?.compact()
+  }
+
+  pub fn add(&mut self, entry: TrashEntry) -> InfuResult<()> {
+    let owner_id = entry.owner_id.clone();
+    self.store_mut(&owner_id)?.add(entry)
+  }
+
+  pub fn remove(&mut self, owner_id: &str, id: &str) -> InfuResult<()> {
+    self.store_mut(owner_id)?.remove(id)
+  }
+
+  pub fn get(&self, owner_id: &str, id: &str) -> InfuResult<Option<&TrashEntry>> {
+    Ok(self.store(owner_id)?.get(id))
+  }
+
+  /// All trash entries of user `owner_id`, oldest first.
+  pub fn get_all(&self, owner_id: &str) -> InfuResult<Vec<&TrashEntry>> {
+    let mut entries = self.store(owner_id)?.get_iter().map(|(_, entry)| entry).collect::<Vec<&TrashEntry>>();
+    entries.sort_by_key(|entry| entry.trashed_date);
+    Ok(entries)
+  }
+
+  fn store(&self, user_id: &str) -> InfuResult<&KVStore<TrashEntry>> {
+    Ok(self.store_by_user_id.get(user_id)
+      .ok_or(format!("Trash store has not been loaded for user '{}'.", user_id))?)
+  }
+
+  fn store_mut(&mut self, user_id: &str) -> InfuResult<&mut KVStore<TrashEntry>> {
+    Ok(self.store_by_user_id.get_mut(user_id)
+      .ok_or(format!("Trash store has not been loaded for user '{}'.", user_id))?)
+  }
+}
@@ -14,8 +14,14 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use lazy_static::lazy_static;
 use serde_json::{Map, Value};
 use sha2::{Sha256, Digest};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, TOTP};
 
 use crate::util::infu::InfuResult;
 use crate::util::uid::Uid;
@@ -23,22 +29,143 @@ use crate::util::json;
 use super::kv_store::JsonLogSerializable;
 
 
-const ALL_JSON_FIELDS: [&'static str; 6] = ["__recordType", "id", "username", "passwordHash", "passwordSalt", "rootPageId"];
+const ALL_JSON_FIELDS: [&'static str; 11] = ["__recordType", "id", "username", "passwordHash", "passwordSalt", "rootPageId", "trashPageId", "dataKeys",
+                                               "totpSecret", "totpRecoveryCodes", "totpLastStep"];
+
+const TOTP_SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// 80 bits.
+const RECOVERY_CODE_LEN: usize = 10;
 
 pub struct User {
   pub id: String,
   pub username: Uid,
+  /// In PHC string format ("$argon2id$v=19$m=..,t=..,p=..$<salt>$<hash>"), which records the algorithm and its
+  /// parameters along with the hash. Users created by earlier versions of Infumap have a legacy hash instead (see
+  /// `legacy_password_hash`), which is replaced the next time they log in.
   pub password_hash: String,
+  /// Only used with a legacy password hash - PHC strings include their salt. Empty otherwise.
   pub password_salt: String,
   pub root_page_id: String,
+  pub trash_page_id: Option<String>,
+  /// The keys the user's data is encrypted with, each wrapped by the server key. The first is the current key,
+  /// any others are only present while a key rotation is in progress. Empty if the user's data is not encrypted.
+  pub data_keys: Vec<String>,
+  /// The secret that TOTP codes are generated from, encrypted with the server key (see `Db::totp_secret`), if the
+  /// user has two-factor authentication enabled.
+  pub totp_secret: Option<String>,
+  /// Hashes (see `hash_recovery_code`) of the recovery codes that have not yet been used. Each can be used once,
+  /// in place of a TOTP code.
+  pub totp_recovery_codes: Vec<String>,
+  /// The time step (see `totp_step`) of the last TOTP code that was accepted. Codes for this or earlier steps are
+  /// rejected, so that a code can't be used twice.
+  pub totp_last_step: Option<i64>,
 }
 
 impl User {
-  pub fn compute_password_hash(password_salt: &str, password: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(format!("{}-{}", password, password_salt));
-    String::from(format!("{:x}", hasher.finalize()))
+  /// A hash of `password`, in PHC string format, computed with Argon2id with the default parameters and a new
+  /// random salt. This deliberately takes a while.
+  pub fn compute_password_hash(password: &str) -> InfuResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+      .map_err(|e| format!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+  }
+
+  pub fn verify_password(&self, password: &str) -> bool {
+    if !self.password_hash.starts_with('$') {
+      // Legacy hashes are quick to check, so an Argon2 hash is checked too, so that this takes as long as it
+      // does for other users, and for usernames that don't exist.
+      verify_password_of_no_user(password);
+      return legacy_password_hash(&self.password_salt, password).as_bytes().ct_eq(self.password_hash.as_bytes()).into();
+    }
+    match PasswordHash::new(&self.password_hash) {
+      // The algorithm and parameters are those recorded in the hash, not the defaults.
+      Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+      Err(e) => {
+        warn!("The password hash of user '{}' could not be read: {}", self.id, e);
+        false
+      }
+    }
+  }
+
+  /// Whether the password hash was computed differently to how `compute_password_hash` would now - with the legacy
+  /// scheme, or with other Argon2 parameters - and so should be replaced when the password is next available.
+  pub fn password_needs_rehash(&self) -> bool {
+    let hash = match PasswordHash::new(&self.password_hash) {
+      Ok(hash) => hash,
+      Err(_) => return true
+    };
+    let default_params = Params::default();
+    match Params::try_from(&hash) {
+      Ok(params) =>
+        hash.algorithm != argon2::Algorithm::Argon2id.ident() ||
+        params.m_cost() != default_params.m_cost() || params.t_cost() != default_params.t_cost() || params.p_cost() != default_params.p_cost(),
+      Err(_) => true
+    }
+  }
+}
+
+/// Check `password` against a hash that it does not match, taking as long as `User::verify_password` does. This
+/// is for when there is no user to check it for, so that how long a failed login takes does not reveal whether
+/// the username exists.
+pub fn verify_password_of_no_user(password: &str) {
+  lazy_static! {
+    static ref NO_USER_PASSWORD_HASH: Option<String> = User::compute_password_hash(&uuid::Uuid::new_v4().to_string()).ok();
   }
+  if let Some(hash) = NO_USER_PASSWORD_HASH.as_ref().and_then(|hash| PasswordHash::new(hash).ok()) {
+    let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+  }
+}
+
+/// A new random TOTP secret.
+pub fn generate_totp_secret() -> Vec<u8> {
+  let mut secret = vec![0u8; TOTP_SECRET_LEN];
+  OsRng.fill_bytes(&mut secret);
+  secret
+}
+
+/// The TOTP code generator of `username`, given their secret. Codes are 6 digits, change every 30 seconds, and
+/// those of the previous and next 30 seconds are also accepted, to allow for clock drift.
+pub fn totp(secret: Vec<u8>, username: &str) -> InfuResult<TOTP<Vec<u8>>> {
+  TOTP::new(Algorithm::SHA1, 6, 1, 30, secret, Some(String::from("infumap")), String::from(username))
+    .map_err(|e| format!("Could not create TOTP generator for user '{}': {:?}", username, e).into())
+}
+
+/// The time step that `code` is the TOTP code for, if it is one of the codes accepted at `time` (seconds since the
+/// epoch), or None if it isn't.
+pub fn totp_step(totp: &TOTP<Vec<u8>>, code: &str, time: u64) -> Option<i64> {
+  let current_step = time / totp.step;
+  (current_step.saturating_sub(totp.skew as u64)..=current_step + totp.skew as u64)
+    .find(|step| bool::from(totp.generate(step * totp.step).as_bytes().ct_eq(code.as_bytes())))
+    .map(|step| step as i64)
+}
+
+/// New random recovery codes, each of the form "xxxxx-xxxxx-xxxxx-xxxxx" (hex digits).
+pub fn generate_recovery_codes() -> Vec<String> {
+  (0..RECOVERY_CODE_COUNT).map(|_| {
+    let mut bytes = [0u8; RECOVERY_CODE_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}", &hex[..5], &hex[5..10], &hex[10..15], &hex[15..])
+  }).collect()
+}
+
+/// How recovery codes are stored. Codes are 80 random bits, too many to brute force, so unlike passwords, don't
+/// need a slow or salted hash. Case, spaces and dashes are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+  let normalized = code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_ascii_lowercase();
+  let mut hasher = Sha256::new();
+  hasher.update(normalized);
+  format!("{:x}", hasher.finalize())
+}
+
+/// How password hashes were computed by earlier versions of Infumap. This is far too fast to withstand brute
+/// forcing, so is only used to check passwords against existing hashes, until they are replaced.
+fn legacy_password_hash(password_salt: &str, password: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(format!("{}-{}", password, password_salt));
+  format!("{:x}", hasher.finalize())
 }
 
 impl Clone for User {
@@ -48,7 +175,12 @@ impl Clone for User {
       username: self.username.clone(),
       password_hash: self.password_hash.clone(),
       password_salt: self.password_salt.clone(),
-      root_page_id: self.root_page_id.clone()
+      root_page_id: self.root_page_id.clone(),
+      trash_page_id: self.trash_page_id.clone(),
+      data_keys: self.data_keys.clone(),
+      totp_secret: self.totp_secret.clone(),
+      totp_recovery_codes: self.totp_recovery_codes.clone(),
+      totp_last_step: self.totp_last_step,
     }
   }
 }
@@ -70,6 +202,11 @@ impl JsonLogSerializable<User> for User {
     result.insert(String::from("passwordHash"), Value::String(self.password_hash.clone()));
     result.insert(String::from("passwordSalt"), Value::String(self.password_salt.clone()));
     result.insert(String::from("rootPageId"), Value::String(self.root_page_id.clone()));
+    if let Some(trash_page_id) = &self.trash_page_id { result.insert(String::from("trashPageId"), Value::String(trash_page_id.clone())); }
+    if !self.data_keys.is_empty() { result.insert(String::from("dataKeys"), string_array_to_json(&self.data_keys)); }
+    if let Some(totp_secret) = &self.totp_secret { result.insert(String::from("totpSecret"), Value::String(totp_secret.clone())); }
+    if !self.totp_recovery_codes.is_empty() { result.insert(String::from("totpRecoveryCodes"), string_array_to_json(&self.totp_recovery_codes)); }
+    if let Some(totp_last_step) = self.totp_last_step { result.insert(String::from("totpLastStep"), Value::Number(totp_last_step.into())); }
     Ok(result)
   }
 
@@ -81,16 +218,43 @@ impl JsonLogSerializable<User> for User {
       password_hash: json::get_string_field(map, "passwordHash")?.ok_or("'passwordHash' field was missing.")?,
       password_salt: json::get_string_field(map, "passwordSalt")?.ok_or("'passwordSalt' field was missing.")?,
       root_page_id: json::get_string_field(map, "rootPageId")?.ok_or("'rootPageId' field was missing.")?,
+      trash_page_id: json::get_string_field(map, "trashPageId")?,
+      data_keys: json::get_string_array_field(map, "dataKeys")?.unwrap_or_default(),
+      totp_secret: json::get_string_field(map, "totpSecret")?,
+      totp_recovery_codes: json::get_string_array_field(map, "totpRecoveryCodes")?.unwrap_or_default(),
+      totp_last_step: json::get_integer_field(map, "totpLastStep")?,
     })
   }
 
   fn create_json_update(old: &User, new: &User) -> InfuResult<Map<String, Value>> {
     if old.id != new.id { return Err("Attempt was made to create a User update record from instances with non-matching ids.".into()); }
     let mut result: Map<String, Value> = Map::new();
-    result.insert(String::from("__recordType"), serde_json::from_str("update")?);
-    if old.password_hash != new.password_hash { result.insert(String::from("passwordHash"), serde_json::from_str(&new.password_hash)?); }
-    if old.password_salt != new.password_salt { result.insert(String::from("passwordSalt"), serde_json::from_str(&new.password_salt)?); }
-    if old.root_page_id != new.root_page_id { result.insert(String::from("rootPageId"), serde_json::from_str(&new.root_page_id)?); }
+    result.insert(String::from("__recordType"), Value::String(String::from("update")));
+    result.insert(String::from("id"), Value::String(new.id.clone()));
+    if old.username != new.username { result.insert(String::from("username"), Value::String(new.username.clone())); }
+    if old.password_hash != new.password_hash { result.insert(String::from("passwordHash"), Value::String(new.password_hash.clone())); }
+    if old.password_salt != new.password_salt { result.insert(String::from("passwordSalt"), Value::String(new.password_salt.clone())); }
+    if old.root_page_id != new.root_page_id { result.insert(String::from("rootPageId"), Value::String(new.root_page_id.clone())); }
+    if old.trash_page_id != new.trash_page_id {
+      match &new.trash_page_id {
+        Some(trash_page_id) => { result.insert(String::from("trashPageId"), Value::String(trash_page_id.clone())); },
+        None => { return Err(format!("Attempt was made to unset the trash page of user '{}'.", new.id).into()); }
+      }
+    }
+    if old.data_keys != new.data_keys { result.insert(String::from("dataKeys"), string_array_to_json(&new.data_keys)); }
+    if old.totp_secret != new.totp_secret {
+      // null when two-factor authentication is reset.
+      result.insert(String::from("totpSecret"), new.totp_secret.clone().map(Value::String).unwrap_or(Value::Null));
+    }
+    if old.totp_recovery_codes != new.totp_recovery_codes {
+      result.insert(String::from("totpRecoveryCodes"), string_array_to_json(&new.totp_recovery_codes));
+    }
+    if old.totp_last_step != new.totp_last_step {
+      match new.totp_last_step {
+        Some(totp_last_step) => { result.insert(String::from("totpLastStep"), Value::Number(totp_last_step.into())); },
+        None => { return Err(format!("Attempt was made to unset the last TOTP step of user '{}'.", new.id).into()); }
+      }
+    }
     Ok(result)
   }
 
@@ -100,6 +264,71 @@ impl JsonLogSerializable<User> for User {
     if let Ok(v) = json::get_string_field(map, "passwordHash") { if let Some(u) = v { self.password_hash = u; } }
     if let Ok(v) = json::get_string_field(map, "passwordSalt") { if let Some(u) = v { self.password_salt = u; } }
     if let Ok(v) = json::get_string_field(map, "rootPageId") { if let Some(u) = v { self.root_page_id = u; } }
+    if let Ok(Some(u)) = json::get_string_field(map, "trashPageId") { self.trash_page_id = Some(u); }
+    if let Some(u) = json::get_string_array_field(map, "dataKeys")? { self.data_keys = u; }
+    if map.contains_key("totpSecret") { self.totp_secret = json::get_string_field(map, "totpSecret")?; }
+    if let Some(u) = json::get_string_array_field(map, "totpRecoveryCodes")? { self.totp_recovery_codes = u; }
+    if let Some(u) = json::get_integer_field(map, "totpLastStep")? { self.totp_last_step = Some(u); }
     Ok(())
   }
 }
+
+fn string_array_to_json(strings: &[String]) -> Value {
+  Value::Array(strings.iter().map(|k| Value::String(k.clone())).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+  use argon2::password_hash::SaltString;
+  use argon2::password_hash::rand_core::OsRng;
+  use super::{legacy_password_hash, User};
+
+  fn user(password_hash: String, password_salt: &str) -> User {
+    User {
+      id: String::from("0123456789abcdef0123456789abcdef"),
+      username: String::from("user"),
+      password_hash,
+      password_salt: String::from(password_salt),
+      root_page_id: String::from("fedcba9876543210fedcba9876543210"),
+      trash_page_id: None,
+      data_keys: vec![],
+      totp_secret: None,
+      totp_recovery_codes: vec![],
+      totp_last_step: None,
+    }
+  }
+
+  #[test]
+  fn legacy_password_hash_is_verified_and_replaced() {
+    let legacy = user(legacy_password_hash("salt", "password"), "salt");
+    assert!(legacy.verify_password("password"));
+    assert!(!legacy.verify_password("other"));
+    assert!(legacy.password_needs_rehash());
+
+    // As done on login.
+    let rehashed = user(User::compute_password_hash("password").unwrap(), "");
+    assert!(rehashed.password_hash.starts_with("$argon2id$"));
+    assert!(rehashed.verify_password("password"));
+    assert!(!rehashed.verify_password("other"));
+    assert!(!rehashed.password_needs_rehash());
+  }
+
+  #[test]
+  fn password_hash_with_other_parameters_is_verified_and_replaced() {
+    let params = Params::new(Params::DEFAULT_M_COST / 2, Params::DEFAULT_T_COST, Params::DEFAULT_P_COST, None).unwrap();
+    let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+      .hash_password(b"password", &SaltString::generate(&mut OsRng)).unwrap().to_string();
+    let user = user(hash, "");
+    assert!(user.verify_password("password"));
+    assert!(user.password_needs_rehash());
+  }
+
+  #[test]
+  fn unreadable_password_hash_is_not_verified() {
+    let user = user(String::from("$not-a-hash"), "");
+    assert!(!user.verify_password("password"));
+    assert!(user.password_needs_rehash());
+  }
+}
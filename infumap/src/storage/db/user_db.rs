@@ -17,12 +17,15 @@
 use std::collections::HashMap;
 use std::collections::hash_map::Iter;
 
+use crate::storage::encryption::Keyring;
 use crate::util::infu::InfuResult;
-use super::kv_store::KVStore;
+use super::kv_store::{KVStore, KVStoreConfig};
 use super::user::User;
 
 
-/// Db for User instances.
+pub const LOG_FILENAME: &str = "users.json";
+
+/// Db for User instances. The log is encrypted with the server key, if there is one.
 /// Not threadsafe.
 pub struct UserDb {
   store: KVStore<User>,
@@ -30,9 +33,8 @@ pub struct UserDb {
 }
 
 impl UserDb {
-  pub fn init(db_dir: &str) -> InfuResult<UserDb> {
-    const LOG_FILENAME: &str = "users.json";
-    let store: KVStore<User> = KVStore::init(db_dir, LOG_FILENAME)?;
+  pub fn init(db_dir: &str, config: &KVStoreConfig) -> InfuResult<UserDb> {
+    let store: KVStore<User> = KVStore::init(db_dir, LOG_FILENAME, config, config.server_keyring.clone())?;
     let mut id_by_username = HashMap::new();
     for (id, user) in store.get_iter() {
       id_by_username.insert(user.username.clone(), id.clone());
@@ -40,14 +42,49 @@ impl UserDb {
     Ok(UserDb { store, id_by_username })
   }
 
-  pub fn _get_iter(&self) -> Iter<String, User> {
+  pub fn get_iter(&self) -> Iter<String, User> {
     self.store.get_iter()
   }
 
-  pub fn _get_by_id(&self, id: &str) -> Option<&User> {
+  pub fn get_by_id(&self, id: &str) -> Option<&User> {
     self.store.get(id)
   }
 
+  pub fn add(&mut self, user: User) -> InfuResult<()> {
+    if self.id_by_username.contains_key(&user.username) {
+      return Err(format!("A user with username '{}' already exists.", user.username).into());
+    }
+    let username = user.username.clone();
+    let id = user.id.clone();
+    self.store.add(user)?;
+    self.id_by_username.insert(username, id);
+    Ok(())
+  }
+
+  pub fn update(&mut self, user: User) -> InfuResult<()> {
+    let old_username = self.store.get(&user.id)
+      .ok_or(format!("Attempt was made to update user '{}', but it does not exist.", user.id))?
+      .username.clone();
+    if old_username != user.username && self.id_by_username.contains_key(&user.username) {
+      return Err(format!("A user with username '{}' already exists.", user.username).into());
+    }
+    let username = user.username.clone();
+    let id = user.id.clone();
+    self.store.update(user)?;
+    self.id_by_username.remove(&old_username);
+    self.id_by_username.insert(username, id);
+    Ok(())
+  }
+
+  pub fn compact(&mut self) -> InfuResult<()> {
+    self.store.compact()
+  }
+
+  /// Rewrite the log encrypted with `keyring`, see KVStore::reencrypt.
+  pub fn reencrypt(&mut self, keyring: Option<Keyring>) -> InfuResult<()> {
+    self.store.reencrypt(keyring)
+  }
+
   pub fn get_by_username(&self, username: &str) -> Option<&User> {
     match self.id_by_username.get(username) {
       None => None,
@@ -0,0 +1,358 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, AeadCore, OsRng, Payload};
+use aes_gcm::aead::rand_core::RngCore;
+
+use crate::util::infu::InfuResult;
+
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: u64 = 16;
+
+/// An AES-256-GCM key. Data encrypted with it is authenticated, so any tampering is detected when it is decrypted.
+#[derive(Clone)]
+pub struct EncryptionKey {
+  bytes: [u8; KEY_LEN],
+  cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+  pub fn generate() -> EncryptionKey {
+    let mut bytes = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    EncryptionKey::from_bytes(bytes)
+  }
+
+  /// Parse a key written as 64 hex digits, as for the encryption_key setting.
+  pub fn from_hex(hex: &str) -> InfuResult<EncryptionKey> {
+    if hex.len() != KEY_LEN * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("An encryption key must be {} hex digits.", KEY_LEN * 2).into());
+    }
+    let mut bytes = [0u8; KEY_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+      *byte = u8::from_str_radix(&hex[i*2..i*2+2], 16).unwrap();
+    }
+    Ok(EncryptionKey::from_bytes(bytes))
+  }
+
+  fn from_bytes(bytes: [u8; KEY_LEN]) -> EncryptionKey {
+    EncryptionKey { cipher: Aes256Gcm::new(&bytes.into()), bytes }
+  }
+
+  /// Encrypt `plaintext` with a random nonce, which the result starts with. `aad` is authenticated along with
+  /// the data, but not included in the result. It identifies where the data belongs, so that data can't be
+  /// moved from one place to another undetected.
+  pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> InfuResult<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut result = nonce.to_vec();
+    result.extend(self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).map_err(|_| "Encryption failed.")?);
+    Ok(result)
+  }
+
+  fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if ciphertext.len() < NONCE_LEN { return None; }
+    let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+    self.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok()
+  }
+
+  /// `key`, encrypted with this key, as base64 text. This is how the data key of each user is stored.
+  pub fn wrap_key(&self, key: &EncryptionKey, aad: &str) -> InfuResult<String> {
+    Ok(base64::encode(self.encrypt(&key.bytes, aad.as_bytes())?))
+  }
+}
+
+
+/// The keys that data may be encrypted with. Data is always encrypted with the first (current) key.
+/// The others are only needed to read data written before a key rotation that is not yet complete.
+#[derive(Clone)]
+pub struct Keyring {
+  keys: Vec<EncryptionKey>,
+}
+
+impl From<EncryptionKey> for Keyring {
+  fn from(key: EncryptionKey) -> Keyring {
+    Keyring { keys: vec![key] }
+  }
+}
+
+impl Keyring {
+  /// `keys` must not be empty, the first is the current key.
+  pub fn new(keys: Vec<EncryptionKey>) -> InfuResult<Keyring> {
+    if keys.is_empty() {
+      return Err("A keyring must have at least one key.".into());
+    }
+    Ok(Keyring { keys })
+  }
+
+  pub fn current(&self) -> &EncryptionKey {
+    &self.keys[0]
+  }
+
+  pub fn keys(&self) -> &[EncryptionKey] {
+    &self.keys
+  }
+
+  pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> InfuResult<Vec<u8>> {
+    self.current().encrypt(plaintext, aad)
+  }
+
+  pub fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> InfuResult<Vec<u8>> {
+    self.keys.iter()
+      .find_map(|key| key.decrypt(ciphertext, aad))
+      .ok_or(DECRYPTION_FAILED.into())
+  }
+
+  /// The key that `wrapped` was created from by `EncryptionKey::wrap_key`.
+  pub fn unwrap_key(&self, wrapped: &str, aad: &str) -> InfuResult<EncryptionKey> {
+    let bytes = self.decrypt(&base64::decode(wrapped).map_err(|e| format!("Wrapped key is not valid base64: {}", e))?, aad.as_bytes())?;
+    let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| "Wrapped key has the wrong length.")?;
+    Ok(EncryptionKey::from_bytes(bytes))
+  }
+}
+
+const DECRYPTION_FAILED: &str =
+  "Data could not be decrypted with any available key. Either the key is wrong, or the data has been corrupted or tampered with.";
+
+
+/// Blobs are encrypted in chunks, so a range of their data can be read without decrypting all that precedes it.
+/// An encrypted blob starts with BLOB_MAGIC and a random nonce prefix. This is followed by the data in chunks of
+/// BLOB_CHUNK_LEN bytes (the last may be shorter), each encrypted and followed by its authentication tag. The
+/// nonce of a chunk is the prefix, then the chunk index (u32, big endian), then a byte that is 1 for the last
+/// chunk only - so chunks can't be reordered, and truncation is detected. Empty data has one empty chunk.
+const BLOB_MAGIC: &[u8; 8] = b"infuenc1";
+const NONCE_PREFIX_LEN: usize = 7;
+pub const BLOB_HEADER_LEN: u64 = BLOB_MAGIC.len() as u64 + NONCE_PREFIX_LEN as u64;
+const BLOB_CHUNK_LEN: u64 = 64 * 1024;
+const ENCRYPTED_CHUNK_LEN: u64 = BLOB_CHUNK_LEN + TAG_LEN;
+
+/// The number of bytes of data in an encrypted blob of `encrypted_len` bytes.
+pub fn decrypted_blob_len(encrypted_len: u64) -> InfuResult<u64> {
+  let body_len = encrypted_len.checked_sub(BLOB_HEADER_LEN).ok_or("Encrypted blob is too short.")?;
+  let (full_chunks, remainder) = (body_len / ENCRYPTED_CHUNK_LEN, body_len % ENCRYPTED_CHUNK_LEN);
+  match remainder {
+    0 if full_chunks > 0 => Ok(full_chunks * BLOB_CHUNK_LEN),
+    r if r >= TAG_LEN => Ok(full_chunks * BLOB_CHUNK_LEN + r - TAG_LEN),
+    _ => Err("Encrypted blob has an invalid length.".into())
+  }
+}
+
+/// Decrypts the chunks of an encrypted blob.
+pub struct BlobCipher {
+  keyring: Keyring,
+  nonce_prefix: [u8; NONCE_PREFIX_LEN],
+  aad: Vec<u8>,
+  /// The number of bytes of data in the blob.
+  pub len: u64,
+}
+
+impl BlobCipher {
+  /// The cipher for the encrypted blob with `header` (its first BLOB_HEADER_LEN bytes) and length `encrypted_len`,
+  /// or None if `header` is not that of an encrypted blob. `aad` must be as given to `encrypt_blob`.
+  pub fn for_header(header: &[u8], encrypted_len: u64, keyring: &Keyring, aad: &[u8]) -> InfuResult<Option<BlobCipher>> {
+    if header.len() < BLOB_HEADER_LEN as usize || !header.starts_with(BLOB_MAGIC) {
+      return Ok(None);
+    }
+    Ok(Some(BlobCipher {
+      keyring: keyring.clone(),
+      nonce_prefix: header[BLOB_MAGIC.len()..BLOB_HEADER_LEN as usize].try_into().unwrap(),
+      aad: aad.to_vec(),
+      len: decrypted_blob_len(encrypted_len)?,
+    }))
+  }
+
+  /// The offset in the encrypted blob at which chunk `index` starts.
+  pub fn chunk_offset(index: u64) -> u64 {
+    BLOB_HEADER_LEN + index * ENCRYPTED_CHUNK_LEN
+  }
+
+  /// The index of the chunk that holds byte `position` of the data.
+  pub fn chunk_index(position: u64) -> u64 {
+    position / BLOB_CHUNK_LEN
+  }
+
+  /// The position in the data of the first byte of chunk `index`.
+  pub fn chunk_start(index: u64) -> u64 {
+    index * BLOB_CHUNK_LEN
+  }
+
+  /// The encrypted length of chunk `index`.
+  pub fn encrypted_chunk_len(&self, index: u64) -> u64 {
+    (self.len - index * BLOB_CHUNK_LEN).min(BLOB_CHUNK_LEN) + TAG_LEN
+  }
+
+  pub fn decrypt_chunk(&self, index: u64, chunk: &[u8]) -> InfuResult<Vec<u8>> {
+    let nonce = chunk_nonce(&self.nonce_prefix, index, index + 1 == chunk_count(self.len))?;
+    self.keyring.keys().iter()
+      .find_map(|key| key.cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad: &self.aad }).ok())
+      .ok_or(DECRYPTION_FAILED.into())
+  }
+}
+
+/// Write the data in the file at `src` to `dest` as an encrypted blob.
+pub fn encrypt_blob(src: &Path, dest: &Path, key: &EncryptionKey, aad: &[u8]) -> InfuResult<()> {
+  let mut reader = BufReader::new(File::open(src)?);
+  let len = reader.get_ref().metadata()?.len();
+  let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+  OsRng.fill_bytes(&mut nonce_prefix);
+  let mut writer = BufWriter::new(File::create(dest)?);
+  writer.write_all(BLOB_MAGIC)?;
+  writer.write_all(&nonce_prefix)?;
+  let count = chunk_count(len);
+  let mut buf = vec![0u8; BLOB_CHUNK_LEN as usize];
+  for index in 0..count {
+    let chunk = &mut buf[..(len - index * BLOB_CHUNK_LEN).min(BLOB_CHUNK_LEN) as usize];
+    reader.read_exact(chunk)?;
+    let nonce = chunk_nonce(&nonce_prefix, index, index + 1 == count)?;
+    writer.write_all(&key.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: chunk, aad }).map_err(|_| "Encryption failed.")?)?;
+  }
+  writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+  Ok(())
+}
+
+fn chunk_count(len: u64) -> u64 {
+  len.div_ceil(BLOB_CHUNK_LEN).max(1)
+}
+
+fn chunk_nonce(nonce_prefix: &[u8; NONCE_PREFIX_LEN], index: u64, last: bool) -> InfuResult<[u8; NONCE_LEN]> {
+  let index: u32 = index.try_into().map_err(|_| "Blob is too large to encrypt.")?;
+  let mut nonce = [0u8; NONCE_LEN];
+  nonce[..NONCE_PREFIX_LEN].copy_from_slice(nonce_prefix);
+  nonce[NONCE_PREFIX_LEN..NONCE_LEN-1].copy_from_slice(&index.to_be_bytes());
+  nonce[NONCE_LEN-1] = last as u8;
+  Ok(nonce)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+  use crate::util::infu::InfuResult;
+  use crate::util::uid::new_uid;
+  use super::{encrypt_blob, BlobCipher, EncryptionKey, Keyring, BLOB_CHUNK_LEN, BLOB_HEADER_LEN, ENCRYPTED_CHUNK_LEN};
+
+  const AAD: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+  /// Temporary files, removed when dropped.
+  struct TestFiles {
+    src: PathBuf,
+    dest: PathBuf,
+  }
+
+  impl TestFiles {
+    fn new() -> TestFiles {
+      let mut src = std::env::temp_dir();
+      src.push(format!("infumap-encryption-test-{}", new_uid()));
+      let dest = src.with_extension("encrypted");
+      TestFiles { src, dest }
+    }
+  }
+
+  impl Drop for TestFiles {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_file(&self.src);
+      let _ = std::fs::remove_file(&self.dest);
+    }
+  }
+
+  fn encrypt(data: &[u8], key: &EncryptionKey) -> Vec<u8> {
+    let files = TestFiles::new();
+    std::fs::write(&files.src, data).unwrap();
+    encrypt_blob(&files.src, &files.dest, key, AAD).unwrap();
+    std::fs::read(&files.dest).unwrap()
+  }
+
+  /// All of the data of `blob`, read one chunk at a time as StoredFile does.
+  fn decrypt(blob: &[u8], keyring: &Keyring, aad: &[u8]) -> InfuResult<Vec<u8>> {
+    let cipher = BlobCipher::for_header(&blob[..BLOB_HEADER_LEN as usize], blob.len() as u64, keyring, aad)?.unwrap();
+    let mut result = vec![];
+    let mut index = 0;
+    while index == 0 || BlobCipher::chunk_start(index) < cipher.len {
+      let offset = BlobCipher::chunk_offset(index) as usize;
+      let chunk = &blob[offset..offset + cipher.encrypted_chunk_len(index) as usize];
+      result.extend(cipher.decrypt_chunk(index, chunk)?);
+      index += 1;
+    }
+    Ok(result)
+  }
+
+  fn data(len: u64) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn blob_round_trip() {
+    let key = EncryptionKey::generate();
+    for len in [0, 1, BLOB_CHUNK_LEN, BLOB_CHUNK_LEN + 1, BLOB_CHUNK_LEN * 2 + 100] {
+      let blob = encrypt(&data(len), &key);
+      assert_eq!(decrypt(&blob, &Keyring::from(key.clone()), AAD).unwrap(), data(len), "length {}", len);
+    }
+  }
+
+  #[test]
+  fn blob_is_readable_with_previous_key() {
+    let previous = EncryptionKey::generate();
+    let blob = encrypt(&data(100), &previous);
+    let keyring = Keyring::new(vec![EncryptionKey::generate(), previous]).unwrap();
+    assert_eq!(decrypt(&blob, &keyring, AAD).unwrap(), data(100));
+  }
+
+  #[test]
+  fn truncated_final_chunk_is_detected() {
+    let key = EncryptionKey::generate();
+    let blob = encrypt(&data(BLOB_CHUNK_LEN * 2 + 100), &key);
+    assert!(decrypt(&blob[..blob.len() - 10], &Keyring::from(key.clone()), AAD).is_err());
+    // Without the final chunk, the one before it would be taken to be the last.
+    let without_final_chunk = &blob[..(BLOB_HEADER_LEN + ENCRYPTED_CHUNK_LEN * 2) as usize];
+    assert!(decrypt(without_final_chunk, &Keyring::from(key), AAD).is_err());
+  }
+
+  #[test]
+  fn swapped_chunks_are_detected() {
+    let key = EncryptionKey::generate();
+    let mut blob = encrypt(&data(BLOB_CHUNK_LEN * 3), &key);
+    let (first, second) = (BlobCipher::chunk_offset(0) as usize, BlobCipher::chunk_offset(1) as usize);
+    let first_chunk = blob[first..second].to_vec();
+    blob.copy_within(second..second + ENCRYPTED_CHUNK_LEN as usize, first);
+    blob[second..second + ENCRYPTED_CHUNK_LEN as usize].copy_from_slice(&first_chunk);
+    assert!(decrypt(&blob, &Keyring::from(key), AAD).is_err());
+  }
+
+  #[test]
+  fn wrong_key_or_aad_is_detected() {
+    let key = EncryptionKey::generate();
+    let blob = encrypt(&data(100), &key);
+    assert!(decrypt(&blob, &Keyring::from(EncryptionKey::generate()), AAD).is_err());
+    assert!(decrypt(&blob, &Keyring::from(key), b"fedcba9876543210fedcba9876543210").is_err());
+  }
+
+  #[test]
+  fn wrapped_key_round_trip() {
+    let server_key = EncryptionKey::generate();
+    let data_key = EncryptionKey::generate();
+    let wrapped = server_key.wrap_key(&data_key, "user").unwrap();
+    let keyring = Keyring::from(server_key);
+    assert_eq!(keyring.unwrap_key(&wrapped, "user").unwrap().bytes, data_key.bytes);
+    assert!(keyring.unwrap_key(&wrapped, "other user").is_err());
+  }
+}
@@ -0,0 +1,79 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use serde_json::{Map, Value};
+
+use crate::storage::db::kv_store::JsonLogSerializable;
+use crate::util::infu::InfuResult;
+use crate::util::json;
+use crate::util::uid::Uid;
+
+
+const ALL_JSON_FIELDS: [&str; 4] = ["__recordType", "id", "hash", "sizeBytes"];
+
+/// Records that the data of an item is the blob with SHA-256 `hash`, in a content addressed file
+/// store. Keyed on the id of the item. The number of refs to a blob is its reference count.
+pub struct BlobRef {
+  pub id: Uid,
+  pub hash: String,
+  pub size_bytes: u64,
+}
+
+impl Clone for BlobRef {
+  fn clone(&self) -> Self {
+    Self { id: self.id.clone(), hash: self.hash.clone(), size_bytes: self.size_bytes }
+  }
+}
+
+impl JsonLogSerializable<BlobRef> for BlobRef {
+  fn value_type_identifier() -> &'static str {
+    "blob-ref"
+  }
+
+  fn get_id(&self) -> &String {
+    &self.id
+  }
+
+  fn to_json(&self) -> InfuResult<Map<String, Value>> {
+    let mut result = Map::new();
+    result.insert(String::from("__recordType"), Value::String(String::from("entry")));
+    result.insert(String::from("id"), Value::String(self.id.clone()));
+    result.insert(String::from("hash"), Value::String(self.hash.clone()));
+    result.insert(String::from("sizeBytes"), Value::Number(self.size_bytes.into()));
+    Ok(result)
+  }
+
+  fn from_json(map: &Map<String, Value>) -> InfuResult<BlobRef> {
+    json::validate_map_fields(map, &ALL_JSON_FIELDS)?; // TODO (LOW): JsonSchema validation.
+    let size_bytes = json::get_integer_field(map, "sizeBytes")?.ok_or("'sizeBytes' field was missing.")?;
+    if size_bytes < 0 {
+      return Err(format!("'sizeBytes' field must not be negative, not {}.", size_bytes).into());
+    }
+    Ok(BlobRef {
+      id: json::get_string_field(map, "id")?.ok_or("'id' field was missing.")?,
+      hash: json::get_string_field(map, "hash")?.ok_or("'hash' field was missing.")?,
+      size_bytes: size_bytes as u64,
+    })
+  }
+
+  fn create_json_update(_old: &BlobRef, _new: &BlobRef) -> InfuResult<Map<String, Value>> {
+    Err("Blob refs are not updated, only added and removed.".into())
+  }
+
+  fn apply_json_update(&mut self, _map: &Map<String, Value>) -> InfuResult<()> {
+    Err("Blob refs are not updated, only added and removed.".into())
+  }
+}
@@ -14,34 +14,741 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::path::PathBuf;
+pub mod blob_ref;
+pub mod object_store;
+pub mod s3;
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, BufReader, Read};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use config::Config;
+use futures::{stream, TryStreamExt};
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use rocket::tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use tokio_util::io::{ReaderStream, StreamReader};
 
+use crate::storage::db::item::{is_data_item, Item};
+use crate::storage::db::kv_store::{KVStore, KVStoreConfig};
+use crate::storage::encryption::{encrypt_blob, BlobCipher, Keyring, BLOB_HEADER_LEN};
 use crate::util::infu::InfuResult;
-use crate::util::uid::Uid;
+use crate::util::uid::{new_uid, Uid};
 use crate::util::fs::expand_tilde;
+use self::blob_ref::BlobRef;
+use self::object_store::{LocalObjectStore, ObjectReader, ObjectStore};
+use self::s3::S3ObjectStore;
+
+
+const BLOB_REFS_LOG_FILENAME: &str = "blob_refs.json";
+/// The blob ref log is written here by migrate_to_content_addressed, and renamed once it is complete.
+const MIGRATING_BLOB_REFS_LOG_FILENAME: &str = "blob_refs_migrating.json";
+const BLOBS_KEY_PREFIX: &str = "blobs/";
+const STAGING_DIRNAME: &str = "staging";
+/// File data added more recently than this is left alone by garbage collection, as the item it is for may
+/// not have been created yet.
+const GC_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+const NOT_CONTENT_ADDRESSABLE: &str =
+  "A content addressed file store can't be used with encryption - data encrypted with the key of one user can't be shared with others.";
+
+/// How much space content addressing saves, by storing the data shared by several items once.
+pub struct DedupStats {
+  pub item_count: usize,
+  pub blob_count: usize,
+  /// The total size of the data of all items.
+  pub referenced_bytes: u64,
+  /// The total size of the blobs that hold it.
+  pub stored_bytes: u64,
+}
+
+impl DedupStats {
+  pub fn saved_bytes(&self) -> u64 {
+    self.referenced_bytes - self.stored_bytes
+  }
+}
+
+/// What FileStore::collect_garbage found.
+pub struct GcReport {
+  /// Items that don't exist, but have file data.
+  pub orphaned_ids: Vec<Uid>,
+  /// Blobs that no item refers to, in a content addressed store.
+  pub unreferenced_blob_count: usize,
+  /// The space taken by the data of orphaned items that isn't shared with other items, and by unreferenced
+  /// blobs. This is what is freed, unless it was a dry run.
+  pub reclaimable_bytes: u64,
+  /// File and image items that have no file data.
+  pub missing_ids: Vec<Uid>,
+}
+
+/// Which blob holds the data of each item, in a content addressed store.
+struct ContentIndex {
+  refs: KVStore<BlobRef>,
+  /// The number of refs to each blob, by hash. A blob is deleted when this reaches zero.
+  ref_counts: HashMap<String, usize>,
+}
+
+impl ContentIndex {
+  fn load(files_dir: &str, log_filename: &str, kv_store_config: &KVStoreConfig) -> InfuResult<ContentIndex> {
+    let refs: KVStore<BlobRef> = KVStore::init(files_dir, log_filename, kv_store_config, None)?;
+    let mut ref_counts = HashMap::new();
+    for (_, blob_ref) in refs.get_iter() {
+      *ref_counts.entry(blob_ref.hash.clone()).or_insert(0) += 1;
+    }
+    Ok(ContentIndex { refs, ref_counts })
+  }
+
+  fn add_ref(&mut self, blob_ref: BlobRef) -> InfuResult<()> {
+    let hash = blob_ref.hash.clone();
+    self.refs.add(blob_ref)?;
+    *self.ref_counts.entry(hash).or_insert(0) += 1;
+    Ok(())
+  }
+
+  /// Returns the hash of the blob that was referred to, and how many refs to it remain.
+  fn remove_ref(&mut self, id: &Uid) -> InfuResult<(String, usize)> {
+    let hash = self.refs.get(id).ok_or(format!("There is no file data for '{}'.", id))?.hash.clone();
+    self.refs.remove(id)?;
+    let count = self.ref_counts.get_mut(&hash).unwrap();
+    *count -= 1;
+    let remaining = *count;
+    if remaining == 0 {
+      self.ref_counts.remove(&hash);
+    }
+    Ok((hash, remaining))
+  }
+
+  fn stats(&self) -> DedupStats {
+    let mut blob_sizes = HashMap::new();
+    let mut referenced_bytes = 0;
+    for (_, blob_ref) in self.refs.get_iter() {
+      referenced_bytes += blob_ref.size_bytes;
+      blob_sizes.insert(&blob_ref.hash, blob_ref.size_bytes);
+    }
+    DedupStats {
+      item_count: self.refs.get_iter().count(),
+      blob_count: blob_sizes.len(),
+      referenced_bytes,
+      stored_bytes: blob_sizes.values().sum(),
+    }
+  }
+}
+
+
+/// The data of an item, as found by FileStore::open.
+pub struct StoredFile {
+  pub size: u64,
+  pub last_modified: SystemTime,
+  object_store: Arc<dyn ObjectStore>,
+  key: String,
+  /// Present if the data is encrypted.
+  cipher: Option<Arc<BlobCipher>>,
+  /// If present, reading all of the data fails if it does not have this hash. See FileStore::open_item.
+  content_hash: Option<String>,
+  id: Uid,
+}
+
+impl StoredFile {
+  /// Stream `len` bytes of the data, starting at `start`. Nothing is requested from the object store
+  /// until the stream is first read.
+  pub fn reader(&self, start: u64, len: u64) -> ObjectReader {
+    let reader = match &self.cipher {
+      Some(cipher) => self.decrypting_reader(cipher.clone(), start, len),
+      None => {
+        let (object_store, key) = (self.object_store.clone(), self.key.clone());
+        let body = stream::once(async move {
+            object_store.get(&key, start, len).await.map_err(io::Error::other)
+          })
+          .map_ok(ReaderStream::new)
+          .try_flatten();
+        Box::pin(StreamReader::new(Box::pin(body)))
+      }
+    };
+    match &self.content_hash {
+      Some(content_hash) if start == 0 && len == self.size => Box::pin(VerifyingReader {
+        reader,
+        hasher: Sha256::new(),
+        remaining: len,
+        content_hash: content_hash.clone(),
+        id: self.id.clone(),
+      }),
+      _ => reader
+    }
+  }
+
+  pub async fn read_all(&self) -> InfuResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(self.size as usize);
+    self.reader(0, self.size).read_to_end(&mut data).await?;
+    Ok(data)
+  }
+
+  /// As for `reader`, for encrypted data. Only the chunks that hold the range are read and decrypted.
+  fn decrypting_reader(&self, cipher: Arc<BlobCipher>, start: u64, len: u64) -> ObjectReader {
+    if len == 0 {
+      return Box::pin(rocket::tokio::io::empty());
+    }
+    let (object_store, key) = (self.object_store.clone(), self.key.clone());
+    let first_chunk = BlobCipher::chunk_index(start);
+    let last_chunk = BlobCipher::chunk_index(start + len - 1);
+    let offset = BlobCipher::chunk_offset(first_chunk);
+    let encrypted_len = BlobCipher::chunk_offset(last_chunk) + cipher.encrypted_chunk_len(last_chunk) - offset;
+    let body = stream::once(async move {
+        object_store.get(&key, offset, encrypted_len).await.map_err(io::Error::other)
+      })
+      .map_ok(move |reader| stream::try_unfold((reader, first_chunk), {
+        let cipher = cipher.clone();
+        move |(mut reader, index)| {
+          let cipher = cipher.clone();
+          async move {
+            if index > last_chunk { return Ok(None); }
+            let mut chunk = vec![0u8; cipher.encrypted_chunk_len(index) as usize];
+            reader.read_exact(&mut chunk).await?;
+            let data = Bytes::from(cipher.decrypt_chunk(index, &chunk).map_err(io::Error::other)?);
+            let skip = if index == first_chunk { (start - BlobCipher::chunk_start(index)) as usize } else { 0 };
+            let end = if index == last_chunk { (start + len - BlobCipher::chunk_start(index)) as usize } else { data.len() };
+            Ok::<_, io::Error>(Some((data.slice(skip..end), (reader, index + 1))))
+          }
+        }
+      }))
+      .try_flatten();
+    Box::pin(StreamReader::new(Box::pin(body)))
+  }
+}
+
+
+/// An id claimed by FileStore::reserve.
+pub struct IdReservation<'a> {
+  file_store: &'a FileStore,
+  id: Uid,
+}
+
+impl Drop for IdReservation<'_> {
+  fn drop(&mut self) {
+    self.file_store.reserved_ids.lock().unwrap().remove(&self.id);
+  }
+}
 
 
+/// Passes on all of the data of an item, failing with an InvalidData error instead of returning the last of it
+/// if it does not have the expected hash, or if there is less of it than expected.
+struct VerifyingReader {
+  reader: ObjectReader,
+  hasher: Sha256,
+  remaining: u64,
+  content_hash: String,
+  id: Uid,
+}
+
+impl AsyncRead for VerifyingReader {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let filled_before = buf.filled().len();
+    if let Err(e) = futures::ready!(self.reader.as_mut().poll_read(cx, buf)) {
+      return Poll::Ready(Err(e));
+    }
+    let data = &buf.filled()[filled_before..];
+    if data.is_empty() && self.remaining > 0 {
+      error!("File data for item '{}' is truncated.", self.id);
+      return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!("File data for item '{}' is truncated", self.id))));
+    }
+    let read_len = (data.len() as u64).min(self.remaining);
+    self.hasher.update(&data[..read_len as usize]);
+    self.remaining -= read_len;
+    if read_len > 0 && self.remaining == 0 && format!("{:x}", self.hasher.finalize_reset()) != self.content_hash {
+      error!("File data for item '{}' does not match its content hash.", self.id);
+      buf.set_filled(filled_before);
+      return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!("File data for item '{}' is corrupt", self.id))));
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+
+/// Holds the data of file and image items, in an ObjectStore. By default, objects are keyed by item id
+/// (<id[..2]>/<id>). In content addressed mode, they are instead keyed by SHA-256 hash
+/// (blobs/<hash[..2]>/<hash>), so items with the same data share one copy.
+///
+/// Uploads are staged in files_dir, whichever object store is used. In content addressed mode, the log
+/// of which blob holds the data of each item is kept there too.
+///
+/// The data of users whose data is encrypted is stored encrypted with their data key, in the format described
+/// in the encryption module. The caller supplies the keyring of the owner of the item concerned.
 pub struct FileStore {
   files_dir: PathBuf,
+  object_store: Arc<dyn ObjectStore>,
+  content_index: Option<Mutex<ContentIndex>>,
+  /// Set by the files_verify_on_read setting. See `open_item`.
+  verify_on_read: bool,
+  /// Ids that data is being committed for. See `reserve`.
+  reserved_ids: std::sync::Mutex<HashSet<Uid>>,
 }
 
 impl FileStore {
-  pub fn new(files_dir: &str) -> InfuResult<FileStore> {
-    let files_dir = expand_tilde(files_dir).ok_or(format!("File store path '{}' is not valid.", files_dir))?;
-    Ok(FileStore { files_dir })
+  /// A store that is keyed by item id must be converted with `migrate_to_content_addressed` before it
+  /// can be opened with `content_addressed` set, and there is no conversion back.
+  pub async fn new(files_dir: &str, content_addressed: bool, kv_store_config: &KVStoreConfig, object_store: Arc<dyn ObjectStore>) -> InfuResult<FileStore> {
+    let files_dir_path = expand_tilde(files_dir).ok_or(format!("File store path '{}' is not valid.", files_dir))?;
+    let is_content_addressed = files_dir_path.join(BLOB_REFS_LOG_FILENAME).exists();
+    if content_addressed && kv_store_config.server_keyring.is_some() {
+      return Err(NOT_CONTENT_ADDRESSABLE.into());
+    }
+    let content_index = if content_addressed {
+      if !is_content_addressed && object_store.list("").await?.iter().any(|key| !key.starts_with(BLOBS_KEY_PREFIX)) {
+        return Err(format!(
+          "File store '{}' holds data keyed by item id. Run 'infumap migrate-files' to convert it before enabling the files_content_addressed setting.",
+          files_dir).into());
+      }
+      Some(Mutex::new(ContentIndex::load(files_dir, BLOB_REFS_LOG_FILENAME, kv_store_config)?))
+    } else {
+      if is_content_addressed {
+        return Err(format!("File store '{}' is content addressed, but the files_content_addressed setting is not enabled.", files_dir).into());
+      }
+      None
+    };
+    Ok(FileStore { files_dir: files_dir_path, object_store, content_index, verify_on_read: false, reserved_ids: std::sync::Mutex::new(HashSet::new()) })
+  }
+
+  pub async fn from_config(config: &Config, kv_store_config: &KVStoreConfig) -> InfuResult<FileStore> {
+    let files_dir = config.get_string("files_dir").map_err(|e| format!("Could not read 'files_dir' setting: {}", e))?;
+    let content_addressed = config.get_bool("files_content_addressed")
+      .map_err(|e| format!("Could not read 'files_content_addressed' setting: {}", e))?;
+    let mut file_store = FileStore::new(&files_dir, content_addressed, kv_store_config, object_store_from_config(config)?).await?;
+    file_store.verify_on_read = config.get_bool("files_verify_on_read")
+      .map_err(|e| format!("Could not read 'files_verify_on_read' setting: {}", e))?;
+    Ok(file_store)
+  }
+
+  /// Find the data for `id`. `keyring` is that of the owner of the item, if their data is encrypted.
+  pub async fn open(&self, id: &Uid, keyring: Option<&Keyring>) -> InfuResult<StoredFile> {
+    let key = self.key_for(id).await?;
+    let metadata = self.object_store.head(&key).await?.ok_or(format!("There is no file data for '{}'.", id))?;
+    let cipher = match keyring {
+      Some(keyring) => {
+        let header = self.read_header(&key, metadata.size).await?;
+        let cipher = BlobCipher::for_header(&header, metadata.size, keyring, id.as_bytes())?
+          .ok_or(format!("File data for '{}' is not encrypted. Run 'infumap rotate-keys' to encrypt existing data.", id))?;
+        Some(Arc::new(cipher))
+      },
+      None => None
+    };
+    Ok(StoredFile {
+      size: cipher.as_ref().map(|cipher| cipher.len).unwrap_or(metadata.size),
+      last_modified: metadata.last_modified,
+      object_store: self.object_store.clone(),
+      key,
+      cipher,
+      content_hash: None,
+      id: id.clone(),
+    })
+  }
+
+  /// As for `open`, for the data of file or image item `item`. If verify_on_read is set, this fails if the size
+  /// of the data is not that recorded for the item, and reading all of the data fails if it does not match the
+  /// item's content hash (reading part of it is not checked).
+  pub async fn open_item(&self, item: &Item, keyring: Option<&Keyring>) -> InfuResult<StoredFile> {
+    let mut file = self.open(&item.id, keyring).await?;
+    if self.verify_on_read {
+      if let Some(problem) = size_problem(item, file.size) {
+        error!("File data for item '{}' {}.", item.id, problem);
+        return Err(format!("File data for item '{}' is corrupt.", item.id).into());
+      }
+      file.content_hash = item.content_hash.clone();
+    }
+    Ok(file)
   }
 
-  pub fn get(&self, id: &Uid) -> InfuResult<Vec<u8>> {
+  /// Read all of the data of file or image item `item`, and check that it matches the size and content hash
+  /// recorded for the item. Returns a description of the problem if it does not, or it can't be read.
+  pub async fn verify(&self, item: &Item, keyring: Option<&Keyring>) -> Option<String> {
+    let file = match self.open(&item.id, keyring).await {
+      Ok(file) => file,
+      Err(e) => return Some(format!("its data could not be read: {}", e))
+    };
+    if let Some(problem) = size_problem(item, file.size) {
+      return Some(format!("its data {}", problem));
+    }
+    let mut reader = file.reader(0, file.size);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+      match reader.read(&mut buf).await {
+        Ok(0) => break,
+        Ok(n) => hasher.update(&buf[..n]),
+        Err(e) => return Some(format!("its data could not be read: {}", e))
+      }
+    }
+    match &item.content_hash {
+      Some(content_hash) if &format!("{:x}", hasher.finalize()) != content_hash =>
+        Some(String::from("its data does not match its content hash")),
+      _ => None
+    }
+  }
+
+  /// Encrypt the data for `id` with the current key of `keyring`. It may be encrypted with any of the keys of
+  /// `current`, or not encrypted. This is used to rotate keys, and to encrypt existing data. Returns false if
+  /// there is no data for `id`.
+  pub async fn reencrypt(&self, id: &Uid, current: &Keyring, keyring: &Keyring) -> InfuResult<bool> {
+    let key = id_key(id)?;
+    let metadata = match self.object_store.head(&key).await? {
+      Some(metadata) => metadata,
+      None => return Ok(false)
+    };
+    let is_encrypted = BlobCipher::for_header(&self.read_header(&key, metadata.size).await?, metadata.size, current, id.as_bytes())?.is_some();
+    let file = self.open(id, if is_encrypted { Some(current) } else { None }).await?;
+    let staging_path = self.new_staging_path()?;
+    let result = async {
+      let mut staged = rocket::tokio::fs::File::create(&staging_path).await?;
+      rocket::tokio::io::copy(&mut file.reader(0, file.size), &mut staged).await?;
+      let encrypted_path = self.encrypt_staged(id, &staging_path, keyring).await?;
+      self.object_store.put(&key, &encrypted_path).await
+    }.await;
+    if result.is_err() {
+      self.discard_staged(&staging_path);
+    }
+    result.map(|_| true)
+  }
+
+  pub async fn delete(&self, id: &Uid) -> InfuResult<()> {
+    match &self.content_index {
+      Some(content_index) => {
+        // The lock is held until the blob is gone, so that it isn't deleted after a commit has found it.
+        let mut content_index = content_index.lock().await;
+        let (hash, remaining) = content_index.remove_ref(id)?;
+        if remaining > 0 { return Ok(()); }
+        self.object_store.delete(&blob_key(&hash)).await
+      },
+      None => self.object_store.delete(&id_key(id)?).await
+    }
+  }
+
+  /// A new path that data can be written to before being moved into place by `commit_staged`. Its name is
+  /// unique, so concurrent uploads (even of data for the same id) don't write over each other. The containing
+  /// directory is created if it does not exist.
+  pub fn new_staging_path(&self) -> InfuResult<PathBuf> {
     let mut path = self.files_dir.clone();
-    path.push(&id[..2]);
-    path.push(id);
+    path.push(STAGING_DIRNAME);
+    fs::create_dir_all(&path)?;
+    path.push(format!("{}.staging", new_uid()));
+    Ok(path)
+  }
+
+  /// Claim `id` for the caller until the returned reservation is dropped, failing if it is already claimed.
+  /// An upload holds this from checking that its item does not exist until the item has been added, so that
+  /// if adding it fails, the data removed is known to be the data it committed.
+  pub fn reserve(&self, id: &Uid) -> InfuResult<IdReservation<'_>> {
+    if !self.reserved_ids.lock().unwrap().insert(id.clone()) {
+      return Err(format!("File data for '{}' is already being added.", id).into());
+    }
+    Ok(IdReservation { file_store: self, id: id.clone() })
+  }
+
+  /// Move the data written to `staging_path` into place as the data for `id`. This is atomic - the data
+  /// for `id` is either absent or complete, even if the machine crashes - and fails if there is already data
+  /// for `id`. In content addressed mode, the staged data is simply discarded if there is already a blob with
+  /// the same content. `content_hash` and `size_bytes` are those of the staged data, as given by `hash_file`.
+  /// If `keyring` is given, the data is encrypted with its current key.
+  pub async fn commit_staged(&self, id: &Uid, staging_path: &Path, content_hash: &str, size_bytes: u64, keyring: Option<&Keyring>) -> InfuResult<()> {
+    validate_id(id)?;
+    let content_index = match &self.content_index {
+      Some(content_index) => content_index,
+      None => {
+        let key = id_key(id)?;
+        let committed = match keyring {
+          Some(keyring) => self.object_store.put_new(&key, &self.encrypt_staged(id, staging_path, keyring).await?).await?,
+          None => self.object_store.put_new(&key, staging_path).await?
+        };
+        if !committed {
+          return Err(format!("File data for '{}' already exists.", id).into());
+        }
+        return Ok(());
+      }
+    };
+    if keyring.is_some() {
+      return Err(NOT_CONTENT_ADDRESSABLE.into());
+    }
+
+    let is_new_blob = {
+      let mut content_index = content_index.lock().await;
+      if content_index.refs.get(id).is_some() {
+        return Err(format!("File data for '{}' already exists.", id).into());
+      }
+      let is_new_blob = self.object_store.put_new(&blob_key(content_hash), staging_path).await?;
+      content_index.add_ref(BlobRef { id: id.clone(), hash: String::from(content_hash), size_bytes })?;
+      is_new_blob
+    };
+    if !is_new_blob {
+      rocket::tokio::fs::remove_file(staging_path).await?;
+    }
+    Ok(())
+  }
+
+  /// Remove data written to `staging_path` that will not be committed, if there is any.
+  pub fn discard_staged(&self, staging_path: &Path) {
+    let _ = fs::remove_file(encrypted_staging_path(staging_path));
+    let _ = fs::remove_file(staging_path);
+  }
+
+  /// Replace the data for `id` written to `staging_path` with an encrypted copy, returning its path.
+  async fn encrypt_staged(&self, id: &Uid, staging_path: &Path, keyring: &Keyring) -> InfuResult<PathBuf> {
+    let encrypted_path = encrypted_staging_path(staging_path);
+    let (src, dest, key, aad) = (staging_path.to_path_buf(), encrypted_path.clone(), keyring.current().clone(), id.clone());
+    rocket::tokio::task::spawn_blocking(move || -> InfuResult<()> {
+      encrypt_blob(&src, &dest, &key, aad.as_bytes())?;
+      fs::remove_file(&src)?;
+      Ok(())
+    }).await.map_err(|e| format!("Encrypting file data for '{}' failed: {}", id, e))??;
+    Ok(encrypted_path)
+  }
+
+  /// The first BLOB_HEADER_LEN bytes of object `key` (or all of it, if it is shorter).
+  async fn read_header(&self, key: &str, size: u64) -> InfuResult<Vec<u8>> {
+    let mut header = vec![];
+    self.object_store.get(key, 0, size.min(BLOB_HEADER_LEN)).await?.read_to_end(&mut header).await?;
+    Ok(header)
+  }
+
+  /// None if the store is not content addressed.
+  pub async fn dedup_stats(&self) -> Option<DedupStats> {
+    match &self.content_index {
+      Some(content_index) => Some(content_index.lock().await.stats()),
+      None => None
+    }
+  }
+
+  /// Delete the file data of those of `items` that have any. The items themselves are expected to be gone
+  /// already, so failures are logged rather than returned - orphaned file data is harmless.
+  pub async fn delete_for_items(&self, items: &[Item]) {
+    for item in items {
+      if is_data_item(&item.item_type) {
+        if let Err(e) = self.delete(&item.id).await {
+          warn!("Could not delete file data for removed item '{}': {}", item.id, e);
+        }
+      }
+    }
+  }
+
+  /// Find file data for items other than those in `data_item_ids`, which should be all file and image items,
+  /// and delete it unless `dry_run` is set. Also finds the items in `data_item_ids` that have no data.
+  pub async fn collect_garbage(&self, data_item_ids: &HashSet<Uid>, dry_run: bool) -> InfuResult<GcReport> {
+    let min_age_cutoff = SystemTime::now() - GC_MIN_AGE;
+    let mut report = GcReport { orphaned_ids: vec![], unreferenced_blob_count: 0, reclaimable_bytes: 0, missing_ids: vec![] };
+    let content_index = match &self.content_index {
+      Some(content_index) => content_index,
+      None => {
+        let mut found_ids = HashSet::new();
+        for key in self.object_store.list("").await? {
+          let id = String::from(key.rsplit('/').next().unwrap());
+          if data_item_ids.contains(&id) {
+            found_ids.insert(id);
+            continue;
+          }
+          // The data may have been deleted along with its item since it was listed.
+          let metadata = match self.object_store.head(&key).await? {
+            Some(metadata) => metadata,
+            None => continue
+          };
+          if metadata.last_modified > min_age_cutoff { continue; }
+          if !dry_run {
+            self.object_store.delete(&key).await?;
+          }
+          report.orphaned_ids.push(id);
+          report.reclaimable_bytes += metadata.size;
+        }
+        report.missing_ids = data_item_ids.difference(&found_ids).cloned().collect();
+        return Ok(report);
+      }
+    };
+
+    // The lock is held throughout, so that blobs and refs don't change while they are being compared.
+    let mut content_index = content_index.lock().await;
+    let min_age_cutoff = min_age_cutoff.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    let mut blob_sizes = HashMap::new();
+    for key in self.object_store.list(BLOBS_KEY_PREFIX).await? {
+      if let Some(metadata) = self.object_store.head(&key).await? {
+        blob_sizes.insert(String::from(key.rsplit('/').next().unwrap()), metadata.size);
+      }
+    }
+
+    let last_written = content_index.refs.get_last_written()?;
+    let mut orphaned_refs = vec![];
+    for (id, blob_ref) in content_index.refs.get_iter() {
+      if data_item_ids.contains(id) {
+        if !blob_sizes.contains_key(&blob_ref.hash) {
+          report.missing_ids.push(id.clone());
+        }
+        continue;
+      }
+      let added = last_written.get(id).copied().flatten().unwrap_or(0);
+      if added <= min_age_cutoff {
+        orphaned_refs.push(id.clone());
+      }
+    }
+    report.missing_ids.extend(data_item_ids.iter().filter(|id| content_index.refs.get(id).is_none()).cloned());
+
+    let mut ref_counts = content_index.ref_counts.clone();
+    for id in &orphaned_refs {
+      let hash = &content_index.refs.get(id).unwrap().hash;
+      let count = ref_counts.get_mut(hash).unwrap();
+      *count -= 1;
+      if *count == 0 {
+        report.reclaimable_bytes += blob_sizes.get(hash).copied().unwrap_or(0);
+      }
+    }
+    for (hash, size) in &blob_sizes {
+      if !content_index.ref_counts.contains_key(hash) {
+        report.unreferenced_blob_count += 1;
+        report.reclaimable_bytes += size;
+        if !dry_run {
+          self.object_store.delete(&blob_key(hash)).await?;
+        }
+      }
+    }
+    if !dry_run {
+      for id in &orphaned_refs {
+        let (hash, remaining) = content_index.remove_ref(id)?;
+        if remaining == 0 && blob_sizes.contains_key(&hash) {
+          self.object_store.delete(&blob_key(&hash)).await?;
+        }
+      }
+    }
+    report.orphaned_ids = orphaned_refs;
+    Ok(report)
+  }
+
+  /// Convert a store keyed by item id to a content addressed one, so it can be opened with the
+  /// files_content_addressed setting enabled. If this is interrupted, it can be run again to finish the job.
+  pub async fn migrate_to_content_addressed(config: &Config, kv_store_config: &KVStoreConfig) -> InfuResult<DedupStats> {
+    let files_dir = config.get_string("files_dir").map_err(|e| format!("Could not read 'files_dir' setting: {}", e))?;
+    let files_dir_path = expand_tilde(&files_dir).ok_or(format!("File store path '{}' is not valid.", files_dir))?;
+    if files_dir_path.join(BLOB_REFS_LOG_FILENAME).exists() {
+      return Err(format!("File store '{}' is already content addressed.", files_dir).into());
+    }
+    if kv_store_config.server_keyring.is_some() {
+      return Err(NOT_CONTENT_ADDRESSABLE.into());
+    }
+    let object_store = object_store_from_config(config)?;
+    let mut content_index = ContentIndex::load(&files_dir, MIGRATING_BLOB_REFS_LOG_FILENAME, kv_store_config)?;
+    let staging_dir = files_dir_path.join(STAGING_DIRNAME);
+    fs::create_dir_all(&staging_dir)?;
+
+    for key in object_store.list("").await? {
+      if key.starts_with(BLOBS_KEY_PREFIX) { continue; }
+      let id = String::from(key.rsplit('/').next().unwrap());
+      validate_id(&id)?;
+      // The ref is recorded before the data is moved, so if this is interrupted in between, it's
+      // apparent where the data belongs when it is run again.
+      let hash = match content_index.refs.get(&id) {
+        Some(blob_ref) => blob_ref.hash.clone(),
+        None => {
+          let staging_path = staging_dir.join(format!("{}.staging", id));
+          let object = object_store.head(&key).await?.ok_or(format!("Object '{}' disappeared during migration.", key))?;
+          let mut reader = object_store.get(&key, 0, object.size).await?;
+          let mut staged = rocket::tokio::fs::File::create(&staging_path).await?;
+          rocket::tokio::io::copy(&mut reader, &mut staged).await?;
+          let (hash, size_bytes) = hash_file(&staging_path)?;
+          content_index.add_ref(BlobRef { id: id.clone(), hash: hash.clone(), size_bytes })?;
+          if !object_store.put_new(&blob_key(&hash), &staging_path).await? {
+            fs::remove_file(&staging_path)?;
+          }
+          hash
+        }
+      };
+      if object_store.head(&blob_key(&hash)).await?.is_none() {
+        return Err(format!("Blob '{}' for item '{}' is missing.", hash, id).into());
+      }
+      object_store.delete(&key).await?;
+    }
+
+    fs::rename(files_dir_path.join(MIGRATING_BLOB_REFS_LOG_FILENAME), files_dir_path.join(BLOB_REFS_LOG_FILENAME))?;
+    File::open(&files_dir_path)?.sync_all()?;
+    Ok(content_index.stats())
+  }
+
+  async fn key_for(&self, id: &Uid) -> InfuResult<String> {
+    match &self.content_index {
+      Some(content_index) => {
+        let content_index = content_index.lock().await;
+        let blob_ref = content_index.refs.get(id).ok_or(format!("There is no file data for '{}'.", id))?;
+        Ok(blob_key(&blob_ref.hash))
+      },
+      None => id_key(id)
+    }
+  }
+}
+
+/// The object store selected by the files_backend setting.
+fn object_store_from_config(config: &Config) -> InfuResult<Arc<dyn ObjectStore>> {
+  let setting = |name: &str| -> InfuResult<String> {
+    config.get_string(name).map_err(|e| format!("Could not read '{}' setting: {}", name, e).into())
+  };
+  match setting("files_backend")?.as_str() {
+    "local" => {
+      let files_dir = setting("files_dir")?;
+      let files_dir_path = expand_tilde(&files_dir).ok_or(format!("File store path '{}' is not valid.", files_dir))?;
+      Ok(Arc::new(LocalObjectStore::new(files_dir_path, STAGING_DIRNAME)))
+    },
+    "s3" => {
+      let mut values = vec![];
+      for name in ["s3_endpoint", "s3_region", "s3_bucket", "s3_access_key_id", "s3_secret_access_key"] {
+        let value = setting(name)?;
+        if value.is_empty() {
+          return Err(format!("The '{}' setting is required when files_backend is 's3'.", name).into());
+        }
+        values.push(value);
+      }
+      Ok(Arc::new(S3ObjectStore::new(&values[0], &values[1], &values[2], &values[3], &values[4])?))
+    },
+    other => Err(format!("'files_backend' setting must be 'local' or 's3', not '{}'.", other).into())
+  }
+}
+
+fn validate_id(id: &Uid) -> InfuResult<()> {
+  if id.len() < 2 || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+    return Err(format!("'{}' is not a valid file id.", id).into());
+  }
+  Ok(())
+}
+
+fn id_key(id: &Uid) -> InfuResult<String> {
+  validate_id(id)?;
+  Ok(format!("{}/{}", &id[..2], id))
+}
+
+fn encrypted_staging_path(staging_path: &Path) -> PathBuf {
+  let mut path = staging_path.to_path_buf();
+  path.set_extension("staging.encrypted");
+  path
+}
+
+fn blob_key(hash: &str) -> String {
+  format!("{}{}/{}", BLOBS_KEY_PREFIX, &hash[..2], hash)
+}
+
+/// How the size of the data of `item`, `size`, differs from that recorded for it, if it does.
+fn size_problem(item: &Item, size: u64) -> Option<String> {
+  match item.file_size_bytes {
+    Some(file_size_bytes) if file_size_bytes as u64 != size =>
+      Some(format!("is {} bytes, but should be {} bytes", size, file_size_bytes)),
+    _ => None
+  }
+}
 
-    let mut f = File::open(&path)?;
-    let mut buffer = vec![0; fs::metadata(&path)?.len() as usize];
-    f.read(&mut buffer)?;
-    Ok(buffer)
+/// The hex encoded SHA-256 hash of the file at `path`, and its size.
+pub fn hash_file(path: &Path) -> InfuResult<(String, u64)> {
+  let mut reader = BufReader::new(File::open(path)?);
+  let mut hasher = Sha256::new();
+  let mut buf = [0u8; 64 * 1024];
+  let mut size_bytes = 0;
+  loop {
+    let n = reader.read(&mut buf)?;
+    if n == 0 { break; }
+    hasher.update(&buf[..n]);
+    size_bytes += n as u64;
   }
+  Ok((format!("{:x}", hasher.finalize()), size_bytes))
 }
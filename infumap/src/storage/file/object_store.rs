@@ -0,0 +1,157 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use rocket::tokio;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+
+use crate::util::infu::InfuResult;
+
+
+/// A stream of (part of) the data of an object.
+pub type ObjectReader = Pin<Box<dyn AsyncRead + Send>>;
+
+pub struct ObjectMetadata {
+  pub size: u64,
+  pub last_modified: SystemTime,
+}
+
+/// Where the FileStore keeps its objects. Keys are '/' separated paths, made up of ASCII alphanumeric
+/// characters and '_' - see FileStore for the layout.
+#[rocket::async_trait]
+pub trait ObjectStore: Send + Sync {
+  /// Move the local file at `staged` into the store as `key`, replacing any object already there. The object
+  /// is either absent or complete, even if this is interrupted. `staged` no longer exists once this succeeds.
+  async fn put(&self, key: &str, staged: &Path) -> InfuResult<()>;
+
+  /// As `put`, but only if there is no object `key` already - checked atomically with the write. Returns false,
+  /// leaving `staged` in place, if there is.
+  async fn put_new(&self, key: &str, staged: &Path) -> InfuResult<bool>;
+
+  /// Stream `len` bytes of object `key`, starting at `start`.
+  async fn get(&self, key: &str, start: u64, len: u64) -> InfuResult<ObjectReader>;
+
+  /// None if there is no object `key`.
+  async fn head(&self, key: &str) -> InfuResult<Option<ObjectMetadata>>;
+
+  async fn delete(&self, key: &str) -> InfuResult<()>;
+
+  /// The keys of all objects whose key starts with `prefix`.
+  async fn list(&self, prefix: &str) -> InfuResult<Vec<String>>;
+}
+
+
+/// Objects kept as files under a directory of the local filesystem, at the path given by their key.
+pub struct LocalObjectStore {
+  root: PathBuf,
+  /// A subdirectory of `root` that does not hold objects.
+  excluded_dir: String,
+}
+
+impl LocalObjectStore {
+  pub fn new(root: PathBuf, excluded_dir: &str) -> LocalObjectStore {
+    LocalObjectStore { root, excluded_dir: String::from(excluded_dir) }
+  }
+
+  fn path_for(&self, key: &str) -> PathBuf {
+    let mut path = self.root.clone();
+    for part in key.split('/') {
+      path.push(part);
+    }
+    path
+  }
+
+  /// Add the keys of the objects under `dir` to `result`. This walks the directory tree with blocking calls, so
+  /// should be run with spawn_blocking.
+  fn list_dir(dir: &Path, excluded_dir: &str, key_prefix: &str, result: &mut Vec<String>) -> InfuResult<()> {
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let name = match entry.file_name().to_str() {
+        Some(name) => String::from(name),
+        None => continue
+      };
+      if entry.file_type()?.is_dir() {
+        if key_prefix.is_empty() && name == excluded_dir { continue; }
+        Self::list_dir(&entry.path(), excluded_dir, &format!("{}{}/", key_prefix, name), result)?;
+      } else if !key_prefix.is_empty() && !name.ends_with(".staging") {
+        // Files at the top level are not objects, and '.staging' files are left over from uploads
+        // that did not complete in earlier versions, which staged them next to their destination.
+        result.push(format!("{}{}", key_prefix, name));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[rocket::async_trait]
+impl ObjectStore for LocalObjectStore {
+  async fn put(&self, key: &str, staged: &Path) -> InfuResult<()> {
+    let path = self.path_for(key);
+    tokio::fs::File::open(staged).await?.sync_all().await?;
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    tokio::fs::rename(staged, &path).await?;
+    tokio::fs::File::open(path.parent().unwrap()).await?.sync_all().await?;
+    Ok(())
+  }
+
+  async fn put_new(&self, key: &str, staged: &Path) -> InfuResult<bool> {
+    let path = self.path_for(key);
+    tokio::fs::File::open(staged).await?.sync_all().await?;
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    // Unlike rename, linking fails if the destination exists.
+    match tokio::fs::hard_link(staged, &path).await {
+      Ok(()) => {},
+      Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(false),
+      Err(e) => return Err(e.into())
+    }
+    tokio::fs::remove_file(staged).await?;
+    tokio::fs::File::open(path.parent().unwrap()).await?.sync_all().await?;
+    Ok(true)
+  }
+
+  async fn get(&self, key: &str, start: u64, len: u64) -> InfuResult<ObjectReader> {
+    let mut file = tokio::fs::File::open(self.path_for(key)).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(Box::pin(file.take(len)))
+  }
+
+  async fn head(&self, key: &str) -> InfuResult<Option<ObjectMetadata>> {
+    match tokio::fs::metadata(self.path_for(key)).await {
+      Ok(metadata) => Ok(Some(ObjectMetadata { size: metadata.len(), last_modified: metadata.modified()? })),
+      Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into())
+    }
+  }
+
+  async fn delete(&self, key: &str) -> InfuResult<()> {
+    Ok(tokio::fs::remove_file(self.path_for(key)).await?)
+  }
+
+  async fn list(&self, prefix: &str) -> InfuResult<Vec<String>> {
+    let (root, excluded_dir) = (self.root.clone(), self.excluded_dir.clone());
+    let mut result = tokio::task::spawn_blocking(move || {
+      let mut result = vec![];
+      Self::list_dir(&root, &excluded_dir, "", &mut result).map(|_| result)
+    }).await.map_err(|e| format!("Listing objects failed: {}", e))??;
+    result.retain(|key| key.starts_with(prefix));
+    Ok(result)
+  }
+}
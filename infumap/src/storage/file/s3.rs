@@ -0,0 +1,452 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use rocket::tokio;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::util::infu::{InfuError, InfuResult};
+use super::hash_file;
+use super::object_store::{ObjectMetadata, ObjectReader, ObjectStore};
+
+
+/// The SHA-256 hash of an empty request body.
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// The headers included in the signature of each request.
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Objects kept in a bucket of an S3 compatible object store. Requests are made to `endpoint` using
+/// path-style addressing (<endpoint>/<bucket>/<key>), which is supported by AWS as well as by
+/// self-hosted stores such as MinIO, and are authenticated with AWS Signature Version 4.
+pub struct S3ObjectStore {
+  client: Client,
+  endpoint: Url,
+  region: String,
+  bucket: String,
+  access_key_id: String,
+  secret_access_key: String,
+}
+
+impl S3ObjectStore {
+  pub fn new(endpoint: &str, region: &str, bucket: &str, access_key_id: &str, secret_access_key: &str) -> InfuResult<S3ObjectStore> {
+    let endpoint = Url::parse(endpoint).map_err(|e| format!("S3 endpoint '{}' is not valid: {}", endpoint, e))?;
+    if endpoint.host_str().is_none() {
+      return Err(format!("S3 endpoint '{}' has no host.", endpoint).into());
+    }
+    Ok(S3ObjectStore {
+      client: Client::new(),
+      endpoint,
+      region: String::from(region),
+      bucket: String::from(bucket),
+      access_key_id: String::from(access_key_id),
+      secret_access_key: String::from(secret_access_key),
+    })
+  }
+
+  /// A request for object `key` (or the bucket, if `key` is empty), signed for a body with SHA-256 hash `payload_hash`.
+  fn request(&self, method: Method, key: &str, query: &[(&str, &str)], payload_hash: &str) -> InfuResult<RequestBuilder> {
+    let path = if key.is_empty() { format!("/{}", self.bucket) } else { format!("/{}/{}", self.bucket, uri_encode(key, false)) };
+    let query = canonical_query(query);
+
+    let mut url = self.endpoint.clone();
+    url.set_path(&path);
+    url.set_query(if query.is_empty() { None } else { Some(&query) });
+    let host = match url.port() {
+      Some(port) => format!("{}:{}", url.host_str().unwrap(), port),
+      None => String::from(url.host_str().unwrap())
+    };
+
+    let now = OffsetDateTime::from(SystemTime::now());
+    let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+    let timestamp = format!("{}T{:02}{:02}{:02}Z", date, now.hour(), now.minute(), now.second());
+    let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+    let canonical_request = canonical_request(method.as_str(), &path, &query, &host, payload_hash, &timestamp);
+    let signature = signature(&self.secret_access_key, &self.region, &date, &timestamp, &canonical_request)?;
+
+    Ok(self.client.request(method, url)
+      .header("x-amz-content-sha256", payload_hash)
+      .header("x-amz-date", timestamp)
+      .header("Authorization", format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        self.access_key_id, scope, SIGNED_HEADERS, signature)))
+  }
+
+  async fn send(&self, request: RequestBuilder, description: &str) -> InfuResult<Response> {
+    request.send().await.map_err(|e| format!("S3 request to {} failed: {}", description, e).into())
+  }
+
+  async fn failure(response: Response, description: &str) -> InfuError {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    format!("S3 request to {} failed with status {}: {}", description, status, body).into()
+  }
+}
+
+#[rocket::async_trait]
+impl ObjectStore for S3ObjectStore {
+  async fn put(&self, key: &str, staged: &Path) -> InfuResult<()> {
+    let staged_path = staged.to_path_buf();
+    let (payload_hash, size) = tokio::task::spawn_blocking(move || hash_file(&staged_path)).await
+      .map_err(|e| format!("Hashing '{}' failed: {}", staged.display(), e))??;
+    let file = tokio::fs::File::open(staged).await?;
+    let request = self.request(Method::PUT, key, &[], &payload_hash)?
+      .header("Content-Length", size)
+      .body(Body::wrap_stream(ReaderStream::new(file)));
+    let description = format!("put '{}'", key);
+    let response = self.send(request, &description).await?;
+    if !response.status().is_success() {
+      return Err(Self::failure(response, &description).await);
+    }
+    tokio::fs::remove_file(staged).await?;
+    Ok(())
+  }
+
+  async fn put_new(&self, key: &str, staged: &Path) -> InfuResult<bool> {
+    let staged_path = staged.to_path_buf();
+    let (payload_hash, size) = tokio::task::spawn_blocking(move || hash_file(&staged_path)).await
+      .map_err(|e| format!("Hashing '{}' failed: {}", staged.display(), e))??;
+    let file = tokio::fs::File::open(staged).await?;
+    let request = self.request(Method::PUT, key, &[], &payload_hash)?
+      .header("Content-Length", size)
+      .header("If-None-Match", "*")
+      .body(Body::wrap_stream(ReaderStream::new(file)));
+    let description = format!("put new '{}'", key);
+    let response = self.send(request, &description).await?;
+    if response.status() == StatusCode::PRECONDITION_FAILED {
+      return Ok(false);
+    }
+    if !response.status().is_success() {
+      return Err(Self::failure(response, &description).await);
+    }
+    tokio::fs::remove_file(staged).await?;
+    Ok(true)
+  }
+
+  async fn get(&self, key: &str, start: u64, len: u64) -> InfuResult<ObjectReader> {
+    if len == 0 {
+      // A range can't be empty.
+      return Ok(Box::pin(tokio::io::empty()));
+    }
+    let request = self.request(Method::GET, key, &[], EMPTY_PAYLOAD_HASH)?
+      .header("Range", format!("bytes={}-{}", start, start + len - 1));
+    let description = format!("get '{}'", key);
+    let response = self.send(request, &description).await?;
+    if !response.status().is_success() {
+      return Err(Self::failure(response, &description).await);
+    }
+    let body = response.bytes_stream().map_err(io::Error::other);
+    Ok(Box::pin(StreamReader::new(body)))
+  }
+
+  async fn head(&self, key: &str) -> InfuResult<Option<ObjectMetadata>> {
+    let request = self.request(Method::HEAD, key, &[], EMPTY_PAYLOAD_HASH)?;
+    let description = format!("head '{}'", key);
+    let response = self.send(request, &description).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+    if !response.status().is_success() {
+      return Err(Self::failure(response, &description).await);
+    }
+    let header = |name: &str| -> InfuResult<String> {
+      Ok(response.headers().get(name)
+        .ok_or(format!("S3 response to {} has no {} header.", description, name))?
+        .to_str().map_err(|e| format!("S3 response to {} has an invalid {} header: {}", description, name, e))?
+        .to_owned())
+    };
+    let size = header("Content-Length")?.parse::<u64>()
+      .map_err(|e| format!("S3 response to {} has an invalid Content-Length header: {}", description, e))?;
+    let last_modified = httpdate::parse_http_date(&header("Last-Modified")?)
+      .map_err(|e| format!("S3 response to {} has an invalid Last-Modified header: {}", description, e))?;
+    Ok(Some(ObjectMetadata { size, last_modified }))
+  }
+
+  async fn delete(&self, key: &str) -> InfuResult<()> {
+    let request = self.request(Method::DELETE, key, &[], EMPTY_PAYLOAD_HASH)?;
+    let description = format!("delete '{}'", key);
+    let response = self.send(request, &description).await?;
+    if !response.status().is_success() {
+      return Err(Self::failure(response, &description).await);
+    }
+    Ok(())
+  }
+
+  async fn list(&self, prefix: &str) -> InfuResult<Vec<String>> {
+    let mut result = vec![];
+    let mut continuation_token: Option<String> = None;
+    loop {
+      let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+      if let Some(token) = &continuation_token {
+        query.push(("continuation-token", token));
+      }
+      let request = self.request(Method::GET, "", &query, EMPTY_PAYLOAD_HASH)?;
+      let description = format!("list '{}'", prefix);
+      let response = self.send(request, &description).await?;
+      if !response.status().is_success() {
+        return Err(Self::failure(response, &description).await);
+      }
+      let body = response.text().await.map_err(|e| format!("S3 response to {} could not be read: {}", description, e))?;
+      let (keys, next_continuation_token) = parse_list_response(&body)
+        .map_err(|e| format!("S3 response to {} is not valid: {}", description, e))?;
+      result.extend(keys);
+      continuation_token = match next_continuation_token {
+        Some(token) => Some(token),
+        None => break
+      };
+    }
+    Ok(result)
+  }
+}
+
+/// The canonical query string of a request with query parameters `query`: each name and value percent
+/// encoded, sorted by name.
+fn canonical_query(query: &[(&str, &str)]) -> String {
+  let mut query = query.iter()
+    .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+    .collect::<Vec<_>>();
+  query.sort();
+  query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&")
+}
+
+/// The canonical form of a request, signing the SIGNED_HEADERS. `path` and `query` must already be encoded.
+fn canonical_request(method: &str, path: &str, query: &str, host: &str, payload_hash: &str, timestamp: &str) -> String {
+  format!(
+    "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+    method, path, query, host, payload_hash, timestamp, SIGNED_HEADERS, payload_hash)
+}
+
+/// The hex encoded Signature Version 4 signature of `canonical_request`, made at `timestamp` (on `date`).
+fn signature(secret_access_key: &str, region: &str, date: &str, timestamp: &str, canonical_request: &str) -> InfuResult<String> {
+  let scope = format!("{}/{}/s3/aws4_request", date, region);
+  let string_to_sign = format!(
+    "AWS4-HMAC-SHA256\n{}\n{}\n{:x}", timestamp, scope, Sha256::digest(canonical_request.as_bytes()));
+  let mut signing_key = hmac_sha256(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes())?;
+  for part in [region, "s3", "aws4_request"] {
+    signing_key = hmac_sha256(&signing_key, part.as_bytes())?;
+  }
+  Ok(hmac_sha256(&signing_key, string_to_sign.as_bytes())?
+    .iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// The keys in a ListObjectsV2 response, and the token to get the next page with if it is truncated.
+fn parse_list_response(body: &str) -> InfuResult<(Vec<String>, Option<String>)> {
+  let keys = xml_elements(body, "Key");
+  match xml_elements(body, "IsTruncated").first().map(|v| v.as_str()) {
+    Some("true") => {
+      let token = xml_elements(body, "NextContinuationToken").into_iter().next()
+        .ok_or("It is truncated, but has no continuation token.")?;
+      Ok((keys, Some(token)))
+    },
+    _ => Ok((keys, None))
+  }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> InfuResult<Vec<u8>> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| format!("Could not create HMAC: {}", e))?;
+  mac.update(data);
+  Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Percent encode `s` as required for signing - everything but unreserved characters, and '/' unless
+/// `encode_slash`.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+  let mut result = String::new();
+  for b in s.bytes() {
+    match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => result.push(b as char),
+      b'/' if !encode_slash => result.push('/'),
+      _ => result.push_str(&format!("%{:02X}", b))
+    }
+  }
+  result
+}
+
+/// The text of each `name` element in `xml`. S3 responses are simple enough that this need not parse them properly.
+fn xml_elements(xml: &str, name: &str) -> Vec<String> {
+  let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+  let mut result = vec![];
+  let mut rest = xml;
+  while let Some(start) = rest.find(&open) {
+    rest = &rest[start + open.len()..];
+    let end = match rest.find(&close) { Some(end) => end, None => break };
+    result.push(rest[..end]
+      .replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&"));
+    rest = &rest[end + close.len()..];
+  }
+  result
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use rocket::tokio;
+  use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use crate::util::uid::new_uid;
+  use super::super::object_store::ObjectStore;
+  use super::{canonical_query, canonical_request, parse_list_response, signature, xml_elements, S3ObjectStore, EMPTY_PAYLOAD_HASH};
+
+  // The examples of "Signature Calculations for the Authorization Header: Transferring Payload in a Single
+  // Chunk" in the AWS S3 API reference, which sign the same headers as S3ObjectStore.
+  const EXAMPLE_SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+  const EXAMPLE_HOST: &str = "examplebucket.s3.amazonaws.com";
+  const EXAMPLE_DATE: &str = "20130524";
+  const EXAMPLE_TIMESTAMP: &str = "20130524T000000Z";
+
+  #[test]
+  fn signs_get_bucket_lifecycle_example() {
+    let query = canonical_query(&[("lifecycle", "")]);
+    let canonical_request = canonical_request("GET", "/", &query, EXAMPLE_HOST, EMPTY_PAYLOAD_HASH, EXAMPLE_TIMESTAMP);
+    assert_eq!(canonical_request, concat!(
+      "GET\n/\nlifecycle=\nhost:examplebucket.s3.amazonaws.com\n",
+      "x-amz-content-sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\nx-amz-date:20130524T000000Z\n\n",
+      "host;x-amz-content-sha256;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+    assert_eq!(
+      signature(EXAMPLE_SECRET_ACCESS_KEY, "us-east-1", EXAMPLE_DATE, EXAMPLE_TIMESTAMP, &canonical_request).unwrap(),
+      "fea454ca298b7da1c68078a5d1bdbfbbe0d65c699e0f91ac7a200a0136783543");
+  }
+
+  #[test]
+  fn signs_list_objects_example() {
+    let query = canonical_query(&[("prefix", "J"), ("max-keys", "2")]);
+    assert_eq!(query, "max-keys=2&prefix=J");
+    let canonical_request = canonical_request("GET", "/", &query, EXAMPLE_HOST, EMPTY_PAYLOAD_HASH, EXAMPLE_TIMESTAMP);
+    assert_eq!(
+      signature(EXAMPLE_SECRET_ACCESS_KEY, "us-east-1", EXAMPLE_DATE, EXAMPLE_TIMESTAMP, &canonical_request).unwrap(),
+      "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7");
+  }
+
+  #[test]
+  fn canonical_query_encodes_names_and_values() {
+    assert_eq!(canonical_query(&[("prefix", "a/b c"), ("continuation-token", "x+y=")]),
+               "continuation-token=x%2By%3D&prefix=a%2Fb%20c");
+  }
+
+  #[test]
+  fn xml_elements_finds_each_element_and_unescapes_it() {
+    let xml = "<R><Key>a</Key><Other><Key>b&amp;c&lt;d&gt;</Key></Other><Key></Key><Key>unterminated</R>";
+    assert_eq!(xml_elements(xml, "Key"), vec!["a", "b&c<d>", ""]);
+    assert!(xml_elements(xml, "Missing").is_empty());
+  }
+
+  #[test]
+  fn parse_list_response_returns_continuation_token_only_if_truncated() {
+    let (keys, token) = parse_list_response(&list_response(&["a", "b"], Some("t1"))).unwrap();
+    assert_eq!(keys, vec!["a", "b"]);
+    assert_eq!(token, Some(String::from("t1")));
+
+    let (keys, token) = parse_list_response(&list_response(&[], None)).unwrap();
+    assert!(keys.is_empty());
+    assert_eq!(token, None);
+
+    assert!(parse_list_response("<ListBucketResult><IsTruncated>true</IsTruncated></ListBucketResult>").is_err());
+  }
+
+  #[rocket::async_test]
+  async fn list_follows_continuation_tokens() {
+    // A stand-in for S3 that returns the keys in pages of two.
+    let keys = ["k/1", "k/2", "k/3", "k/4", "k/5"];
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let queries = Arc::new(Mutex::new(vec![]));
+    let server_queries = queries.clone();
+    tokio::spawn(async move {
+      loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let mut buf = [0u8; 4096];
+        while !request.ends_with(b"\r\n\r\n") {
+          let n = stream.read(&mut buf).await.unwrap();
+          if n == 0 { break; }
+          request.extend_from_slice(&buf[..n]);
+        }
+        let request_line = String::from_utf8_lossy(&request).lines().next().unwrap().to_string();
+        let query = request_line.split(' ').nth(1).unwrap().split_once('?').map(|(_, q)| q.to_string()).unwrap_or_default();
+        // Tokens are "page+<n>", which must be encoded in the query.
+        let page = query.split('&')
+          .find_map(|p| p.strip_prefix("continuation-token=page%2B"))
+          .map(|n| n.parse::<usize>().unwrap())
+          .unwrap_or(0);
+        server_queries.lock().unwrap().push(query);
+        let end = (page * 2 + 2).min(keys.len());
+        let next = if end < keys.len() { Some(format!("page+{}", page + 1)) } else { None };
+        let body = list_response(&keys[page * 2..end], next.as_deref());
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+        stream.write_all(response.as_bytes()).await.unwrap();
+      }
+    });
+
+    let store = S3ObjectStore::new(&format!("http://127.0.0.1:{}", port), "us-east-1", "bucket", "id", "secret").unwrap();
+    assert_eq!(store.list("k/").await.unwrap(), keys);
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.len(), 3);
+    assert!(queries.iter().all(|q| q.contains("list-type=2") && q.contains("prefix=k%2F")));
+    assert!(!queries[0].contains("continuation-token"));
+    assert!(queries[2].contains("continuation-token=page%2B2"));
+  }
+
+  /// Run against a real S3 compatible store (e.g. MinIO) with `cargo test -- --ignored`. INFUMAP_TEST_S3_ENDPOINT,
+  /// INFUMAP_TEST_S3_BUCKET (which must exist), INFUMAP_TEST_S3_ACCESS_KEY_ID and INFUMAP_TEST_S3_SECRET_ACCESS_KEY
+  /// must be set, and optionally INFUMAP_TEST_S3_REGION.
+  #[rocket::async_test]
+  #[ignore = "requires an S3 compatible store, given by the INFUMAP_TEST_S3_* environment variables"]
+  async fn object_store_operations_against_endpoint() {
+    let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{} must be set to run this test.", name));
+    let region = std::env::var("INFUMAP_TEST_S3_REGION").unwrap_or(String::from("us-east-1"));
+    let store = S3ObjectStore::new(&var("INFUMAP_TEST_S3_ENDPOINT"), &region, &var("INFUMAP_TEST_S3_BUCKET"),
+                                   &var("INFUMAP_TEST_S3_ACCESS_KEY_ID"), &var("INFUMAP_TEST_S3_SECRET_ACCESS_KEY")).unwrap();
+
+    let prefix = format!("infumap_test_{}/", new_uid());
+    let key = format!("{}object", prefix);
+    let staged = std::env::temp_dir().join(format!("infumap-s3-test-{}", new_uid()));
+    let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+
+    std::fs::write(&staged, &data).unwrap();
+    assert!(store.put_new(&key, &staged).await.unwrap());
+    assert!(!staged.exists());
+    std::fs::write(&staged, b"other").unwrap();
+    assert!(!store.put_new(&key, &staged).await.unwrap());
+    assert!(staged.exists());
+    std::fs::remove_file(&staged).unwrap();
+
+    assert_eq!(store.head(&key).await.unwrap().unwrap().size, data.len() as u64);
+    let mut range = vec![];
+    store.get(&key, 1000, 5000).await.unwrap().read_to_end(&mut range).await.unwrap();
+    assert_eq!(range, &data[1000..6000]);
+    assert_eq!(store.list(&prefix).await.unwrap(), vec![key.clone()]);
+
+    store.delete(&key).await.unwrap();
+    assert!(store.head(&key).await.unwrap().is_none());
+    assert!(store.list(&prefix).await.unwrap().is_empty());
+  }
+
+  fn list_response(keys: &[&str], next_continuation_token: Option<&str>) -> String {
+    let contents = keys.iter().map(|key| format!("<Contents><Key>{}</Key><Size>1</Size></Contents>", key)).collect::<String>();
+    let token = next_continuation_token.map(|t| format!("<NextContinuationToken>{}</NextContinuationToken>", t)).unwrap_or_default();
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>bucket</Name><IsTruncated>{}</IsTruncated>{}{}</ListBucketResult>",
+            next_continuation_token.is_some(), contents, token)
+  }
+}
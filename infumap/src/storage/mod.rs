@@ -16,4 +16,5 @@
 
 pub mod cache;
 pub mod db;
+pub mod encryption;
 pub mod file;
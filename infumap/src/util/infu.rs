@@ -64,6 +64,12 @@ impl From<SystemTimeError> for InfuError {
   }
 }
 
+impl From<image::ImageError> for InfuError {
+  fn from(err: image::ImageError) -> Self {
+    Self::new(&err.to_string())
+  }
+}
+
 impl From<String> for InfuError {
   fn from(err: String) -> Self {
     Self::new(&err)
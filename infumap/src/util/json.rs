@@ -35,6 +35,19 @@ pub fn get_float_field(map: &Map<String, Value>, field: &str) -> InfuResult<Opti
   Ok(Some(v.as_f64().ok_or(format!("'{}' field was not of type 'f64'.", field))?))
 }
 
+pub fn get_bool_field(map: &Map<String, Value>, field: &str) -> InfuResult<Option<bool>> {
+  let v = match map.get(field) { None => return Ok(None), Some(s) => s };
+  Ok(Some(v.as_bool().ok_or(format!("'{}' field was not of type 'bool'.", field))?))
+}
+
+pub fn get_string_array_field(map: &Map<String, Value>, field: &str) -> InfuResult<Option<Vec<String>>> {
+  let v = match map.get(field) { None => return Ok(None), Some(s) => s };
+  let a = v.as_array().ok_or(format!("'{}' field was not of type 'array'.", field))?;
+  Ok(Some(a.iter()
+    .map(|e| e.as_str().map(String::from).ok_or(format!("'{}' field has an element that is not of type 'string'.", field).into()))
+    .collect::<InfuResult<Vec<String>>>()?))
+}
+
 pub fn get_vector_field(map: &Map<String, Value>, field: &str) -> InfuResult<Option<Vector<i64>>> {
   let v = match map.get(field) { None => return Ok(None), Some(s) => s };
   let o = v.as_object().ok_or(format!("'{}' field was not of type 'object'.", field))?;
@@ -19,4 +19,5 @@ pub mod infu;
 pub mod uid;
 pub mod geometry;
 pub mod lang;
-pub mod json;
\ No newline at end of file
+pub mod json;
+pub mod ordering;
\ No newline at end of file
@@ -0,0 +1,45 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Ordering keys for items within a parent. These mirror the functions of the same name in
+//! web/src/util/ordering.ts, and must produce the same results.
+
+const N: u8 = 1;
+
+pub fn new_ordering() -> Vec<u8> {
+  vec![128]
+}
+
+pub fn new_ordering_after(end: &[u8]) -> Vec<u8> {
+  let mut r = vec![];
+  for v in end {
+    if *v == 255 {
+      r.push(255);
+      continue;
+    }
+    r.push(*v + N);
+    return r;
+  }
+  r.push(N);
+  r
+}
+
+pub fn new_ordering_at_end(orderings: &[&Vec<u8>]) -> Vec<u8> {
+  match orderings.iter().max() {
+    Some(highest) => new_ordering_after(highest),
+    None => new_ordering()
+  }
+}
@@ -0,0 +1,169 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::time::Duration;
+use crate::storage::db::Db;
+use crate::storage::db::api_token::ApiToken;
+use crate::storage::db::api_token_db::TOKEN_PREFIX;
+use crate::storage::db::session::{hash_session_id, Session};
+use crate::util::infu::InfuResult;
+use crate::util::uid::Uid;
+
+
+/// The HttpOnly cookie the session id is kept in by browsers. It is set on login, so is not accessible to
+/// scripts, and is only sent with requests made from Infumap pages (SameSite=Strict).
+pub const SESSION_COOKIE_NAME: &str = "infumap_session";
+
+/// Request guard for routes that act on behalf of a user. The credential is taken from an "Authorization: Bearer
+/// <session id or API token>" header if there is one (for clients other than the browser), otherwise from the
+/// session cookie. Requests without a valid session or API token fail with 401 Unauthorized.
+pub struct Authenticated {
+  pub user_id: Uid,
+  /// The session the request was made with, or None if it was made with an API token.
+  pub session: Option<Session>,
+  /// The API token the request was made with, or None if it was made with a session.
+  pub api_token: Option<ApiToken>,
+}
+
+impl Authenticated {
+  /// Check that the credential the request was made with allows access to items `item_ids`, which must be within
+  /// the subtree an API token is scoped to, and that it may change them if `writes` is true.
+  pub fn check_access(&self, db: &Db, item_ids: &[&Uid], writes: bool) -> InfuResult<()> {
+    let api_token = match &self.api_token {
+      Some(api_token) => api_token,
+      None => return Ok(())
+    };
+    if writes && api_token.read_only {
+      return Err(format!("API token '{}' is read-only.", api_token.id).into());
+    }
+    if let Some(scope_item_id) = &api_token.scope_item_id {
+      for item_id in item_ids {
+        if !db.is_within(scope_item_id, item_id)? {
+          return Err(format!("Item '{}' is not within the scope of API token '{}'.", item_id, api_token.id).into());
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether the request was made with an API token that is limited to a subtree.
+  pub fn is_scoped(&self) -> bool {
+    self.api_token.as_ref().map(|api_token| api_token.scope_item_id.is_some()).unwrap_or(false)
+  }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authenticated {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let credential = match request.headers().get_one("Authorization") {
+      Some(authorization) => match authorization.strip_prefix("Bearer ") {
+        Some(credential) => credential.trim().to_string(),
+        None => {
+          warn!("Request to '{}' has an Authorization header that is not of the form 'Bearer <credential>'.", request.uri());
+          return Outcome::Failure((Status::Unauthorized, ()));
+        }
+      },
+      None => match request.cookies().get(SESSION_COOKIE_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Outcome::Failure((Status::Unauthorized, ()))
+      }
+    };
+    let db = match request.rocket().state::<Arc<Mutex<Db>>>() {
+      Some(db) => db,
+      None => return Outcome::Failure((Status::InternalServerError, ()))
+    };
+
+    if credential.starts_with(TOKEN_PREFIX) {
+      let result = db.lock().unwrap().api_token.authenticate(&credential);
+      return match result {
+        Ok(Some(api_token)) => Outcome::Success(Authenticated { user_id: api_token.user_id.clone(), session: None, api_token: Some(api_token) }),
+        Ok(None) => {
+          info!("API token used in request to '{}' is not valid. It may have been revoked.", request.uri());
+          Outcome::Failure((Status::Unauthorized, ()))
+        },
+        Err(e) => {
+          error!("An error occurred retrieving an API token: {}", e);
+          Outcome::Failure((Status::InternalServerError, ()))
+        }
+      };
+    }
+
+    let result = db.lock().unwrap().session.get_session(&credential);
+    // Session ids grant access to their user's data, so are not logged. The start of their hash is the public id
+    // of the session, if it exists.
+    let public_id = &hash_session_id(&credential)[..16];
+    match result {
+      Ok(Some(session)) => Outcome::Success(Authenticated { user_id: session.user_id.clone(), session: Some(session), api_token: None }),
+      Ok(None) => {
+        info!("Session '{}' used in request to '{}' is not available. It may have expired.", public_id, request.uri());
+        Outcome::Failure((Status::Unauthorized, ()))
+      },
+      Err(e) => {
+        error!("An error occurred retrieving session '{}': {}", public_id, e);
+        Outcome::Failure((Status::InternalServerError, ()))
+      }
+    }
+  }
+}
+
+/// Request guard for routes that manage the account (its sessions, second factor and API tokens), which can't be
+/// used with an API token. Requests made with one fail with 403 Forbidden.
+pub struct AuthenticatedSession {
+  pub session: Session,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedSession {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match request.guard::<Authenticated>().await {
+      Outcome::Success(Authenticated { session: Some(session), .. }) => Outcome::Success(AuthenticatedSession { session }),
+      Outcome::Success(_) => {
+        warn!("Request to '{}' was made with an API token, which can't be used to manage the account.", request.uri());
+        Outcome::Failure((Status::Forbidden, ()))
+      },
+      Outcome::Failure(f) => Outcome::Failure(f),
+      Outcome::Forward(f) => Outcome::Forward(f)
+    }
+  }
+}
+
+/// How the session cookie is set, from the secure_cookies setting.
+pub struct SessionCookieSettings {
+  pub secure: bool,
+}
+
+/// Set the session cookie to `session_id`, the id of `session`, which expires along with it.
+pub fn set_session_cookie(cookies: &CookieJar<'_>, settings: &SessionCookieSettings, session: &Session, session_id: &str) {
+  let max_age = session.expires - session.created;
+  cookies.add(Cookie::build(SESSION_COOKIE_NAME, String::from(session_id))
+    .path("/")
+    .http_only(true)
+    .secure(settings.secure)
+    .same_site(SameSite::Strict)
+    .max_age(Duration::seconds(max_age))
+    .finish());
+}
+
+pub fn remove_session_cookie(cookies: &CookieJar<'_>) {
+  cookies.remove(Cookie::build(SESSION_COOKIE_NAME, "").path("/").finish());
+}
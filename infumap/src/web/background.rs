@@ -0,0 +1,128 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rocket::tokio;
+
+use crate::storage::cache::FileCache;
+use crate::storage::db::Db;
+use crate::storage::file::{FileStore, GcReport};
+use crate::util::infu::InfuResult;
+use crate::util::uid::Uid;
+
+
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CACHE_EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Start a task that, once an hour, permanently removes items that have been in the trash for
+/// more than `retention_days`, along with their file data. This must be called from within the
+/// async runtime.
+pub fn start_trash_purge(db: Arc<Mutex<Db>>, file_store: Arc<FileStore>, retention_days: i64) {
+  tokio::spawn(async move {
+    loop {
+      match purge_trash(&db, &file_store, retention_days).await {
+        Ok(0) => {},
+        Ok(count) => { info!("Purged {} items that had been in the trash for more than {} days.", count, retention_days); },
+        Err(e) => { error!("An error occurred purging the trash: {}", e); }
+      }
+      tokio::time::sleep(TRASH_PURGE_INTERVAL).await;
+    }
+  });
+}
+
+/// Permanently remove items that have been in the trash for more than `older_than_days`, along with
+/// their file data. Returns the number of items removed.
+pub async fn purge_trash(db: &Mutex<Db>, file_store: &FileStore, older_than_days: i64) -> InfuResult<usize> {
+  let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+  let purged = db.lock().unwrap().purge_trash(now - older_than_days * 24 * 60 * 60)?;
+  file_store.delete_for_items(&purged).await;
+  Ok(purged.len())
+}
+
+/// Start a task that, every `interval_hours`, deletes file data that no item refers to, and logs a warning for
+/// each file or image item whose data is missing. This must be called from within the async runtime.
+pub fn start_gc(db: Arc<Mutex<Db>>, file_store: Arc<FileStore>, interval_hours: i64) {
+  tokio::spawn(async move {
+    loop {
+      match collect_garbage(&db, &file_store, false).await {
+        Ok(report) => {
+          if !report.orphaned_ids.is_empty() || report.unreferenced_blob_count > 0 {
+            info!("Deleted the file data of {} items that no longer exist, and {} unreferenced blobs, freeing {} bytes.",
+                  report.orphaned_ids.len(), report.unreferenced_blob_count, report.reclaimable_bytes);
+          }
+          for id in &report.missing_ids {
+            warn!("There is no file data for item '{}'.", id);
+          }
+        },
+        Err(e) => { error!("An error occurred collecting garbage from the file store: {}", e); }
+      }
+      tokio::time::sleep(Duration::from_secs(interval_hours as u64 * 60 * 60)).await;
+    }
+  });
+}
+
+/// Find file data that no file or image item refers to, and delete it unless `dry_run` is set. The
+/// items of all users are loaded to do this.
+pub async fn collect_garbage(db: &Mutex<Db>, file_store: &FileStore, dry_run: bool) -> InfuResult<GcReport> {
+  let data_item_ids = db.lock().unwrap().data_items_by_user()?.into_iter()
+    .flat_map(|(_, items)| items.into_iter().map(|item| item.id))
+    .collect::<HashSet<Uid>>();
+  file_store.collect_garbage(&data_item_ids, dry_run).await
+}
+
+/// Read the data of every file and image item, checking it against the size and content hash recorded for
+/// the item. Returns the number of items checked, how many of those had no content hash (so only their size
+/// was checked), and a description of each problem found.
+pub async fn verify_files(db: &Mutex<Db>, file_store: &FileStore) -> InfuResult<(usize, usize, Vec<String>)> {
+  let data_items_by_user = {
+    let mut db = db.lock().unwrap();
+    db.data_items_by_user()?.into_iter()
+      .map(|(user, items)| Ok((db.user_keyring(&user.id)?, user, items)))
+      .collect::<InfuResult<Vec<_>>>()?
+  };
+  let (mut checked_count, mut unhashed_count, mut problems) = (0, 0, vec![]);
+  for (keyring, user, mut items) in data_items_by_user {
+    items.sort_by(|a, b| a.title.cmp(&b.title));
+    for item in items {
+      if let Some(problem) = file_store.verify(&item, keyring.as_ref()).await {
+        problems.push(format!("User '{}': {} '{}' ({}): {}.",
+                              user.username, item.item_type, item.title.as_deref().unwrap_or(""), item.id, problem));
+      }
+      checked_count += 1;
+      if item.content_hash.is_none() {
+        unhashed_count += 1;
+      }
+    }
+  }
+  Ok((checked_count, unhashed_count, problems))
+}
+
+/// Start a thread that, once a minute, evicts the least recently used files from the file cache if it
+/// is over its maximum size, and persists the cache index.
+pub fn start_cache_eviction(file_cache: Arc<FileCache>) {
+  thread::spawn(move || loop {
+    thread::sleep(CACHE_EVICTION_INTERVAL);
+    match file_cache.evict() {
+      Ok(0) => {},
+      Ok(count) => { info!("Evicted {} files from the file cache.", count); },
+      Err(e) => { error!("An error occurred evicting files from the file cache: {}", e); }
+    }
+  });
+}
@@ -0,0 +1,314 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+
+/// This many failed logins for a username are allowed in quick succession. After that, each must wait twice as
+/// long as the last before another attempt is accepted, starting at BASE_DELAY_SECS.
+const USERNAME_FREE_ATTEMPTS: u32 = 3;
+/// After this many failed logins for a username, no attempts are accepted for LOCKOUT_SECS.
+const USERNAME_LOCKOUT_ATTEMPTS: u32 = 10;
+/// The same, for failed logins from an IP address, for any username. These are higher, since many users may
+/// share an address.
+const IP_FREE_ATTEMPTS: u32 = 10;
+const IP_LOCKOUT_ATTEMPTS: u32 = 50;
+
+const BASE_DELAY_SECS: i64 = 1;
+const LOCKOUT_SECS: i64 = 60*15;
+
+/// The failed logins of a username or IP address are forgotten once there have been none for this long.
+const FORGET_AFTER_SECS: i64 = 60*60;
+
+/// Records that can be forgotten are removed once this many usernames, or addresses, have records, so that attempts
+/// with many usernames or addresses can't use up memory. If that is not enough, the records with the oldest failures
+/// are removed, down to RECORDS_AFTER_EVICTION.
+const MAX_RECORDS: usize = 100_000;
+const RECORDS_AFTER_EVICTION: usize = MAX_RECORDS / 10 * 9;
+
+
+#[derive(Clone)]
+pub struct FailedLogins {
+  /// The number of failed logins since the last success, or since FORGET_AFTER_SECS without one failing.
+  pub count: u32,
+  pub last_failure: i64,
+  /// Login attempts are rejected without being checked until this time.
+  pub blocked_until: i64,
+}
+
+impl FailedLogins {
+  fn record_failure(&mut self, now: i64, free_attempts: u32, lockout_attempts: u32) {
+    if now - self.last_failure >= FORGET_AFTER_SECS {
+      self.count = 0;
+    }
+    self.count += 1;
+    self.last_failure = now;
+    self.update_blocked_until(free_attempts, lockout_attempts);
+  }
+
+  /// Undo `record_failure`, for an attempt that turned out not to fail.
+  fn undo_failure(&mut self, free_attempts: u32, lockout_attempts: u32) {
+    self.count = self.count.saturating_sub(1);
+    self.update_blocked_until(free_attempts, lockout_attempts);
+  }
+
+  fn update_blocked_until(&mut self, free_attempts: u32, lockout_attempts: u32) {
+    self.blocked_until =
+      if self.count >= lockout_attempts { self.last_failure + LOCKOUT_SECS }
+      else if self.count > free_attempts { self.last_failure + (BASE_DELAY_SECS << (self.count - free_attempts - 1)).min(LOCKOUT_SECS) }
+      else { self.last_failure };
+  }
+}
+
+
+/// Tracks failed logins per username and per client IP address, so that passwords can't be guessed quickly.
+/// Usernames that don't exist are tracked the same as those that do, so that how login attempts are treated does
+/// not reveal which exist. This is not persisted - a restart forgets all failures.
+#[derive(Default)]
+pub struct LoginThrottle {
+  by_username: HashMap<String, FailedLogins>,
+  by_ip: HashMap<IpAddr, FailedLogins>,
+}
+
+impl LoginThrottle {
+  /// If login attempts for `username`, or from `ip`, are currently blocked, the number of seconds until they
+  /// will be accepted again.
+  pub fn retry_after(&self, username: &str, ip: Option<IpAddr>, now: i64) -> Option<i64> {
+    let username_blocked_until = self.by_username.get(username).map(|f| f.blocked_until).unwrap_or(0);
+    let ip_blocked_until = ip.and_then(|ip| self.by_ip.get(&ip)).map(|f| f.blocked_until).unwrap_or(0);
+    let blocked_until = username_blocked_until.max(ip_blocked_until);
+    if blocked_until > now { Some(blocked_until - now) } else { None }
+  }
+
+  /// Start a login attempt for `username` from `ip`, or if attempts are currently blocked, return the number of
+  /// seconds until they will be accepted again. The attempt is recorded as a failure straight away, so that
+  /// attempts made at the same time count towards the limits while they are being checked. If it does not fail,
+  /// `record_success` or `release` must be called.
+  pub fn start_attempt(&mut self, username: &str, ip: Option<IpAddr>, now: i64) -> Result<(), i64> {
+    if let Some(retry_after_secs) = self.retry_after(username, ip, now) {
+      return Err(retry_after_secs);
+    }
+    self.record_failure(username, ip, now);
+    Ok(())
+  }
+
+  fn record_failure(&mut self, username: &str, ip: Option<IpAddr>, now: i64) {
+    if self.by_username.len() >= MAX_RECORDS || self.by_ip.len() >= MAX_RECORDS {
+      self.forget_old(now);
+      evict_oldest(&mut self.by_username);
+      evict_oldest(&mut self.by_ip);
+    }
+    self.by_username.entry(String::from(username)).or_insert_with(new_record)
+      .record_failure(now, USERNAME_FREE_ATTEMPTS, USERNAME_LOCKOUT_ATTEMPTS);
+    if let Some(ip) = ip {
+      self.by_ip.entry(ip).or_insert_with(new_record)
+        .record_failure(now, IP_FREE_ATTEMPTS, IP_LOCKOUT_ATTEMPTS);
+    }
+  }
+
+  /// Forget the failed logins of `username`, after an attempt started with `start_attempt` succeeds. Those from
+  /// the IP address are kept, other than the attempt itself, otherwise someone with an account could reset them
+  /// by logging in to it between guesses at the passwords of others.
+  pub fn record_success(&mut self, username: &str, ip: Option<IpAddr>) {
+    self.by_username.remove(username);
+    self.release_ip(ip);
+  }
+
+  /// Undo `start_attempt`, for an attempt that neither failed nor succeeded - e.g. one with the right password,
+  /// but which still needs a TOTP code.
+  pub fn release(&mut self, username: &str, ip: Option<IpAddr>) {
+    if let Some(f) = self.by_username.get_mut(username) {
+      f.undo_failure(USERNAME_FREE_ATTEMPTS, USERNAME_LOCKOUT_ATTEMPTS);
+      if f.count == 0 { self.by_username.remove(username); }
+    }
+    self.release_ip(ip);
+  }
+
+  fn release_ip(&mut self, ip: Option<IpAddr>) {
+    if let Some(ip) = ip {
+      if let Some(f) = self.by_ip.get_mut(&ip) {
+        f.undo_failure(IP_FREE_ATTEMPTS, IP_LOCKOUT_ATTEMPTS);
+        if f.count == 0 { self.by_ip.remove(&ip); }
+      }
+    }
+  }
+
+  /// The failed logins of each username with any that have not been forgotten.
+  pub fn get_by_username(&self, now: i64) -> Vec<(String, FailedLogins)> {
+    self.by_username.iter()
+      .filter(|(_, f)| !is_forgotten(f, now))
+      .map(|(username, f)| (username.clone(), f.clone()))
+      .collect()
+  }
+
+  /// The failed logins from each IP address with any that have not been forgotten.
+  pub fn get_by_ip(&self, now: i64) -> Vec<(IpAddr, FailedLogins)> {
+    self.by_ip.iter()
+      .filter(|(_, f)| !is_forgotten(f, now))
+      .map(|(ip, f)| (*ip, f.clone()))
+      .collect()
+  }
+
+  /// Forget the failed logins of `username_or_ip`, unblocking it. Returns false if there were none.
+  pub fn clear(&mut self, username_or_ip: &str) -> bool {
+    let cleared_ip = match username_or_ip.parse::<IpAddr>() {
+      Ok(ip) => self.by_ip.remove(&ip).is_some(),
+      Err(_) => false
+    };
+    self.by_username.remove(username_or_ip).is_some() || cleared_ip
+  }
+
+  fn forget_old(&mut self, now: i64) {
+    self.by_username.retain(|_, f| !is_forgotten(f, now));
+    self.by_ip.retain(|_, f| !is_forgotten(f, now));
+  }
+}
+
+fn new_record() -> FailedLogins {
+  FailedLogins { count: 0, last_failure: 0, blocked_until: 0 }
+}
+
+fn is_forgotten(failed_logins: &FailedLogins, now: i64) -> bool {
+  failed_logins.blocked_until <= now && now - failed_logins.last_failure >= FORGET_AFTER_SECS
+}
+
+/// If there are MAX_RECORDS or more `records`, remove those with the oldest last failure, leaving
+/// RECORDS_AFTER_EVICTION.
+fn evict_oldest<K: Eq + Hash>(records: &mut HashMap<K, FailedLogins>) {
+  if records.len() < MAX_RECORDS { return; }
+  let mut to_remove = records.len() - RECORDS_AFTER_EVICTION;
+  let mut last_failures = records.values().map(|f| f.last_failure).collect::<Vec<i64>>();
+  let cutoff = *last_failures.select_nth_unstable(to_remove - 1).1;
+  // There are fewer than to_remove records older than the cutoff. The rest are taken from those at it.
+  records.retain(|_, f| f.last_failure >= cutoff);
+  to_remove -= last_failures.len() - records.len();
+  records.retain(|_, f| {
+    if to_remove > 0 && f.last_failure == cutoff { to_remove -= 1; false } else { true }
+  });
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::net::IpAddr;
+  use super::{LoginThrottle, BASE_DELAY_SECS, FORGET_AFTER_SECS, IP_FREE_ATTEMPTS, LOCKOUT_SECS, MAX_RECORDS,
+              RECORDS_AFTER_EVICTION, USERNAME_FREE_ATTEMPTS, USERNAME_LOCKOUT_ATTEMPTS};
+
+  const NOW: i64 = 1_700_000_000;
+
+  fn ip() -> Option<IpAddr> {
+    Some("192.0.2.1".parse().unwrap())
+  }
+
+  fn username_count(throttle: &LoginThrottle, now: i64) -> u32 {
+    throttle.get_by_username(now).iter().find(|(username, _)| username == "user").map(|(_, f)| f.count).unwrap_or(0)
+  }
+
+  #[test]
+  fn failed_logins_are_delayed_then_locked_out() {
+    let mut throttle = LoginThrottle::default();
+    let mut now = NOW;
+    for _ in 0..USERNAME_FREE_ATTEMPTS {
+      assert_eq!(throttle.start_attempt("user", None, now), Ok(()));
+    }
+    let mut delay = BASE_DELAY_SECS;
+    for _ in USERNAME_FREE_ATTEMPTS..USERNAME_LOCKOUT_ATTEMPTS - 1 {
+      assert_eq!(throttle.start_attempt("user", None, now), Ok(()));
+      assert_eq!(throttle.start_attempt("user", None, now), Err(delay));
+      now += delay;
+      delay *= 2;
+    }
+    assert_eq!(throttle.start_attempt("user", None, now), Ok(()));
+    assert_eq!(throttle.retry_after("user", None, now), Some(LOCKOUT_SECS));
+    assert_eq!(throttle.start_attempt("user", None, now + LOCKOUT_SECS - 1), Err(1));
+    assert_eq!(throttle.retry_after("other", None, now), None);
+  }
+
+  #[test]
+  fn failed_logins_from_an_address_are_delayed_for_any_username() {
+    let mut throttle = LoginThrottle::default();
+    for i in 0..=IP_FREE_ATTEMPTS {
+      assert_eq!(throttle.start_attempt(&format!("user{}", i), ip(), NOW), Ok(()));
+    }
+    assert_eq!(throttle.start_attempt("other", ip(), NOW), Err(BASE_DELAY_SECS));
+    assert_eq!(throttle.start_attempt("other", Some("192.0.2.2".parse().unwrap()), NOW), Ok(()));
+  }
+
+  #[test]
+  fn released_attempts_are_not_counted() {
+    let mut throttle = LoginThrottle::default();
+    for _ in 0..USERNAME_LOCKOUT_ATTEMPTS {
+      assert_eq!(throttle.start_attempt("user", ip(), NOW), Ok(()));
+      throttle.release("user", ip());
+    }
+    assert!(throttle.get_by_username(NOW).is_empty());
+    assert!(throttle.get_by_ip(NOW).is_empty());
+
+    assert_eq!(throttle.start_attempt("user", ip(), NOW), Ok(()));
+    assert_eq!(throttle.start_attempt("user", ip(), NOW), Ok(()));
+    throttle.release("user", ip());
+    assert_eq!(username_count(&throttle, NOW), 1);
+  }
+
+  #[test]
+  fn success_forgets_failures_of_username_but_not_of_address() {
+    let mut throttle = LoginThrottle::default();
+    for _ in 0..USERNAME_FREE_ATTEMPTS {
+      assert_eq!(throttle.start_attempt("user", ip(), NOW), Ok(()));
+    }
+    assert_eq!(throttle.start_attempt("user", ip(), NOW), Ok(()));
+    throttle.record_success("user", ip());
+    assert!(throttle.get_by_username(NOW).is_empty());
+    assert_eq!(throttle.get_by_ip(NOW)[0].1.count, USERNAME_FREE_ATTEMPTS);
+  }
+
+  #[test]
+  fn failures_are_forgotten() {
+    let mut throttle = LoginThrottle::default();
+    for _ in 0..USERNAME_FREE_ATTEMPTS + 1 {
+      assert_eq!(throttle.start_attempt("user", None, NOW), Ok(()));
+    }
+    assert_eq!(username_count(&throttle, NOW + FORGET_AFTER_SECS - 1), USERNAME_FREE_ATTEMPTS + 1);
+    assert!(throttle.get_by_username(NOW + FORGET_AFTER_SECS).is_empty());
+    // Counting starts again.
+    assert_eq!(throttle.start_attempt("user", None, NOW + FORGET_AFTER_SECS), Ok(()));
+    assert_eq!(username_count(&throttle, NOW + FORGET_AFTER_SECS), 1);
+  }
+
+  #[test]
+  fn forgettable_records_are_removed_at_the_limit() {
+    let mut throttle = LoginThrottle::default();
+    for i in 0..MAX_RECORDS {
+      assert_eq!(throttle.start_attempt(&format!("user{}", i), None, NOW), Ok(()));
+    }
+    assert_eq!(throttle.start_attempt("user", None, NOW + FORGET_AFTER_SECS), Ok(()));
+    assert_eq!(throttle.by_username.len(), 1);
+  }
+
+  #[test]
+  fn oldest_records_are_evicted_beyond_the_limit() {
+    let mut throttle = LoginThrottle::default();
+    for i in 0..MAX_RECORDS {
+      let now = if i < MAX_RECORDS / 2 { NOW } else { NOW + 1 };
+      assert_eq!(throttle.start_attempt(&format!("user{}", i), None, now), Ok(()));
+    }
+    assert_eq!(throttle.start_attempt("user", None, NOW + 1), Ok(()));
+    assert_eq!(throttle.by_username.len(), RECORDS_AFTER_EVICTION + 1);
+    assert_eq!(throttle.by_username.values().filter(|f| f.last_failure == NOW + 1).count(), MAX_RECORDS / 2 + 1);
+  }
+}
@@ -14,18 +14,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod auth;
 mod responders;
 mod dist_handlers;
+pub mod background;
+pub mod login_throttle;
 pub mod routes;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use rocket::{Rocket, Build};
+use rocket::{Rocket, Build, Orbit};
+use rocket::data::{Limits, ToByteUnit};
 use rocket::fairing::AdHoc;
 use clap::{App, ArgMatches, Arg};
 use crate::storage::cache::FileCache;
 use crate::storage::file::FileStore;
 use crate::storage::db::Db;
+use crate::storage::db::kv_store::KVStoreConfig;
 use crate::config::setup_config;
+use crate::web::auth::SessionCookieSettings;
+use crate::web::login_throttle::LoginThrottle;
+use crate::web::routes::account::ClientIpHeader;
+use crate::web::routes::admin::AdminToken;
 
 
 pub fn make_clap_subcommand<'a, 'b>() -> App<'a> {
@@ -48,22 +57,42 @@ pub async fn execute<'a>(arg_matches: &ArgMatches) {
   };
 
   let db_dir = config.get_string("db_dir").unwrap();
+  let kv_store_config = match KVStoreConfig::from_config(&config) {
+    Ok(c) => c,
+    Err(e) => { println!("Could not setup configuration {e}"); return; }
+  };
+  let file_store_kv_store_config = kv_store_config.clone();
   let init_db = |rocket: Rocket<Build>| async move {
-    rocket.manage(Mutex::new(
-      match Db::new(&db_dir) {
-        Ok(db) => db,
-        Err(e) => {
-          println!("Failed to initialize db: {}", e);
-          panic!();
-        }
-      }))
+    let db = match Db::new(&db_dir, &kv_store_config) {
+      Ok(db) => db,
+      Err(e) => {
+        println!("Failed to initialize db: {}", e);
+        panic!();
+      }
+    };
+    // Created only once the db directory lock is held, so a second instance can't clobber the token.
+    let admin_token = match AdminToken::create(&db_dir) {
+      Ok(t) => t,
+      Err(e) => {
+        println!("Failed to create admin token: {}", e);
+        panic!();
+      }
+    };
+    rocket.manage(Arc::new(Mutex::new(db))).manage(admin_token)
   };
 
-  let files_dir = config.get_string("files_dir").unwrap();
-  let init_file_store = |rocket: Rocket<Build>| async move {
-    rocket.manage(Mutex::new(
-      match FileStore::new(&files_dir) {
-        Ok(file_store) => file_store,
+  let file_store_config = config.clone();
+  let init_file_store = move |rocket: Rocket<Build>| async move {
+    // FileStore synchronizes access to its state itself, so is shared without a lock.
+    rocket.manage(Arc::new(
+      match FileStore::from_config(&file_store_config, &file_store_kv_store_config).await {
+        Ok(file_store) => {
+          if let Some(stats) = file_store.dedup_stats().await {
+            info!("Content addressed file store holds the data of {} items in {} blobs, saving {} bytes.",
+                  stats.item_count, stats.blob_count, stats.saved_bytes());
+          }
+          file_store
+        },
         Err(e) => {
           println!("Failed to initialize file store: {}", e);
           panic!();
@@ -72,9 +101,15 @@ pub async fn execute<'a>(arg_matches: &ArgMatches) {
   };
 
   let cache_dir = config.get_string("cache_dir").unwrap();
-  let init_cache = |rocket: Rocket<Build>| async move {
-    rocket.manage(Mutex::new(
-      match FileCache::new(&cache_dir) {
+  let cache_max_bytes = config.get_int("cache_max_bytes").unwrap();
+  if cache_max_bytes <= 0 {
+    println!("'cache_max_bytes' setting must be positive, not {}.", cache_max_bytes);
+    return;
+  }
+  let init_cache = move |rocket: Rocket<Build>| async move {
+    // FileCache synchronizes access to its index itself, so is shared without a lock.
+    rocket.manage(Arc::new(
+      match FileCache::new(&cache_dir, cache_max_bytes as u64) {
         Ok(file_cache) => file_cache,
         Err(e) => {
           println!("Failed to initialize file cache: {}", e);
@@ -83,15 +118,70 @@ pub async fn execute<'a>(arg_matches: &ArgMatches) {
       }))
   };
 
+  let trash_retention_days = config.get_int("trash_retention_days").unwrap();
+  let files_gc_interval_hours = config.get_int("files_gc_interval_hours").unwrap();
+  let start_background_tasks = move |rocket: &Rocket<Orbit>| {
+    let db = rocket.state::<Arc<Mutex<Db>>>().unwrap().clone();
+    let file_store = rocket.state::<Arc<FileStore>>().unwrap().clone();
+    let file_cache = rocket.state::<Arc<FileCache>>().unwrap().clone();
+    if trash_retention_days > 0 {
+      background::start_trash_purge(db.clone(), file_store.clone(), trash_retention_days);
+    }
+    if files_gc_interval_hours > 0 {
+      background::start_gc(db, file_store, files_gc_interval_hours);
+    }
+    background::start_cache_eviction(file_cache);
+  };
+
+  let max_upload_size_mb = config.get_int("max_upload_size_mb").unwrap();
+  if max_upload_size_mb <= 0 {
+    println!("'max_upload_size_mb' setting must be positive, not {}.", max_upload_size_mb);
+    return;
+  }
+  let cookie_settings = SessionCookieSettings { secure: config.get_bool("secure_cookies").unwrap() };
+  let client_ip_header = ClientIpHeader(Some(config.get_string("client_ip_header").unwrap()).filter(|header| !header.is_empty()));
+
+  // Multipart form fields other than the file itself are small, so 1MiB is ample for them.
+  let limits = Limits::default()
+    .limit("file", (max_upload_size_mb as u64).mebibytes())
+    .limit("data-form", (max_upload_size_mb as u64 + 1).mebibytes());
+
   _ = dist_handlers::mount(
-    rocket::build()
+    rocket::custom(rocket::Config::figment().merge(("limits", limits)))
       .mount("/", routes![
         routes::files::get,
+        routes::files::upload,
         routes::account::login,
         routes::account::logout,
+        routes::account::logout_others,
+        routes::account::list_sessions,
+        routes::account::revoke_session,
+        routes::account::totp_enrol,
+        routes::account::totp_confirm,
+        routes::account::create_api_token,
+        routes::account::list_api_tokens,
+        routes::account::revoke_api_token,
         routes::command::command,
+        routes::admin::add_user,
+        routes::admin::reset_totp,
+        routes::admin::create_api_token,
+        routes::admin::list_api_tokens,
+        routes::admin::revoke_api_token,
+        routes::admin::compact,
+        routes::admin::check,
+        routes::admin::purge_trash,
+        routes::admin::gc,
+        routes::admin::verify_files,
+        routes::admin::cache_stats,
+        routes::admin::clear_cache,
+        routes::admin::login_failures,
+        routes::admin::clear_login_failures,
       ])
+      .manage(Arc::new(Mutex::new(LoginThrottle::default())))
+      .manage(cookie_settings)
+      .manage(client_ip_header)
       .attach(AdHoc::on_ignite("Initialize Db", init_db)))
       .attach(AdHoc::on_ignite("Initialize Cache", init_cache))
-      .attach(AdHoc::on_ignite("Initialize File Store", init_file_store)).launch().await;
+      .attach(AdHoc::on_ignite("Initialize File Store", init_file_store))
+      .attach(AdHoc::on_liftoff("Start Background Tasks", move |rocket| Box::pin(async move { start_background_tasks(rocket) }))).launch().await;
 }
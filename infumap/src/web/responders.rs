@@ -14,11 +14,19 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
 use rocket::response::{self, Responder};
 use rocket::request::Request;
+use rocket::tokio;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, ReadBuf};
 use rocket::Response;
-use std::io::Cursor;
+use std::fs::File;
+use std::io::{self, Cursor, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use crate::storage::file::StoredFile;
+use crate::util::infu::InfuResult;
 
 
 
@@ -49,17 +57,173 @@ impl<'r> Responder<'r, 'static> for RawIcoImage<&'static [u8]> {
   }
 }
 
+/// Data that can be streamed from any offset.
+pub trait ReadRange: Send {
+  /// Stream `len` bytes of the data, starting at `start`.
+  fn read_range(self: Box<Self>, start: u64, len: u64) -> InfuResult<Pin<Box<dyn AsyncRead + Send>>>;
+}
+
+impl ReadRange for File {
+  fn read_range(mut self: Box<Self>, start: u64, len: u64) -> InfuResult<Pin<Box<dyn AsyncRead + Send>>> {
+    self.seek(SeekFrom::Start(start))?;
+    Ok(Box::pin(tokio::fs::File::from_std(*self).take(len)))
+  }
+}
+
+impl ReadRange for Cursor<Vec<u8>> {
+  fn read_range(mut self: Box<Self>, start: u64, len: u64) -> InfuResult<Pin<Box<dyn AsyncRead + Send>>> {
+    self.set_position(start);
+    Ok(Box::pin(self.take(len)))
+  }
+}
+
+impl ReadRange for StoredFile {
+  fn read_range(self: Box<Self>, start: u64, len: u64) -> InfuResult<Pin<Box<dyn AsyncRead + Send>>> {
+    Ok(self.reader(start, len))
+  }
+}
+
+/// The data of a file or image item. Conditional requests (If-None-Match, If-Modified-Since) and requests
+/// for a single byte range (Range, If-Range) are supported.
 pub struct FileResponse {
-  pub data: Vec<u8>,
+  pub data: Box<dyn ReadRange>,
+  pub size: u64,
   pub mime_type: ContentType,
+  pub etag: String,
+  pub last_modified: SystemTime,
 }
 
 impl<'r> Responder<'r, 'static> for FileResponse {
-  fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
-    Response::build()
-      .header(self.mime_type)
-      .sized_body(self.data.len(), Cursor::new(self.data))
-      .ok()
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    let last_modified = httpdate::fmt_http_date(self.last_modified);
+    let mut response = Response::build();
+    response
+      .raw_header("ETag", self.etag.clone())
+      .raw_header("Last-Modified", last_modified.clone())
+      .raw_header("Accept-Ranges", "bytes");
+
+    if self.is_not_modified(request) {
+      return response.status(Status::NotModified).ok();
+    }
+
+    let range = match request.headers().get_one("Range") {
+      Some(range) if self.is_if_range_satisfied(request, &last_modified) => parse_range(range, self.size),
+      _ => ByteRange::Full
+    };
+    match range {
+      ByteRange::Full => {
+        let body = self.data.read_range(0, self.size).map_err(|_| Status::InternalServerError)?;
+        response
+          .header(self.mime_type)
+          .sized_body(self.size as usize, SizedStream(body));
+      },
+      ByteRange::Partial { start, end } => {
+        let len = end - start + 1;
+        let body = self.data.read_range(start, len).map_err(|_| Status::InternalServerError)?;
+        response
+          .status(Status::PartialContent)
+          .header(self.mime_type)
+          .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, self.size))
+          .sized_body(len as usize, SizedStream(body));
+      },
+      ByteRange::Unsatisfiable => {
+        response
+          .status(Status::RangeNotSatisfiable)
+          .raw_header("Content-Range", format!("bytes */{}", self.size));
+      }
+    }
+    response.ok()
   }
 }
 
+/// A stream of known size. Rocket requires sized bodies to be seekable, but only seeks them to find their
+/// size if it isn't given - and a sized body is needed for HEAD requests to be answered with the size.
+struct SizedStream(Pin<Box<dyn AsyncRead + Send>>);
+
+impl AsyncRead for SizedStream {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    self.0.as_mut().poll_read(cx, buf)
+  }
+}
+
+impl AsyncSeek for SizedStream {
+  fn start_seek(self: Pin<&mut Self>, _position: SeekFrom) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Stream is not seekable."))
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    Poll::Ready(Ok(0))
+  }
+}
+
+impl FileResponse {
+  fn is_not_modified(&self, request: &Request<'_>) -> bool {
+    // If-Modified-Since is ignored if If-None-Match is present.
+    if let Some(if_none_match) = request.headers().get_one("If-None-Match") {
+      return if_none_match.split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag);
+    }
+    match request.headers().get_one("If-Modified-Since").and_then(|date| httpdate::parse_http_date(date).ok()) {
+      Some(since) => match self.last_modified.duration_since(since) {
+        // Last-Modified has whole second resolution.
+        Ok(newer_by) => newer_by.as_secs() == 0,
+        Err(_) => true
+      },
+      None => false
+    }
+  }
+
+  /// Whether a Range header should be honored. It is not if an If-Range header identifies a different
+  /// version of the data - then the whole of the current data is sent instead.
+  fn is_if_range_satisfied(&self, request: &Request<'_>, last_modified: &str) -> bool {
+    match request.headers().get_one("If-Range") {
+      None => true,
+      Some(if_range) => if_range == self.etag || if_range == last_modified
+    }
+  }
+}
+
+enum ByteRange {
+  Full,
+  Partial { start: u64, end: u64 },
+  Unsatisfiable,
+}
+
+/// Interpret a Range header for data of `size` bytes. Only single ranges in bytes are supported - the whole
+/// of the data is sent for anything else, which is allowed.
+fn parse_range(range: &str, size: u64) -> ByteRange {
+  let spec = match range.trim().strip_prefix("bytes=") {
+    Some(spec) if !spec.contains(',') => spec.trim(),
+    _ => return ByteRange::Full
+  };
+  let (first, last) = match spec.split_once('-') {
+    Some(bounds) => bounds,
+    None => return ByteRange::Full
+  };
+  if first.is_empty() {
+    // The final `last` bytes.
+    return match last.parse::<u64>() {
+      Ok(0) => ByteRange::Unsatisfiable,
+      Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+      Ok(suffix_len) => ByteRange::Partial { start: size.saturating_sub(suffix_len), end: size - 1 },
+      Err(_) => ByteRange::Full
+    };
+  }
+  let start = match first.parse::<u64>() {
+    Ok(start) => start,
+    Err(_) => return ByteRange::Full
+  };
+  let end = if last.is_empty() {
+    size.saturating_sub(1)
+  } else {
+    match last.parse::<u64>() {
+      Ok(end) if end >= start => end.min(size.saturating_sub(1)),
+      _ => return ByteRange::Full
+    }
+  };
+  if start >= size {
+    return ByteRange::Unsatisfiable;
+  }
+  ByteRange::Partial { start, end }
+}
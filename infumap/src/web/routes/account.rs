@@ -15,114 +15,567 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use rocket::State;
+use rocket::http::CookieJar;
+use rocket::request::{FromRequest, Outcome, Request};
 use rocket::serde::json::Json;
+use rocket::tokio::task::spawn_blocking;
 use serde::{Deserialize, Serialize};
-use uuid::{uuid, Uuid};
-use std::sync::Mutex;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
-use totp_rs::{Algorithm, TOTP};
 use crate::storage::db::Db;
-use crate::storage::db::user::User;
+use crate::storage::db::api_token::ApiToken;
+use crate::storage::db::session::Session;
+use crate::storage::db::user::{self, User};
+use crate::util::infu::InfuResult;
+use crate::web::auth::{remove_session_cookie, set_session_cookie, AuthenticatedSession, SessionCookieSettings};
+use crate::web::login_throttle::LoginThrottle;
 
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
     username: String,
     password: String,
+    /// A TOTP code, or one of the user's recovery codes. Required if they have two-factor authentication enabled.
+    totp: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct LoginResponse {
   success: bool,
+  /// Browsers are given the session in an HttpOnly cookie. Other clients authenticate subsequent requests by
+  /// passing this in an "Authorization: Bearer <session id>" header.
   #[serde(rename="sessionId")]
   session_id: Option<String>,
   #[serde(rename="userId")]
   user_id: Option<String>,
   #[serde(rename="rootPageId")]
   root_page_id: Option<String>,
+  /// Set if the password was correct, but the user has two-factor authentication enabled, and the TOTP code
+  /// was missing or incorrect.
+  #[serde(rename="totpRequired")]
+  totp_required: bool,
+  /// Set if there have been too many failed login attempts for the username, or from the client's address, in
+  /// which case further attempts are rejected without being checked for this many seconds.
+  #[serde(rename="retryAfterSecs")]
+  retry_after_secs: Option<i64>,
 }
 
+fn login_failed(totp_required: bool, retry_after_secs: Option<i64>) -> Json<LoginResponse> {
+  Json(LoginResponse { success: false, session_id: None, user_id: None, root_page_id: None, totp_required, retry_after_secs })
+}
+
+/// The longest User-Agent header that is recorded with a session. Any more is cut off.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Request guard for the User-Agent header of a request, if it has one.
+pub struct UserAgent(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let user_agent = request.headers().get_one("User-Agent")
+      .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect::<String>());
+    Outcome::Success(UserAgent(user_agent))
+  }
+}
+
+/// The header that a reverse proxy in front of Infumap sets to the address of the client, from the
+/// client_ip_header setting, if there is one.
+pub struct ClientIpHeader(pub Option<String>);
+
+/// Request guard for the IP address of the client, if it is known. This is the address the request was received
+/// from, unless the client_ip_header setting is set - a header can't be trusted otherwise, since clients can set
+/// it to anything.
+pub struct ClientIp(Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let header = request.rocket().state::<ClientIpHeader>().and_then(|header| header.0.as_deref());
+    let ip = match header {
+      Some(header) => request.headers().get_one(header).and_then(|ip| match ip.trim().parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => { warn!("The '{}' header of a request to '{}' is not an IP address: {}", header, request.uri(), ip); None }
+      }),
+      None => request.remote().map(|remote| remote.ip())
+    };
+    Outcome::Success(ClientIp(ip))
+  }
+}
+
+/// Log in with a username and password, and a TOTP code if the user has two-factor authentication enabled.
+/// Failed attempts are tracked by username and client address, and once there have been too many, further
+/// attempts are rejected for a while (see LoginThrottle). The response to a failed attempt is the same whether or
+/// not the username exists.
 #[post("/account/login", data = "<request>")]
-pub fn login(db: &State<Mutex<Db>>, request: Json<LoginRequest>) -> Json<LoginResponse> {
-  let mut db = db.lock().unwrap();
+pub async fn login(
+    db: &State<Arc<Mutex<Db>>>, login_throttle: &State<Arc<Mutex<LoginThrottle>>>,
+    cookies: &CookieJar<'_>, cookie_settings: &State<SessionCookieSettings>,
+    user_agent: UserAgent, ip: ClientIp, request: Json<LoginRequest>) -> Json<LoginResponse> {
+  let ip = ip.0;
+  let from = ip.map(|ip| ip.to_string()).unwrap_or(String::from("an unknown address"));
+  let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+  if let Err(retry_after_secs) = login_throttle.lock().unwrap().start_attempt(&request.username, ip, now) {
+    info!("A login attempt from {} was rejected without being checked, due to too many recent failed attempts.", from);
+    return login_failed(false, Some(retry_after_secs));
+  }
+
+  let user = db.lock().unwrap().user.get_by_username(&request.username).cloned();
+
+  // Password hashing is slow by design, so is done on a blocking thread, without holding the db lock. If the user
+  // does not exist, a password is checked anyway, so that this takes as long as if they did.
+  let password = request.password.clone();
+  let verified = spawn_blocking(move || {
+    let verified = match &user {
+      Some(user) => user.verify_password(&password),
+      None => { user::verify_password_of_no_user(&password); false }
+    };
+    (user, verified)
+  }).await;
+  let user = match verified {
+    Ok((Some(user), true)) => user,
+    Ok((Some(user), false)) => {
+      info!("A login attempt for user '{}' from {} failed due to incorrect password.", user.id, from);
+      return failed_login(login_throttle, &request.username, ip, now, false);
+    },
+    Ok((None, _)) => {
+      info!("A login attempt from {} failed due to an unknown username.", from);
+      return failed_login(login_throttle, &request.username, ip, now, false);
+    },
+    Err(e) => {
+      error!("Could not check the password of user '{}': {}", request.username, e);
+      login_throttle.lock().unwrap().release(&request.username, ip);
+      return login_failed(false, None);
+    }
+  };
+
+  if user.totp_secret.is_some() {
+    let second_factor = check_second_factor(&mut db.lock().unwrap(), &user, request.totp.as_deref(), now);
+    match second_factor {
+      Ok(true) => {},
+      Ok(false) if request.totp.as_deref().map(str::trim).unwrap_or("").is_empty() => {
+        // The client is expected to ask for a code and try again.
+        login_throttle.lock().unwrap().release(&request.username, ip);
+        return login_failed(true, None);
+      },
+      Ok(false) => {
+        info!("A login attempt for user '{}' from {} failed due to an incorrect TOTP code.", user.id, from);
+        return failed_login(login_throttle, &request.username, ip, now, true);
+      },
+      Err(e) => {
+        error!("Could not check the TOTP code of user '{}': {}", request.username, e);
+        login_throttle.lock().unwrap().release(&request.username, ip);
+        return login_failed(true, None);
+      }
+    }
+  }
+  login_throttle.lock().unwrap().record_success(&request.username, ip);
 
-  let user = match db.user.get_by_username(&request.username) {
-    Some(user) => user,
-    None => {
-      info!("Login was attempted for a user that does not exist '{}'.", request.username);
-      return Json(LoginResponse { success: false, session_id: None, user_id: None, root_page_id: None })
+  let new_password_hash = if user.password_needs_rehash() {
+    let password = request.password.clone();
+    match spawn_blocking(move || User::compute_password_hash(&password)).await {
+      Ok(Ok(hash)) => Some(hash),
+      Ok(Err(e)) => { warn!("Could not rehash the password of user '{}': {}", request.username, e); None },
+      Err(e) => { warn!("Could not rehash the password of user '{}': {}", request.username, e); None }
     }
-  }.clone();
+  } else {
+    None
+  };
 
-  let test_hash = User::compute_password_hash(&user.password_salt, &request.password);
-  if test_hash != user.password_hash {
-    info!("A login attempt for user '{}' failed due to incorrect password.", request.username);
-    return Json(LoginResponse { success: false, session_id: None, user_id: None, root_page_id: None });
+  let mut db = db.lock().unwrap();
+  if let Some(password_hash) = new_password_hash {
+    if let Err(e) = rehash_password(&mut db, &user.id, password_hash) {
+      warn!("Could not update the password hash of user '{}': {}", request.username, e);
+    }
   }
 
-  match db.session.create_session(&user.id, &request.username, &request.password) {
-    Ok(session) => {
+  match db.session.create_session(&user.id, &request.username, user_agent.0.as_deref()) {
+    Ok((session, session_id)) => {
+      set_session_cookie(cookies, cookie_settings, &session, &session_id);
       let result = LoginResponse {
         success: true,
-        session_id: Some(session.id),
+        session_id: Some(session_id),
         user_id: Some(user.id),
-        root_page_id: Some(user.root_page_id.clone())
+        root_page_id: Some(user.root_page_id.clone()),
+        totp_required: false,
+        retry_after_secs: None
       };
       Json(result)
     },
     Err(e) => {
       error!("Failed to create session for user '{}': {}.", request.username, e);
-      Json(LoginResponse { success: false, session_id: None, user_id: None, root_page_id: None })
+      login_failed(false, None)
     }
   }
 }
 
 
-#[derive(Deserialize)]
-pub struct LogoutRequest {
-  #[serde(rename="userId")]
-  _user_id: String,
-  #[serde(rename="sessionId")]
-  _session_id: String,
+/// The response to a failed login attempt, which `LoginThrottle::start_attempt` has already recorded.
+fn failed_login(login_throttle: &Mutex<LoginThrottle>, username: &str, ip: Option<IpAddr>, now: i64, totp_required: bool) -> Json<LoginResponse> {
+  login_failed(totp_required, login_throttle.lock().unwrap().retry_after(username, ip, now))
 }
 
+
+/// Whether `code` is a current TOTP code of `user` that they have not used before, or one of their unused recovery
+/// codes - which is then used up.
+fn check_second_factor(db: &mut Db, user: &User, code: Option<&str>, now: i64) -> InfuResult<bool> {
+  let code = match code.map(|c| c.trim()) {
+    Some(code) if !code.is_empty() => code,
+    _ => return Ok(false)
+  };
+  if db.use_totp_code(&user.id, code, now as u64)? {
+    return Ok(true);
+  }
+  if db.use_totp_recovery_code(&user.id, code)? {
+    let remaining = db.user.get_by_id(&user.id).map(|u| u.totp_recovery_codes.len()).unwrap_or(0);
+    info!("User '{}' logged in with a recovery code. {} remain.", user.username, remaining);
+    return Ok(true);
+  }
+  Ok(false)
+}
+
+
+/// Replace the password hash of user `user_id` with `password_hash`, computed from the same password.
+fn rehash_password(db: &mut Db, user_id: &str, password_hash: String) -> InfuResult<()> {
+  let mut user = db.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?.clone();
+  user.password_hash = password_hash;
+  user.password_salt = String::new();
+  db.user.update(user)?;
+  info!("Upgraded the password hash of user '{}'.", user_id);
+  Ok(())
+}
+
+
 #[derive(Serialize)]
 pub struct LogoutResponse {
   success: bool,
 }
 
-#[post("/account/logout", data = "<_payload>")]
-pub fn logout(_db: &State<Mutex<Db>>, _payload: Json<LogoutRequest>) -> Json<LogoutResponse> {
-  let result = LogoutResponse { success: false };
-
-  Json(result)
-}
-
-
-#[get("/gen")]
-fn _gen() -> String {
-  // TODO (HIGH): remove. playing with OTP / uuids.
-
-  const ID: Uuid = uuid!("3d14c109-9934-4717-aef0-be64a95a8550");
-  // let key_uuid = uuid::Uuid::new_v4();
-  let a = ID.to_string().replace("-", "");
-  println!("{}", a);
-
-  // The secret should be randomly generated of N bits length (look it up)
-  let totp = TOTP::new(
-    Algorithm::SHA1,
-    6,
-    1,
-    30,
-    "hello123123123123123123123123123".as_bytes(),
-    Some("infumap".to_string()),
-    "math".to_string()
-  ).unwrap();
-  let time = SystemTime::now()
-    .duration_since(SystemTime::UNIX_EPOCH).unwrap()
-    .as_secs();
-  println!("{}", totp.get_url());
-  let token = totp.generate(time);
-  println!("{}", token);
-  "hello".to_string()
+/// End the session, and remove the session cookie. This succeeds if the session has already ended.
+#[post("/account/logout")]
+pub fn logout(auth: Option<AuthenticatedSession>, db: &State<Arc<Mutex<Db>>>, cookies: &CookieJar<'_>) -> Json<LogoutResponse> {
+  remove_session_cookie(cookies);
+  let session = match auth {
+    Some(auth) => auth.session,
+    None => return Json(LogoutResponse { success: true })
+  };
+  match db.lock().unwrap().session.delete_session(&session.id) {
+    Ok(()) => Json(LogoutResponse { success: true }),
+    Err(e) => {
+      error!("Failed to end session '{}' of user '{}': {}", session.id, session.user_id, e);
+      Json(LogoutResponse { success: false })
+    }
+  }
+}
+
+
+#[derive(Serialize)]
+pub struct LogoutOthersResponse {
+  success: bool,
+  #[serde(rename="loggedOutCount")]
+  logged_out_count: usize,
+}
+
+/// End all sessions of the user other than the one the request is made with.
+#[post("/account/logout-others")]
+pub fn logout_others(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>) -> Json<LogoutOthersResponse> {
+  let session = auth.session;
+  match db.lock().unwrap().session.delete_other_sessions(&session.user_id, &session.id) {
+    Ok(logged_out_count) => {
+      info!("Ended {} other sessions of user '{}'.", logged_out_count, session.user_id);
+      Json(LogoutOthersResponse { success: true, logged_out_count })
+    },
+    Err(e) => {
+      error!("Failed to end the other sessions of user '{}': {}", session.user_id, e);
+      Json(LogoutOthersResponse { success: false, logged_out_count: 0 })
+    }
+  }
+}
+
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+  /// Identifies the session to `/account/revoke-session`. This is not the session id.
+  id: String,
+  created: i64,
+  #[serde(rename="lastSeen")]
+  last_seen: i64,
+  expires: i64,
+  #[serde(rename="userAgent")]
+  user_agent: Option<String>,
+  /// Whether this is the session the request was made with.
+  current: bool,
+}
+
+#[derive(Serialize)]
+pub struct ListSessionsResponse {
+  success: bool,
+  sessions: Vec<SessionInfo>,
+}
+
+/// The active sessions of the user, most recently used first, so that they can see which devices are logged in.
+#[post("/account/sessions")]
+pub fn list_sessions(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>) -> Json<ListSessionsResponse> {
+  let current = auth.session;
+  match db.lock().unwrap().session.get_user_sessions(&current.user_id) {
+    Ok(mut sessions) => {
+      sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+      let sessions = sessions.iter().map(|session| SessionInfo {
+        id: session.public_id(),
+        created: session.created,
+        last_seen: session.last_seen,
+        expires: session.expires,
+        user_agent: session.user_agent.clone(),
+        current: session.id == current.id
+      }).collect();
+      Json(ListSessionsResponse { success: true, sessions })
+    },
+    Err(e) => {
+      error!("Failed to list the sessions of user '{}': {}", current.user_id, e);
+      Json(ListSessionsResponse { success: false, sessions: vec![] })
+    }
+  }
+}
+
+
+#[derive(Deserialize)]
+pub struct RevokeSessionRequest {
+  /// The id of the session to end, as given by `/account/sessions`.
+  #[serde(rename="revokeId")]
+  revoke_id: String,
+}
+
+#[derive(Serialize)]
+pub struct RevokeSessionResponse {
+  success: bool,
+}
+
+/// End one of the user's sessions, which may be the one the request is made with.
+#[post("/account/revoke-session", data = "<request>")]
+pub fn revoke_session(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>, request: Json<RevokeSessionRequest>) -> Json<RevokeSessionResponse> {
+  let user_id = auth.session.user_id;
+  let mut db = db.lock().unwrap();
+  let result = db.session.get_user_sessions(&user_id).and_then(|sessions| {
+    match sessions.iter().find(|session| session.public_id() == request.revoke_id) {
+      Some(session) => db.session.delete_session(&session.id).map(|_| true),
+      None => Ok(false)
+    }
+  });
+  match result {
+    Ok(true) => {
+      info!("Revoked a session of user '{}'.", user_id);
+      Json(RevokeSessionResponse { success: true })
+    },
+    Ok(false) => {
+      info!("User '{}' has no session '{}' to revoke.", user_id, request.revoke_id);
+      Json(RevokeSessionResponse { success: false })
+    },
+    Err(e) => {
+      error!("Failed to revoke session '{}' of user '{}': {}", request.revoke_id, user_id, e);
+      Json(RevokeSessionResponse { success: false })
+    }
+  }
+}
+
+
+#[derive(Serialize)]
+pub struct TotpEnrolResponse {
+  success: bool,
+  /// An otpauth:// URL for the new secret, which authenticator apps can import.
+  url: Option<String>,
+  /// The same URL as a QR code: a base64 encoded PNG image.
+  qr: Option<String>,
+}
+
+/// Start enabling two-factor authentication. This issues a new TOTP secret, which is not used until a code
+/// generated from it is given to `/account/totp/confirm`, using the same session.
+#[post("/account/totp/enrol")]
+pub fn totp_enrol(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>) -> Json<TotpEnrolResponse> {
+  let session = auth.session;
+  match start_totp_enrolment(&mut db.lock().unwrap(), &session) {
+    Ok((url, qr)) => Json(TotpEnrolResponse { success: true, url: Some(url), qr: Some(qr) }),
+    Err(e) => {
+      warn!("Could not start two-factor authentication enrolment for user '{}': {}", session.username, e);
+      Json(TotpEnrolResponse { success: false, url: None, qr: None })
+    }
+  }
+}
+
+/// Returns the otpauth URL and QR code of the new secret.
+fn start_totp_enrolment(db: &mut Db, session: &Session) -> InfuResult<(String, String)> {
+  if !db.has_server_key() {
+    return Err("Two-factor authentication can't be enabled unless the encryption_key setting is set.".into());
+  }
+  let user = db.user.get_by_id(&session.user_id).ok_or(format!("Unknown user '{}'.", session.user_id))?;
+  if user.totp_secret.is_some() {
+    return Err("Two-factor authentication is already enabled. It can be reset with 'infumap reset-totp'.".into());
+  }
+  let secret = user::generate_totp_secret();
+  let totp = user::totp(secret.clone(), &user.username)?;
+  let qr = totp.get_qr().map_err(|e| format!("Could not create QR code: {}", e))?;
+  db.session.set_pending_totp_secret(&session.id, Some(secret))?;
+  Ok((totp.get_url(), qr))
+}
+
+
+#[derive(Deserialize)]
+pub struct TotpConfirmRequest {
+  totp: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpConfirmResponse {
+  success: bool,
+  /// Single use codes that can be given in place of a TOTP code. These are only ever returned here.
+  #[serde(rename="recoveryCodes")]
+  recovery_codes: Option<Vec<String>>,
+}
+
+/// Finish enabling two-factor authentication, given a code generated from the secret issued by `/account/totp/enrol`.
+#[post("/account/totp/confirm", data = "<request>")]
+pub fn totp_confirm(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>, request: Json<TotpConfirmRequest>) -> Json<TotpConfirmResponse> {
+  let session = auth.session;
+  match confirm_totp_enrolment(&mut db.lock().unwrap(), &session, request.totp.trim()) {
+    Ok(Some(recovery_codes)) => {
+      info!("Enabled two-factor authentication for user '{}'.", session.username);
+      Json(TotpConfirmResponse { success: true, recovery_codes: Some(recovery_codes) })
+    },
+    Ok(None) => {
+      info!("Two-factor authentication was not enabled for user '{}', because the TOTP code was incorrect.", session.username);
+      Json(TotpConfirmResponse { success: false, recovery_codes: None })
+    },
+    Err(e) => {
+      warn!("Could not enable two-factor authentication for user '{}': {}", session.username, e);
+      Json(TotpConfirmResponse { success: false, recovery_codes: None })
+    }
+  }
+}
+
+/// Returns the new recovery codes, or None if `code` is incorrect.
+fn confirm_totp_enrolment(db: &mut Db, session: &Session, code: &str) -> InfuResult<Option<Vec<String>>> {
+  let secret = db.session.pending_totp_secret(&session.id)
+    .ok_or("Enrolment has not been started with this session.")?;
+  let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|e| e.to_string())?.as_secs();
+  let step = match user::totp_step(&user::totp(secret.clone(), &session.username)?, code, now) {
+    Some(step) => step,
+    None => return Ok(None)
+  };
+  let recovery_codes = db.enable_totp(&session.user_id, &secret, step)?;
+  db.session.set_pending_totp_secret(&session.id, None)?;
+  Ok(Some(recovery_codes))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct ApiTokenInfo {
+  pub id: String,
+  pub name: String,
+  pub created: i64,
+  #[serde(rename="lastUsed")]
+  pub last_used: Option<i64>,
+  #[serde(rename="readOnly")]
+  pub read_only: bool,
+  #[serde(rename="scopeItemId")]
+  pub scope_item_id: Option<String>,
+}
+
+impl ApiTokenInfo {
+  pub fn from(api_token: &ApiToken) -> ApiTokenInfo {
+    ApiTokenInfo {
+      id: api_token.id.clone(),
+      name: api_token.name.clone(),
+      created: api_token.created,
+      last_used: api_token.last_used,
+      read_only: api_token.read_only,
+      scope_item_id: api_token.scope_item_id.clone()
+    }
+  }
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+  name: String,
+  #[serde(rename="readOnly")]
+  read_only: Option<bool>,
+  /// If given, the token can only be used to access this item and the items under it.
+  #[serde(rename="scopeItemId")]
+  scope_item_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreateApiTokenResponse {
+  success: bool,
+  /// The token string, to be sent as "Authorization: Bearer <token>". It is only ever returned here.
+  token: Option<String>,
+  #[serde(rename="apiToken")]
+  api_token: Option<ApiTokenInfo>,
+}
+
+/// Create an API token, for scripts and integrations to use in place of a session. It lasts until revoked.
+#[post("/account/api-tokens/create", data = "<request>")]
+pub fn create_api_token(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>, request: Json<CreateApiTokenRequest>) -> Json<CreateApiTokenResponse> {
+  let user_id = auth.session.user_id;
+  let result = db.lock().unwrap().create_api_token(
+    &user_id, &request.name, request.read_only.unwrap_or(false), request.scope_item_id.as_deref());
+  match result {
+    Ok((api_token, token)) => {
+      info!("Created API token '{}' for user '{}'.", api_token.id, user_id);
+      Json(CreateApiTokenResponse { success: true, token: Some(token), api_token: Some(ApiTokenInfo::from(&api_token)) })
+    },
+    Err(e) => {
+      warn!("Could not create an API token for user '{}': {}", user_id, e);
+      Json(CreateApiTokenResponse { success: false, token: None, api_token: None })
+    }
+  }
+}
+
+
+#[derive(Serialize)]
+pub struct ListApiTokensResponse {
+  success: bool,
+  #[serde(rename="apiTokens")]
+  api_tokens: Vec<ApiTokenInfo>,
+}
+
+/// The API tokens of the user, most recently created first. The token strings themselves are not available.
+#[post("/account/api-tokens")]
+pub fn list_api_tokens(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>) -> Json<ListApiTokensResponse> {
+  let api_tokens = db.lock().unwrap().api_token.get_user_tokens(&auth.session.user_id);
+  Json(ListApiTokensResponse { success: true, api_tokens: api_tokens.iter().map(ApiTokenInfo::from).collect() })
+}
+
+
+#[derive(Deserialize)]
+pub struct RevokeApiTokenRequest {
+  id: String,
+}
+
+#[derive(Serialize)]
+pub struct RevokeApiTokenResponse {
+  success: bool,
+}
+
+/// Revoke one of the user's API tokens. Requests made with it fail from then on.
+#[post("/account/api-tokens/revoke", data = "<request>")]
+pub fn revoke_api_token(auth: AuthenticatedSession, db: &State<Arc<Mutex<Db>>>, request: Json<RevokeApiTokenRequest>) -> Json<RevokeApiTokenResponse> {
+  let user_id = auth.session.user_id;
+  match db.lock().unwrap().api_token.revoke(&user_id, &request.id) {
+    Ok(true) => {
+      info!("Revoked API token '{}' of user '{}'.", request.id, user_id);
+      Json(RevokeApiTokenResponse { success: true })
+    },
+    Ok(false) => {
+      info!("User '{}' has no API token '{}' to revoke.", user_id, request.id);
+      Json(RevokeApiTokenResponse { success: false })
+    },
+    Err(e) => {
+      error!("Failed to revoke API token '{}' of user '{}': {}", request.id, user_id, e);
+      Json(RevokeApiTokenResponse { success: false })
+    }
+  }
 }
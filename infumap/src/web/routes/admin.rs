@@ -0,0 +1,406 @@
+// Copyright (C) 2023 Matt Howlett
+// This file is part of Infumap.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use crate::storage::cache::FileCache;
+use crate::storage::db::Db;
+use crate::storage::file::{FileStore, GcReport};
+use crate::util::fs::expand_tilde;
+use crate::util::infu::{InfuError, InfuResult};
+use crate::util::uid::new_uid;
+use crate::web::background;
+use crate::web::routes::account::ApiTokenInfo;
+use crate::web::login_throttle::LoginThrottle;
+
+
+pub const ADMIN_TOKEN_HEADER: &str = "X-Infumap-Admin-Token";
+const ADMIN_TOKEN_FILENAME: &str = "admin_token";
+
+/// The token that must accompany requests to the admin routes. These are used by CLI commands
+/// to make changes through a running server, which holds the db directory lock. A new token
+/// is written to the db directory on startup, so possessing it demonstrates the same access
+/// that would be required to modify the logs directly.
+pub struct AdminToken(String);
+
+impl AdminToken {
+  pub fn create(db_dir: &str) -> InfuResult<AdminToken> {
+    let token = new_uid();
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(0o600).open(admin_token_path(db_dir)?)?;
+    file.write_all(token.as_bytes())?;
+    Ok(AdminToken(token))
+  }
+
+  pub fn read(db_dir: &str) -> InfuResult<String> {
+    let path = admin_token_path(db_dir)?;
+    std::fs::read_to_string(&path)
+      .map_err(|e| format!("Could not read admin token from '{}' - is 'infumap web' running? {}", path.display(), e).into())
+  }
+}
+
+fn admin_token_path(db_dir: &str) -> InfuResult<PathBuf> {
+  let mut path = expand_tilde(db_dir).ok_or("Could not interpret path.")?;
+  path.push(ADMIN_TOKEN_FILENAME);
+  Ok(path)
+}
+
+
+/// Request guard for the admin routes.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let expected = match request.rocket().state::<AdminToken>() {
+      Some(t) => t,
+      None => { return Outcome::Failure((Status::InternalServerError, ())); }
+    };
+    match request.headers().get_one(ADMIN_TOKEN_HEADER) {
+      Some(token) if token == expected.0 => Outcome::Success(Admin),
+      _ => {
+        warn!("Admin request to '{}' was made without a valid admin token.", request.uri());
+        Outcome::Failure((Status::Unauthorized, ()))
+      }
+    }
+  }
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct AddUserRequest {
+  pub username: String,
+  pub password: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AddUserResponse {
+  #[serde(rename="userId")]
+  pub user_id: String,
+}
+
+#[post("/admin/add-user", data = "<request>")]
+pub fn add_user(_admin: Admin, db: &State<Arc<Mutex<Db>>>, request: Json<AddUserRequest>) -> Result<Json<AddUserResponse>, InfuError> {
+  let mut db = db.lock().unwrap();
+  let user = db.add_user(&request.username, &request.password)?;
+  info!("Added user '{}' via admin request.", request.username);
+  Ok(Json(AddUserResponse { user_id: user.id }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetTotpRequest {
+  pub username: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ResetTotpResponse {
+  #[serde(rename="wasEnabled")]
+  pub was_enabled: bool,
+}
+
+#[post("/admin/reset-totp", data = "<request>")]
+pub fn reset_totp(_admin: Admin, db: &State<Arc<Mutex<Db>>>, request: Json<ResetTotpRequest>) -> Result<Json<ResetTotpResponse>, InfuError> {
+  let mut db = db.lock().unwrap();
+  let was_enabled = db.reset_totp(&request.username)?;
+  if was_enabled {
+    info!("Reset two-factor authentication of user '{}' via admin request.", request.username);
+  }
+  Ok(Json(ResetTotpResponse { was_enabled }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateUserApiTokenRequest {
+  pub username: String,
+  pub name: String,
+  #[serde(rename="readOnly")]
+  pub read_only: bool,
+  #[serde(rename="scopeItemId")]
+  pub scope_item_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CreateUserApiTokenResponse {
+  pub token: String,
+  #[serde(rename="apiToken")]
+  pub api_token: ApiTokenInfo,
+}
+
+#[post("/admin/create-api-token", data = "<request>")]
+pub fn create_api_token(_admin: Admin, db: &State<Arc<Mutex<Db>>>, request: Json<CreateUserApiTokenRequest>) -> Result<Json<CreateUserApiTokenResponse>, InfuError> {
+  let response = handle_create_api_token(&mut db.lock().unwrap(), &request)?;
+  info!("Created API token '{}' for user '{}' via admin request.", response.api_token.id, request.username);
+  Ok(Json(response))
+}
+
+/// Also used by the api-token CLI command when there is no server to send the request to.
+pub fn handle_create_api_token(db: &mut Db, request: &CreateUserApiTokenRequest) -> InfuResult<CreateUserApiTokenResponse> {
+  let user_id = db.user.get_by_username(&request.username).ok_or(format!("Unknown user '{}'.", request.username))?.id.clone();
+  let (api_token, token) = db.create_api_token(&user_id, &request.name, request.read_only, request.scope_item_id.as_deref())?;
+  Ok(CreateUserApiTokenResponse { token, api_token: ApiTokenInfo::from(&api_token) })
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct ListUserApiTokensRequest {
+  pub username: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ListUserApiTokensResponse {
+  #[serde(rename="apiTokens")]
+  pub api_tokens: Vec<ApiTokenInfo>,
+}
+
+#[post("/admin/list-api-tokens", data = "<request>")]
+pub fn list_api_tokens(_admin: Admin, db: &State<Arc<Mutex<Db>>>, request: Json<ListUserApiTokensRequest>) -> Result<Json<ListUserApiTokensResponse>, InfuError> {
+  Ok(Json(handle_list_api_tokens(&db.lock().unwrap(), &request)?))
+}
+
+pub fn handle_list_api_tokens(db: &Db, request: &ListUserApiTokensRequest) -> InfuResult<ListUserApiTokensResponse> {
+  let user_id = db.user.get_by_username(&request.username).ok_or(format!("Unknown user '{}'.", request.username))?.id.clone();
+  Ok(ListUserApiTokensResponse { api_tokens: db.api_token.get_user_tokens(&user_id).iter().map(ApiTokenInfo::from).collect() })
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct RevokeUserApiTokenRequest {
+  pub username: String,
+  pub id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RevokeUserApiTokenResponse {
+  pub revoked: bool,
+}
+
+#[post("/admin/revoke-api-token", data = "<request>")]
+pub fn revoke_api_token(_admin: Admin, db: &State<Arc<Mutex<Db>>>, request: Json<RevokeUserApiTokenRequest>) -> Result<Json<RevokeUserApiTokenResponse>, InfuError> {
+  let response = handle_revoke_api_token(&mut db.lock().unwrap(), &request)?;
+  if response.revoked {
+    info!("Revoked API token '{}' of user '{}' via admin request.", request.id, request.username);
+  }
+  Ok(Json(response))
+}
+
+pub fn handle_revoke_api_token(db: &mut Db, request: &RevokeUserApiTokenRequest) -> InfuResult<RevokeUserApiTokenResponse> {
+  let user_id = db.user.get_by_username(&request.username).ok_or(format!("Unknown user '{}'.", request.username))?.id.clone();
+  Ok(RevokeUserApiTokenResponse { revoked: db.api_token.revoke(&user_id, &request.id)? })
+}
+
+
+#[post("/admin/compact")]
+pub fn compact(_admin: Admin, db: &State<Arc<Mutex<Db>>>) -> Result<(), InfuError> {
+  let mut db = db.lock().unwrap();
+  db.compact()
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct CheckResponse {
+  pub problems: Vec<String>,
+}
+
+#[post("/admin/check")]
+pub fn check(_admin: Admin, db: &State<Arc<Mutex<Db>>>) -> Result<Json<CheckResponse>, InfuError> {
+  let mut db = db.lock().unwrap();
+  Ok(Json(CheckResponse { problems: db.check()? }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct PurgeTrashRequest {
+  #[serde(rename="olderThanDays")]
+  pub older_than_days: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PurgeTrashResponse {
+  #[serde(rename="purgedCount")]
+  pub purged_count: usize,
+}
+
+#[post("/admin/purge-trash", data = "<request>")]
+pub async fn purge_trash(_admin: Admin, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>, request: Json<PurgeTrashRequest>) -> Result<Json<PurgeTrashResponse>, InfuError> {
+  let purged_count = background::purge_trash(db, file_store, request.older_than_days).await?;
+  info!("Purged {} items from the trash via admin request.", purged_count);
+  Ok(Json(PurgeTrashResponse { purged_count }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct GcRequest {
+  #[serde(rename="dryRun")]
+  pub dry_run: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GcResponse {
+  #[serde(rename="orphanedIds")]
+  pub orphaned_ids: Vec<String>,
+  #[serde(rename="unreferencedBlobCount")]
+  pub unreferenced_blob_count: usize,
+  #[serde(rename="reclaimableBytes")]
+  pub reclaimable_bytes: u64,
+  #[serde(rename="missingIds")]
+  pub missing_ids: Vec<String>,
+}
+
+impl From<GcReport> for GcResponse {
+  fn from(report: GcReport) -> GcResponse {
+    GcResponse {
+      orphaned_ids: report.orphaned_ids,
+      unreferenced_blob_count: report.unreferenced_blob_count,
+      reclaimable_bytes: report.reclaimable_bytes,
+      missing_ids: report.missing_ids,
+    }
+  }
+}
+
+#[post("/admin/gc", data = "<request>")]
+pub async fn gc(_admin: Admin, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>, request: Json<GcRequest>) -> Result<Json<GcResponse>, InfuError> {
+  let report = background::collect_garbage(db, file_store, request.dry_run).await?;
+  if !request.dry_run {
+    info!("Deleted the file data of {} items that no longer exist, and {} unreferenced blobs, via admin request.",
+          report.orphaned_ids.len(), report.unreferenced_blob_count);
+  }
+  Ok(Json(report.into()))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct VerifyFilesResponse {
+  #[serde(rename="checkedCount")]
+  pub checked_count: usize,
+  #[serde(rename="unhashedCount")]
+  pub unhashed_count: usize,
+  pub problems: Vec<String>,
+}
+
+#[post("/admin/verify-files")]
+pub async fn verify_files(_admin: Admin, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>) -> Result<Json<VerifyFilesResponse>, InfuError> {
+  let (checked_count, unhashed_count, problems) = background::verify_files(db, file_store).await?;
+  Ok(Json(VerifyFilesResponse { checked_count, unhashed_count, problems }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct CacheStatsResponse {
+  pub hits: u64,
+  pub misses: u64,
+  pub evictions: u64,
+  #[serde(rename="entryCount")]
+  pub entry_count: usize,
+  #[serde(rename="sizeBytes")]
+  pub size_bytes: u64,
+  #[serde(rename="maxBytes")]
+  pub max_bytes: u64,
+}
+
+/// Counters are since the server started.
+#[post("/admin/cache-stats")]
+pub fn cache_stats(_admin: Admin, file_cache: &State<Arc<FileCache>>) -> Json<CacheStatsResponse> {
+  let stats = file_cache.stats();
+  Json(CacheStatsResponse {
+    hits: stats.hits,
+    misses: stats.misses,
+    evictions: stats.evictions,
+    entry_count: stats.entry_count,
+    size_bytes: stats.size_bytes,
+    max_bytes: stats.max_bytes,
+  })
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct ClearCacheResponse {
+  #[serde(rename="removedCount")]
+  pub removed_count: usize,
+}
+
+#[post("/admin/clear-cache")]
+pub fn clear_cache(_admin: Admin, file_cache: &State<Arc<FileCache>>) -> Result<Json<ClearCacheResponse>, InfuError> {
+  let removed_count = file_cache.clear()?;
+  info!("Cleared {} files from the file cache via admin request.", removed_count);
+  Ok(Json(ClearCacheResponse { removed_count }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct LoginFailuresEntry {
+  /// A username, or a client IP address.
+  pub key: String,
+  #[serde(rename="isIp")]
+  pub is_ip: bool,
+  pub count: u32,
+  #[serde(rename="lastFailure")]
+  pub last_failure: i64,
+  #[serde(rename="blockedUntil")]
+  pub blocked_until: i64,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct LoginFailuresResponse {
+  pub now: i64,
+  pub entries: Vec<LoginFailuresEntry>,
+}
+
+/// The failed logins of each username and IP address with any recently, since the server started.
+#[post("/admin/login-failures")]
+pub fn login_failures(_admin: Admin, login_throttle: &State<Arc<Mutex<LoginThrottle>>>) -> Result<Json<LoginFailuresResponse>, InfuError> {
+  let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+  let login_throttle = login_throttle.lock().unwrap();
+  let by_username = login_throttle.get_by_username(now).into_iter().map(|(username, f)| (username, false, f));
+  let by_ip = login_throttle.get_by_ip(now).into_iter().map(|(ip, f)| (ip.to_string(), true, f));
+  let mut entries = by_username.chain(by_ip)
+    .map(|(key, is_ip, f)| LoginFailuresEntry { key, is_ip, count: f.count, last_failure: f.last_failure, blocked_until: f.blocked_until })
+    .collect::<Vec<LoginFailuresEntry>>();
+  entries.sort_by_key(|entry| std::cmp::Reverse(entry.last_failure));
+  Ok(Json(LoginFailuresResponse { now, entries }))
+}
+
+
+#[derive(Deserialize, Serialize)]
+pub struct ClearLoginFailuresRequest {
+  /// A username, or a client IP address.
+  pub key: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ClearLoginFailuresResponse {
+  pub cleared: bool,
+}
+
+#[post("/admin/clear-login-failures", data = "<request>")]
+pub fn clear_login_failures(_admin: Admin, login_throttle: &State<Arc<Mutex<LoginThrottle>>>, request: Json<ClearLoginFailuresRequest>) -> Json<ClearLoginFailuresResponse> {
+  let cleared = login_throttle.lock().unwrap().clear(&request.key);
+  if cleared {
+    info!("Cleared the failed logins of '{}' via admin request.", request.key);
+  }
+  Json(ClearLoginFailuresResponse { cleared })
+}
@@ -14,23 +14,22 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 use log::{error, warn};
 use rocket::{State, serde::json::Json};
 use serde::{Deserialize, Serialize};
 use crate::storage::db::Db;
-use crate::storage::db::item::Item;
+use crate::storage::db::item::{Item, RelationshipToParent};
+use crate::storage::file::FileStore;
 use crate::util::infu::InfuResult;
+use crate::util::json;
+use crate::web::auth::Authenticated;
 use super::WebApiJsonSerializable;
 
 
 
 #[derive(Deserialize)]
 pub struct SendRequest {
-  #[serde(rename="userId")]
-  user_id: String,
-  #[serde(rename="sessionId")]
-  session_id: String,
   command: String,
   #[serde(rename="jsonData")]
   json_data: String,
@@ -44,48 +43,35 @@ pub struct SendResponse {
 }
 
 #[post("/command", data = "<request>")]
-pub fn command(db: &State<Mutex<Db>>, request: Json<SendRequest>) -> Json<SendResponse> {
+pub fn command(auth: Authenticated, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>, request: Json<SendRequest>) -> Json<SendResponse> {
   let mut db = db.lock().unwrap();
 
-  // validate session
-  let session = match
-      match db.session.get_session(&request.session_id) {
-        Ok(s) => s,
-        Err(e) => {
-          error!("An error occurred retrieving session '{}' for user '{}': {}.", request.session_id, request.user_id, e);
-          return Json(SendResponse { success: false, json_data: None });
-        }
-      } {
-    Some(s) => s,
-    None => {
-      info!("Session '{}' for user '{}' is not availble. It may have expired.", request.session_id, request.user_id);
-      return Json(SendResponse { success: false, json_data: None });
-    }
-  };
-  if session.user_id != request.user_id {
-    warn!("Session '{}' if for user '{}' not user '{}'.", request.session_id, session.user_id, request.user_id);
+  // load user items if required
+  if let Err(e) = db.load_user_if_required(&auth.user_id) {
+    error!("An error occurred loading item state for user '{}': {}", auth.user_id, e);
     return Json(SendResponse { success: false, json_data: None });
   }
 
-  // load user items if required
-  if !db.item.user_items_loaded(&session.user_id) {
-    match db.item.load_user_items(&session.user_id, false) {
-      Ok(_) => {},
-      Err(e) => {
-        error!("An error occurred loading item state for user '{}': {}", session.user_id, e);
-        return Json(SendResponse { success: false, json_data: None });
-      }
-    }
+  if let Err(e) = check_command_access(&db, &auth, &request.command, &request.json_data) {
+    warn!("A '{}' command issued by user '{}' was not permitted by the API token it was made with: {}", request.command, auth.user_id, e);
+    return Json(SendResponse { success: false, json_data: None });
   }
 
   // handle
   let response_data_maybe = match request.command.as_str() {
-    "get-children" => handle_get_children(&mut db, &request.json_data),
-    "get-attachments" => handle_get_attachments(&mut db, &request.json_data),
-    "add-item" => handle_add_item(&mut db, &request.json_data),
-    "update-item" => handle_update_item(&mut db, &request.json_data),
+    "get-children" => handle_get_children(&mut db, &auth.user_id, &request.json_data),
+    "get-attachments" => handle_get_attachments(&mut db, &auth.user_id, &request.json_data),
+    "add-item" => handle_add_item(&mut db, &auth.user_id, &request.json_data),
+    "update-item" => handle_update_item(&mut db, &auth.user_id, &request.json_data),
+    "get-item-history" => handle_get_item_history(&mut db, &auth.user_id, &request.json_data),
+    "restore-item" => handle_restore_item(&mut db, &auth, &request.json_data),
+    "delete-item" => handle_delete_item(&mut db, file_store, &auth.user_id, &request.json_data),
+    "move-item" => handle_move_item(&mut db, &auth.user_id, &request.json_data),
+    "trash-item" => handle_trash_item(&mut db, &auth.user_id, &request.json_data),
+    "restore-trashed-item" => handle_restore_trashed_item(&mut db, &auth.user_id, &request.json_data),
+    "get-trash" => handle_get_trash(&mut db, &auth.user_id),
     _ => {
-      warn!("Unknown command '{}' issued by user '{}', session '{}'", request.command, request.user_id, request.session_id);
+      warn!("Unknown command '{}' issued by user '{}'", request.command, auth.user_id);
       return Json(SendResponse { success: false, json_data: None });
     }
   };
@@ -93,7 +79,7 @@ pub fn command(db: &State<Mutex<Db>>, request: Json<SendRequest>) -> Json<SendRe
   let response_data = match response_data_maybe {
     Ok(r) => r,
     Err(e) => {
-      error!("An error occurred servicing a '{}' command for user '{}': {}.", request.command, request.user_id, e);
+      error!("An error occurred servicing a '{}' command for user '{}': {}.", request.command, auth.user_id, e);
       return Json(SendResponse { success: false, json_data: None });
     }
   };
@@ -103,14 +89,63 @@ pub fn command(db: &State<Mutex<Db>>, request: Json<SendRequest>) -> Json<SendRe
 }
 
 
+/// Check that the credential the request was made with permits `command` (see `Authenticated::check_access`).
+fn check_command_access(db: &Db, auth: &Authenticated, command: &str, json_data: &str) -> InfuResult<()> {
+  // The fields of the request data that identify the items the command acts on (and whether each is required),
+  // or None if it acts on the trash, which is not within the scope of any API token. Along with whether the
+  // command changes anything.
+  let (fields, writes): (Option<&[(&str, bool)]>, bool) = match command {
+    "get-children" | "get-attachments" => (Some(&[("parentId", true)]), false),
+    "add-item" => (Some(&[("parentId", true)]), true),
+    "update-item" => (Some(&[("id", true), ("parentId", false)]), true),
+    "get-item-history" => (Some(&[("id", true)]), false),
+    "restore-item" | "delete-item" | "trash-item" => (Some(&[("id", true)]), true),
+    "move-item" => (Some(&[("id", true), ("newParentId", true)]), true),
+    "get-trash" => (None, false),
+    "restore-trashed-item" => (None, true),
+    // Commands that aren't listed here are not permitted with an API token, until it's decided what they need.
+    _ if auth.api_token.is_some() => return Err(format!("The '{}' command can't be used with an API token.", command).into()),
+    _ => return Ok(())
+  };
+  if !auth.is_scoped() {
+    return auth.check_access(db, &[], writes);
+  }
+  let fields = fields.ok_or(format!("The '{}' command can't be used with an API token that is scoped to an item.", command))?;
+  let map: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json_data)?;
+  let mut item_ids = vec![];
+  for (field, required) in fields {
+    match json::get_string_field(&map, field)? {
+      Some(item_id) => item_ids.push(item_id),
+      None if *required => return Err(format!("'{}' field was missing.", field).into()),
+      None => {}
+    }
+  }
+  auth.check_access(db, &item_ids.iter().collect::<Vec<&String>>(), writes)
+}
+
+
 #[derive(Deserialize)]
 pub struct GetChildrenRequest {
   #[serde(rename="parentId")]
   parent_id: String,
 }
 
-fn handle_get_children(db: &mut MutexGuard<Db>, json_data: &str) -> InfuResult<Option<String>> {
+/// Error if item `id` does not exist, or is not owned by user `user_id`. The error is the same either way, so as
+/// not to reveal whether items of other users exist.
+fn check_owner(db: &Db, user_id: &str, id: &str) -> InfuResult<()> {
+  match db.item.get(&String::from(id)) {
+    Ok(item) if item.owner_id == user_id => Ok(()),
+    Ok(_) => {
+      warn!("User '{}' issued a command for item '{}', which they do not own.", user_id, id);
+      Err(format!("Unknown item '{}'.", id).into())
+    },
+    Err(_) => Err(format!("Unknown item '{}'.", id).into())
+  }
+}
+
+fn handle_get_children(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
   let request: GetChildrenRequest = serde_json::from_str(json_data)?;
+  check_owner(db, user_id, &request.parent_id)?;
   let children = db.item
     .get_children(&request.parent_id)?.iter()
     .map(|v| v.to_api_json().ok())
@@ -125,8 +160,9 @@ pub struct GetAttachmentsRequest {
   parent_id: String,
 }
 
-fn handle_get_attachments(db: &mut MutexGuard<Db>, json_data: &str) -> InfuResult<Option<String>> {
+fn handle_get_attachments(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
   let request: GetAttachmentsRequest = serde_json::from_str(json_data)?;
+  check_owner(db, user_id, &request.parent_id)?;
   let attachments = db.item
     .get_attachments(&request.parent_id)?.iter()
     .map(|v| v.to_api_json().ok())
@@ -135,23 +171,195 @@ fn handle_get_attachments(db: &mut MutexGuard<Db>, json_data: &str) -> InfuResul
 }
 
 
-fn handle_add_item(db: &mut MutexGuard<Db>, json_data: &str) -> InfuResult<Option<String>> {
+fn handle_add_item(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
   let deserializer = serde_json::Deserializer::from_str(json_data);
   let mut iterator = deserializer.into_iter::<serde_json::Value>();
   let item_map_maybe = iterator.next().ok_or("Add item request has no item")??;
   let item_map = item_map_maybe.as_object().ok_or("Add item request body is not a JSON object")?;
   let item: Item = Item::from_api_json(item_map)?;
+  if item.owner_id != user_id {
+    warn!("User '{}' attempted to add item '{}' for user '{}'.", user_id, item.id, item.owner_id);
+    return Err(format!("Unknown item '{}'.", item.id).into());
+  }
+  if let Some(parent_id) = &item.parent_id {
+    check_owner(db, user_id, parent_id)?;
+  }
   db.item.add(item)?;
   Ok(None)
 }
 
 
-fn handle_update_item(db: &mut MutexGuard<Db>, json_data: &str) -> InfuResult<Option<String>> {
+fn handle_update_item(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
   let deserializer = serde_json::Deserializer::from_str(json_data);
   let mut iterator = deserializer.into_iter::<serde_json::Value>();
   let item_map_maybe = iterator.next().ok_or("Update item request has no item")??;
   let item_map = item_map_maybe.as_object().ok_or("Update item request body is not a JSON object")?;
   let item: Item = Item::from_api_json(item_map)?;
+  check_owner(db, user_id, &item.id)?;
+  if item.owner_id != user_id {
+    warn!("User '{}' attempted to give item '{}' to user '{}'.", user_id, item.id, item.owner_id);
+    return Err(format!("Unknown item '{}'.", item.id).into());
+  }
   db.item.update(&item)?;
   Ok(None)
 }
+
+
+#[derive(Deserialize)]
+pub struct MoveItemRequest {
+  id: String,
+  #[serde(rename="newParentId")]
+  new_parent_id: String,
+  #[serde(rename="relationshipToParent")]
+  relationship_to_parent: Option<String>,
+}
+
+fn handle_move_item(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
+  let request: MoveItemRequest = serde_json::from_str(json_data)?;
+  let relationship_to_parent = match &request.relationship_to_parent {
+    Some(r) => RelationshipToParent::from_string(r)?,
+    None => RelationshipToParent::Child
+  };
+  let moved = db.move_item(user_id, &request.id, &request.new_parent_id, relationship_to_parent)?;
+  Ok(Some(serde_json::to_string(&moved.to_api_json()?)?))
+}
+
+
+#[derive(Deserialize)]
+pub struct GetItemHistoryRequest {
+  id: String,
+}
+
+#[derive(Serialize)]
+pub struct ItemHistoryRecord {
+  timestamp: Option<i64>,
+  record: serde_json::Map<String, serde_json::Value>,
+}
+
+fn handle_get_item_history(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
+  let request: GetItemHistoryRequest = serde_json::from_str(json_data)?;
+  let history = db.item
+    .get_history(user_id, &request.id)?.into_iter()
+    .map(|(timestamp, record)| ItemHistoryRecord { timestamp, record })
+    .collect::<Vec<ItemHistoryRecord>>();
+  Ok(Some(serde_json::to_string(&history)?))
+}
+
+
+#[derive(Deserialize)]
+pub struct RestoreItemRequest {
+  id: String,
+  timestamp: i64,
+  #[serde(rename="includeDescendants")]
+  include_descendants: bool,
+}
+
+fn handle_restore_item(db: &mut MutexGuard<Db>, auth: &Authenticated, json_data: &str) -> InfuResult<Option<String>> {
+  let request: RestoreItemRequest = serde_json::from_str(json_data)?;
+  let restored = db.item.restore(&auth.user_id, &request.id, request.timestamp, request.include_descendants)?;
+  let restored_ids = restored.ids();
+
+  // The restored items may have moved, from or to anywhere, so the API token the request was made with (if any)
+  // must allow access to each of them where they now are, and to the parent of the item.
+  let mut accessed_ids = restored_ids.clone();
+  if let Some(parent_id) = &db.item.get(&request.id)?.parent_id {
+    accessed_ids.push(parent_id.clone());
+  }
+  if let Err(e) = auth.check_access(db, &accessed_ids.iter().collect::<Vec<&String>>(), true) {
+    return Err(match db.item.undo_restore(restored) {
+      Ok(()) => e,
+      Err(undo_e) => format!("{}. Undoing the restore also failed: {}", e, undo_e).into()
+    });
+  }
+  Ok(Some(serde_json::to_string(&restored_ids)?))
+}
+
+
+#[derive(Deserialize)]
+pub struct DeleteItemRequest {
+  id: String,
+}
+
+fn handle_delete_item(db: &mut MutexGuard<Db>, file_store: &State<Arc<FileStore>>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
+  let request: DeleteItemRequest = serde_json::from_str(json_data)?;
+  let item = db.item.get(&request.id)?;
+  if item.owner_id != user_id {
+    return Err(format!("Item '{}' is not owned by user '{}'.", request.id, user_id).into());
+  }
+  let user = db.user.get_by_id(user_id).ok_or(format!("Unknown user '{}'.", user_id))?;
+  if user.root_page_id == request.id {
+    return Err(format!("Item '{}' is the root page of user '{}', and cannot be deleted.", request.id, user_id).into());
+  }
+  if user.trash_page_id.as_ref() == Some(&request.id) {
+    return Err(format!("Item '{}' is the trash page of user '{}', and cannot be deleted.", request.id, user_id).into());
+  }
+
+  let removed = db.item.remove_with_descendants(&request.id)?;
+  if db.trash.get(user_id, &request.id)?.is_some() {
+    db.trash.remove(user_id, &request.id)?;
+  }
+
+  let removed_ids = removed.iter().map(|item| item.id.clone()).collect::<Vec<String>>();
+
+  // The db lock is held for the duration of the command, so the file data is deleted once it's released.
+  let file_store = file_store.inner().clone();
+  rocket::tokio::spawn(async move { file_store.delete_for_items(&removed).await; });
+  Ok(Some(serde_json::to_string(&removed_ids)?))
+}
+
+
+#[derive(Deserialize)]
+pub struct TrashItemRequest {
+  id: String,
+}
+
+fn handle_trash_item(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
+  let request: TrashItemRequest = serde_json::from_str(json_data)?;
+  db.trash_item(user_id, &request.id)?;
+  Ok(None)
+}
+
+
+#[derive(Deserialize)]
+pub struct RestoreTrashedItemRequest {
+  id: String,
+}
+
+fn handle_restore_trashed_item(db: &mut MutexGuard<Db>, user_id: &str, json_data: &str) -> InfuResult<Option<String>> {
+  let request: RestoreTrashedItemRequest = serde_json::from_str(json_data)?;
+  db.restore_trashed_item(user_id, &request.id)?;
+  Ok(None)
+}
+
+
+#[derive(Serialize)]
+pub struct GetTrashResponse {
+  #[serde(rename="trashPageId")]
+  trash_page_id: String,
+  entries: Vec<TrashEntryResponse>,
+}
+
+#[derive(Serialize)]
+pub struct TrashEntryResponse {
+  id: String,
+  #[serde(rename="parentId")]
+  parent_id: String,
+  #[serde(rename="relationshipToParent")]
+  relationship_to_parent: String,
+  #[serde(rename="trashedDate")]
+  trashed_date: i64,
+}
+
+fn handle_get_trash(db: &mut MutexGuard<Db>, user_id: &str) -> InfuResult<Option<String>> {
+  let trash_page_id = db.get_or_create_trash_page(user_id)?;
+  let entries = db.trash
+    .get_all(user_id)?.iter()
+    .map(|entry| TrashEntryResponse {
+      id: entry.id.clone(),
+      parent_id: entry.parent_id.clone(),
+      relationship_to_parent: String::from(entry.relationship_to_parent.to_string()),
+      trashed_date: entry.trashed_date
+    })
+    .collect::<Vec<TrashEntryResponse>>();
+  Ok(Some(serde_json::to_string(&GetTrashResponse { trash_page_id, entries })?))
+}
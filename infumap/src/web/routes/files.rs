@@ -14,31 +14,213 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::sync::Mutex;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use rocket::{State, http::ContentType};
+use rocket::data::Capped;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::serde::json::Json;
+use rocket::tokio::task::spawn_blocking;
+use serde::Serialize;
+use serde_json::{Map, Value};
 
+use crate::storage::cache::{FileCache, ScaledImage, ScaledImageData, image_metadata, scaled_width_for};
 use crate::storage::db::Db;
-use crate::storage::file::FileStore;
+use crate::storage::db::item::{is_data_item, is_image_item, Item};
+use crate::storage::encryption::Keyring;
+use crate::storage::file::{hash_file, FileStore};
+use crate::web::auth::Authenticated;
 use crate::web::responders::FileResponse;
-use crate::util::infu::InfuError;
+use crate::util::infu::{InfuError, InfuResult};
+use crate::util::json;
+use super::WebApiJsonSerializable;
 
-#[get("/files/<uid>")]
-pub fn get(db: &State<Mutex<Db>>, file_store: &State<Mutex<FileStore>>, uid: &str) -> Result<FileResponse, InfuError> {
-  let db = db.lock().unwrap();
-  let file_store = file_store.lock().unwrap();
+/// Stream the data of file or image item `uid`, which must be owned by the user. Conditional (If-None-Match, If-Modified-Since) and range
+/// requests are supported, see FileResponse.
+///
+/// If `width` is given for an image, a version scaled down to about that width is served instead of the
+/// original. These are generated on first request and kept in the file cache.
+#[get("/files/<uid>?<width>")]
+pub async fn get(
+    auth: Authenticated, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>, file_cache: &State<Arc<FileCache>>,
+    uid: &str, width: Option<u32>) -> Result<FileResponse, InfuError> {
+  let (item, mime_type_string, keyring) = {
+    let mut db = db.lock().unwrap();
+    db.load_user_if_required(&auth.user_id)?;
+    let item = db.item.get(&String::from(uid))?;
+    if item.owner_id != auth.user_id {
+      warn!("User '{}' requested the data of item '{}', which they do not own.", auth.user_id, uid);
+      // The same as for an item that does not exist, so as not to reveal that it does.
+      return Err(format!("Unknown item '{}'.", uid).into());
+    }
+    if let Err(e) = auth.check_access(&db, &[&item.id], false) {
+      warn!("User '{}' requested the data of item '{}', which the API token the request was made with does not permit: {}", auth.user_id, uid, e);
+      return Err(format!("Unknown item '{}'.", uid).into());
+    }
+    let mime_type = item.mime_type.as_ref().ok_or(format!("mime type is not available for item '{}'.", uid))?.clone();
+    (item.clone(), mime_type, db.user_keyring(&item.owner_id)?)
+  };
+
+  if let (Some(width), Some(image_size_px)) = (width, &item.image_size_px) {
+    if let Some(scaled_width) = scaled_width_for(width, image_size_px.w) {
+      let scaled = get_scaled(file_store, file_cache.inner().clone(), &item, scaled_width, keyring).await?;
+      return Ok(FileResponse {
+        data: match scaled.data {
+          ScaledImageData::File(file) => Box::new(file),
+          ScaledImageData::Decrypted(data) => Box::new(Cursor::new(data))
+        },
+        size: scaled.size,
+        mime_type: ContentType::parse_flexible(scaled.mime_type).unwrap(),
+        etag: format!("\"{}_{}\"", uid, scaled_width),
+        last_modified: scaled.last_modified
+      });
+    }
+  }
 
-  let item = db.item.get(&String::from(uid))?;
-  let mime_type_string = item.mime_type.as_ref().ok_or(format!("mime type is not available for item '{}'.", uid))?;
-  let mime_type = match ContentType::parse_flexible(mime_type_string) {
+  let mime_type = match ContentType::parse_flexible(&mime_type_string) {
     Some(s) => s,
     None => ContentType::Binary
   };
-  
-  let data = file_store.get(&String::from(uid))?;
+
+  let file = file_store.open_item(&item, keyring.as_ref()).await?;
 
   Ok(FileResponse {
-    data,
-    mime_type
+    size: file.size,
+    last_modified: file.last_modified,
+    data: Box::new(file),
+    mime_type,
+    // The data of an item never changes, so the item id identifies it.
+    etag: format!("\"{}\"", uid),
   })
 }
+
+/// Open the version of image `item` scaled to `width_px`, creating it if it is not in the cache. Scaling a
+/// large image takes a while, so this is done off the async runtime.
+async fn get_scaled(file_store: &FileStore, file_cache: Arc<FileCache>, item: &Item, width_px: u32, keyring: Option<Keyring>) -> InfuResult<ScaledImage> {
+  if let Some(scaled) = file_cache.open_scaled(&item.id, width_px, keyring.as_ref())? {
+    return Ok(scaled);
+  }
+  let original = file_store.open_item(item, keyring.as_ref()).await?.read_all().await?;
+  let uid = item.id.clone();
+  spawn_blocking(move || file_cache.create_scaled(&uid, width_px, &original, keyring.as_ref()))
+    .await.map_err(|e| InfuError::new(&format!("Scaling image failed: {}", e)))?
+}
+
+
+#[derive(FromForm)]
+pub struct UploadForm<'r> {
+  /// The file or image item to create, as for the add-item command, but without the
+  /// mimeType, fileSizeBytes, contentHash and originalCreationDate fields, which are set from the upload. Likewise
+  /// the imageSizePx and thumbnail fields of an image item.
+  item: String,
+  #[field(name = "originalCreationDate")]
+  original_creation_date: Option<i64>,
+  file: Capped<TempFile<'r>>,
+}
+
+#[derive(Serialize)]
+pub struct UploadResponse {
+  success: bool,
+  #[serde(rename="jsonData")]
+  json_data: Option<String>,
+}
+
+/// Store the uploaded file, and create the file or image item that refers to it. The size of uploads is
+/// limited by the max_upload_size_mb setting.
+#[post("/files/upload", data = "<upload>")]
+pub async fn upload(auth: Authenticated, db: &State<Arc<Mutex<Db>>>, file_store: &State<Arc<FileStore>>, upload: Form<UploadForm<'_>>) -> Json<UploadResponse> {
+  match handle_upload(db, file_store, &auth, upload.into_inner()).await {
+    Ok(item_json) => Json(UploadResponse { success: true, json_data: Some(item_json) }),
+    Err(e) => {
+      error!("An error occurred servicing a file upload for user '{}': {}", auth.user_id, e);
+      Json(UploadResponse { success: false, json_data: None })
+    }
+  }
+}
+
+async fn handle_upload(db: &Mutex<Db>, file_store: &FileStore, auth: &Authenticated, mut upload: UploadForm<'_>) -> InfuResult<String> {
+  let user_id = &auth.user_id;
+  if !upload.file.is_complete() {
+    return Err("File exceeds the maximum upload size.".into());
+  }
+
+  let mut item_map = {
+    let mut db = db.lock().unwrap();
+    db.load_user_if_required(user_id)?;
+
+    let mut item_map = serde_json::from_str::<Value>(&upload.item)?
+      .as_object().ok_or("Upload item is not a JSON object.")?.clone();
+    let parent_id = json::get_string_field(&item_map, "parentId")?.ok_or("Upload item has no parent.")?;
+    auth.check_access(&db, &[&parent_id], true)?;
+    let mime_type = match upload.file.content_type() {
+      Some(content_type) => content_type.to_string(),
+      None => String::from("application/octet-stream")
+    };
+    let original_creation_date = match upload.original_creation_date {
+      Some(date) => date,
+      None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64
+    };
+    item_map.insert(String::from("mimeType"), Value::String(mime_type));
+    item_map.insert(String::from("fileSizeBytes"), Value::Number(upload.file.len().into()));
+    item_map.insert(String::from("originalCreationDate"), Value::Number(original_creation_date.into()));
+    item_map
+  };
+
+  let staging_path = file_store.new_staging_path()?;
+  let result = async {
+    upload.file.move_copy_to(&staging_path).await?;
+    let hash_path = staging_path.clone();
+    let (content_hash, size_bytes) = spawn_blocking(move || hash_file(&hash_path)).await
+      .map_err(|e| InfuError::new(&format!("Hashing file failed: {}", e)))??;
+    item_map.insert(String::from("contentHash"), Value::String(content_hash.clone()));
+    // The dimensions and thumbnail of an image are determined from the uploaded data, not given by the client.
+    if json::get_string_field(&item_map, "itemType")?.map(|t| is_image_item(&t)).unwrap_or(false) {
+      let staging_path = staging_path.clone();
+      let metadata = spawn_blocking(move || image_metadata(File::open(staging_path)?)).await
+        .map_err(|e| InfuError::new(&format!("Reading image failed: {}", e)))??;
+      item_map.insert(String::from("imageSizePx"), json::dimensions_to_object(&metadata.size_px)?);
+      item_map.insert(String::from("thumbnail"), Value::String(metadata.thumbnail));
+    }
+    add_staged_item(db, file_store, user_id, &item_map, &staging_path, &content_hash, size_bytes).await
+  }.await;
+  if result.is_err() {
+    file_store.discard_staged(&staging_path);
+  }
+  Ok(serde_json::to_string(&result?.to_api_json()?)?)
+}
+
+/// Create the item described by `item_map`, moving its data from `staging_path` into place. `content_hash` and
+/// `size_bytes` are those of the staged data.
+async fn add_staged_item(db: &Mutex<Db>, file_store: &FileStore, user_id: &str, item_map: &Map<String, Value>, staging_path: &Path, content_hash: &str, size_bytes: u64) -> InfuResult<Item> {
+  let item = Item::from_api_json(item_map)?;
+  if !is_data_item(&item.item_type) {
+    return Err(format!("Uploaded data can not be stored for an item of type '{}'.", item.item_type).into());
+  }
+  if item.owner_id != user_id {
+    return Err(format!("Item '{}' is not owned by user '{}'.", item.id, user_id).into());
+  }
+  // The id is reserved under the db lock, so no other upload can commit data for it before this one has
+  // either added its item or removed the data it committed.
+  let (keyring, _reservation) = {
+    let db = db.lock().unwrap();
+    if db.item.get(&item.id).is_ok() {
+      return Err(format!("Item '{}' already exists.", item.id).into());
+    }
+    let reservation = file_store.reserve(&item.id)?;
+    (db.user_keyring(user_id)?, reservation)
+  };
+
+  file_store.commit_staged(&item.id, staging_path, content_hash, size_bytes, keyring.as_ref()).await?;
+
+  let added = db.lock().unwrap().item.add(item.clone());
+  if let Err(e) = added {
+    file_store.delete_for_items(&[item]).await;
+    return Err(e);
+  }
+  Ok(item)
+}
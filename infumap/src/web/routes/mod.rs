@@ -17,6 +17,7 @@
 use serde_json::{Map, Value};
 use crate::util::infu::InfuResult;
 
+pub mod admin;
 pub mod files;
 pub mod account;
 pub mod command;
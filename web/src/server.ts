@@ -62,7 +62,8 @@ async function send(command: string, user: User, payload: object): Promise<any>
       'Accept': 'application/json',
       'Content-Type': 'application/json'
     },
-    body: JSON.stringify({ command, userId: user.userId!, sessionId: user.sessionId!, jsonData: JSON.stringify(payload) })
+    // The session is identified by the HttpOnly session cookie set on login.
+    body: JSON.stringify({ command, jsonData: JSON.stringify(payload) })
   });
   let r = await fetchResult.json();
   if (!r.success) { throwExpression(`'${command}' command failed!`); }
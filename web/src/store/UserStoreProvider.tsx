@@ -29,7 +29,6 @@ const EXPIRE_DAYS = 30;
 export type User = {
   username: string,
   userId: Uid,
-  sessionId: Uid,
   rootPageId: Uid
 }
 
@@ -65,7 +64,8 @@ export function UserStoreProvider(props: UserStoreContextProps) {
         setSessionDataString(null);
         return false;
       }
-      const cookiePayload = JSON.stringify({ username, userId: r.userId, sessionId: r.sessionId, rootPageId: r.rootPageId });
+      // The session id itself is kept in an HttpOnly cookie set by the server, which scripts can't read.
+      const cookiePayload = JSON.stringify({ username, userId: r.userId, rootPageId: r.rootPageId });
       setCookie(SESSION_NAME, cookiePayload, EXPIRE_DAYS);
       setSessionDataString(cookiePayload);
       return true;
@@ -77,15 +77,12 @@ export function UserStoreProvider(props: UserStoreContextProps) {
         console.log("not logged in.");
         return;
       };
-      const user: User = JSON.parse(data);
 
       let fetchResult = await fetch('/account/logout', {
         method: 'POST',
         headers: {
-          'Accept': 'application/json',
-          'Content-Type': 'application/json'
-        },
-        body: JSON.stringify({ "userId": user.userId, "sessionId": user.sessionId })
+          'Accept': 'application/json'
+        }
       });
 
       let r = await fetchResult.json();